@@ -0,0 +1,85 @@
+use anyhow::{bail, Context, Result};
+use embuild::utils::{Mirrors, MIRRORS_FILE_VAR, MIRRORS_VAR};
+use log::*;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    author,
+    about = "Populate the local mirrors configured with EMBUILD_MIRRORS or EMBUILD_MIRRORS_FILE",
+    setting = structopt::clap::AppSettings::DeriveDisplayOrder
+)]
+struct Opt {
+    /// Prints verbose output
+    #[structopt(short, long)]
+    verbose: bool,
+
+    #[structopt(subcommand)]
+    cmd: Command,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Mirrors git repositories and their submodules, or updates existing mirrors
+    Git {
+        /// The urls of the repositories
+        #[structopt(required = true)]
+        urls: Vec<String>,
+    },
+    /// Downloads files that are not mirrored yet
+    File {
+        /// The urls of the files
+        #[structopt(required = true)]
+        urls: Vec<String>,
+    },
+}
+
+fn main() -> Result<()> {
+    let opt = Opt::from_args();
+
+    env_logger::Builder::from_env(
+        env_logger::Env::new()
+            .write_style_or("EMBUILD_MIRROR_LOG_STYLE", "Auto")
+            .filter_or(
+                "EMBUILD_MIRROR_LOG",
+                (if opt.verbose {
+                    LevelFilter::Debug
+                } else {
+                    LevelFilter::Info
+                })
+                .to_string(),
+            ),
+    )
+    .target(env_logger::Target::Stderr)
+    .format_level(false)
+    .format_indent(None)
+    .format_module_path(false)
+    .format_timestamp(None)
+    .init();
+
+    let mirrors = Mirrors::from_env()?;
+    if mirrors.is_empty() {
+        bail!("No mirrors configured, set ${MIRRORS_VAR} or ${MIRRORS_FILE_VAR}");
+    }
+
+    match opt.cmd {
+        Command::Git { urls } => {
+            for url in urls {
+                let path = mirrors
+                    .populate_git(&url)
+                    .with_context(|| format!("Could not mirror '{url}'"))?;
+                info!("Mirrored '{url}' into '{}'", path.display());
+            }
+        }
+        Command::File { urls } => {
+            for url in urls {
+                let path = mirrors
+                    .populate_file(&url)
+                    .with_context(|| format!("Could not mirror '{url}'"))?;
+                info!("Mirrored '{url}' into '{}'", path.display());
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,14 +1,23 @@
 use std::collections::HashMap;
 use std::env;
-use std::path::Path;
 use std::process::Command;
 use std::vec::Vec;
 
 use anyhow::{bail, Result};
 use embuild::build;
-use embuild::cli::{ParseFrom, UnixCommandArgs};
+use embuild::cli::{expand_response_files, ResponseFileFlavor};
 use log::*;
 
+embuild::cli_args! {
+    /// The arguments consumed by ldproxy itself, all other arguments are passed on to the
+    /// actual linker.
+    struct LdproxyArgs {
+        linker: String = build::LDPROXY_LINKER_ARG,
+        dedup_libs: bool = build::LDPROXY_DEDUP_LIBS_ARG,
+        cwd: Option<String> = build::LDPROXY_WORKING_DIRECTORY_ARG,
+    }
+}
+
 fn main() -> Result<()> {
     env_logger::Builder::from_env(
         env_logger::Env::new()
@@ -30,28 +39,14 @@ fn main() -> Result<()> {
 
     debug!("Link arguments: {args:?}");
 
-    let [linker, remove_duplicate_libs, cwd] = [
-        &build::LDPROXY_LINKER_ARG,
-        &build::LDPROXY_DEDUP_LIBS_ARG,
-        &build::LDPROXY_WORKING_DIRECTORY_ARG,
-    ]
-    .parse_from(&mut args);
-
-    let linker = linker
-        .ok()
-        .and_then(|v| v.into_iter().next_back())
-        .unwrap_or_else(|| {
-            panic!(
-                "Cannot locate argument '{}'",
-                build::LDPROXY_LINKER_ARG.format(Some("<linker>"))
-            )
-        });
+    let LdproxyArgs {
+        linker,
+        dedup_libs: remove_duplicate_libs,
+        cwd,
+    } = LdproxyArgs::parse_from(&mut args)?;
 
     debug!("Actual linker executable: {linker}");
 
-    let cwd = cwd.ok().and_then(|v| v.into_iter().next_back());
-    let remove_duplicate_libs = remove_duplicate_libs.is_ok();
-
     let args = if remove_duplicate_libs {
         debug!("Duplicate libs removal requested");
 
@@ -121,31 +116,13 @@ fn main() -> Result<()> {
 ///
 /// FIXME: handle other linker flavors (https://doc.rust-lang.org/rustc/codegen-options/index.html#linker-flavor)
 fn args() -> Result<Vec<String>> {
-    let mut result = Vec::new();
-
-    for arg in env::args().skip(1) {
-        // Rustc could invoke use with response file arguments, so we could get arguments
-        // like: `@<link-args-file>` (as per `@file` section of
-        // https://gcc.gnu.org/onlinedocs/gcc-11.2.0/gcc/Overall-Options.html)
-        //
-        // Deal with that
-        if let Some(rsp_file_str) = arg.strip_prefix('@') {
-            let rsp_file = Path::new(rsp_file_str);
-            // get all arguments from the response file if it exists
-            if rsp_file.exists() {
-                let contents = std::fs::read_to_string(rsp_file)?;
-                debug!("Contents of {}: {}", rsp_file_str, contents);
-
-                result.extend(UnixCommandArgs::new(&contents));
-            }
-            // otherwise just add the argument as normal
-            else {
-                result.push(arg);
-            }
-        } else {
-            result.push(arg);
-        }
-    }
-
-    Ok(result)
+    // Rustc could invoke use with response file arguments, so we could get arguments
+    // like: `@<link-args-file>` (as per `@file` section of
+    // https://gcc.gnu.org/onlinedocs/gcc-11.2.0/gcc/Overall-Options.html)
+    //
+    // Deal with that
+    Ok(expand_response_files(
+        env::args().skip(1),
+        ResponseFileFlavor::Unix,
+    )?)
 }
@@ -212,8 +212,19 @@ pub fn run_for_file(builder: bindgen::Builder, output_file: impl AsRef<Path>) ->
         .generate()
         .map_err(|_| Error::msg("Failed to generate bindings"))?;
 
-    bindings.write_to_file(output_file)?;
-    cargo_fmt_file(output_file);
+    // Format the bindings in a temporary file first, so that `output_file` is only
+    // touched if the formatted bindings changed.
+    let file_name = output_file
+        .file_name()
+        .ok_or_else(|| anyhow!("'{}' is not a file path", output_file.display()))?;
+    let tmp_file = output_file.with_file_name(format!(".{}.fmt.rs", file_name.to_string_lossy()));
+
+    bindings.write_to_file(&tmp_file)?;
+    cargo_fmt_file(&tmp_file);
+    let formatted = fs::read(&tmp_file);
+    fs::remove_file(&tmp_file).ok();
+
+    crate::fs::write_atomic_if_changed(output_file, formatted?)?;
 
     Ok(())
 }
@@ -1,9 +1,13 @@
 //! CLI argument manipulation utilities.
 
 mod arg;
+mod args_struct;
 mod parse_args;
+mod response_file;
 mod separate_args;
 
 pub use arg::*;
+pub use args_struct::*;
 pub use parse_args::*;
+pub use response_file::*;
 pub use separate_args::*;
@@ -0,0 +1,325 @@
+use std::path::PathBuf;
+
+use super::{ArgDef, ParseError};
+
+/// The error when parsing a struct defined with [`cli_args!`](crate::cli_args).
+#[derive(Debug, thiserror::Error)]
+pub enum ArgsError {
+    /// A required argument was not found.
+    #[error("missing required argument '{0}'")]
+    Missing(String),
+    /// An argument that requires a value was given without one.
+    #[error("argument '{0}' requires a value")]
+    NoValue(String),
+    /// The value of an argument could not be converted.
+    #[error("invalid value '{1}' for argument '{0}'")]
+    Invalid(String, String, #[source] anyhow::Error),
+}
+
+/// A single value of an argument that can be converted from and to a [`String`].
+pub trait ArgScalar: Sized {
+    /// Convert the argument value `value` into `Self`.
+    fn from_arg(value: &str) -> anyhow::Result<Self>;
+
+    /// Convert `self` back into an argument value.
+    fn to_arg(&self) -> String;
+}
+
+impl ArgScalar for String {
+    fn from_arg(value: &str) -> anyhow::Result<Self> {
+        Ok(value.to_owned())
+    }
+
+    fn to_arg(&self) -> String {
+        self.clone()
+    }
+}
+
+impl ArgScalar for PathBuf {
+    fn from_arg(value: &str) -> anyhow::Result<Self> {
+        Ok(value.into())
+    }
+
+    fn to_arg(&self) -> String {
+        self.to_string_lossy().into_owned()
+    }
+}
+
+/// The type of a field in a struct defined with [`cli_args!`](crate::cli_args).
+///
+/// The type determines the multiplicity of the argument:
+/// - `bool`: a flag that is either present or not,
+/// - `T`: a required option, the last occurrence wins,
+/// - `Option<T>`: an optional option, the last occurrence wins,
+/// - `Vec<T>`: an option that can be specified any number of times,
+///
+/// where `T` is any [`ArgScalar`].
+pub trait ArgValue: Sized {
+    /// Convert the result of [`ParseFrom::parse_from`](super::ParseFrom::parse_from) for
+    /// `def` into `Self`, using `default` if the argument was not found.
+    fn from_parsed(
+        parsed: Result<Vec<String>, ParseError>,
+        def: &ArgDef,
+        default: Option<Self>,
+    ) -> Result<Self, ArgsError>;
+
+    /// Get the value of every occurrence of the argument, or [`None`] for a flag.
+    fn to_values(&self) -> Vec<Option<String>>;
+}
+
+fn convert<T: ArgScalar>(def: &ArgDef, value: String) -> Result<T, ArgsError> {
+    T::from_arg(&value).map_err(|e| ArgsError::Invalid(def.name.to_owned(), value, e))
+}
+
+impl ArgValue for bool {
+    fn from_parsed(
+        parsed: Result<Vec<String>, ParseError>,
+        _def: &ArgDef,
+        default: Option<Self>,
+    ) -> Result<Self, ArgsError> {
+        Ok(parsed.is_ok() || default.unwrap_or(false))
+    }
+
+    fn to_values(&self) -> Vec<Option<String>> {
+        if *self {
+            vec![None]
+        } else {
+            vec![]
+        }
+    }
+}
+
+impl<T: ArgScalar> ArgValue for Option<T> {
+    fn from_parsed(
+        parsed: Result<Vec<String>, ParseError>,
+        def: &ArgDef,
+        default: Option<Self>,
+    ) -> Result<Self, ArgsError> {
+        match parsed {
+            Ok(mut values) => values.pop().map(|v| convert(def, v)).transpose(),
+            Err(ParseError::NotFound) => Ok(default.flatten()),
+        }
+    }
+
+    fn to_values(&self) -> Vec<Option<String>> {
+        self.iter().map(|v| Some(v.to_arg())).collect()
+    }
+}
+
+impl<T: ArgScalar> ArgValue for Vec<T> {
+    fn from_parsed(
+        parsed: Result<Vec<String>, ParseError>,
+        def: &ArgDef,
+        default: Option<Self>,
+    ) -> Result<Self, ArgsError> {
+        match parsed {
+            Ok(values) => values.into_iter().map(|v| convert(def, v)).collect(),
+            Err(ParseError::NotFound) => Ok(default.unwrap_or_default()),
+        }
+    }
+
+    fn to_values(&self) -> Vec<Option<String>> {
+        self.iter().map(|v| Some(v.to_arg())).collect()
+    }
+}
+
+macro_rules! impl_required_arg_value {
+    ($($ty:ty),*) => {
+        $(
+            impl ArgValue for $ty {
+                fn from_parsed(
+                    parsed: Result<Vec<String>, ParseError>,
+                    def: &ArgDef,
+                    default: Option<Self>,
+                ) -> Result<Self, ArgsError> {
+                    match (parsed, default) {
+                        (Ok(mut values), _) => match values.pop() {
+                            Some(v) => convert(def, v),
+                            None => Err(ArgsError::NoValue(def.name.to_owned())),
+                        },
+                        (Err(ParseError::NotFound), Some(default)) => Ok(default),
+                        (Err(ParseError::NotFound), None) => {
+                            Err(ArgsError::Missing(def.name.to_owned()))
+                        }
+                    }
+                }
+
+                fn to_values(&self) -> Vec<Option<String>> {
+                    vec![Some(self.to_arg())]
+                }
+            }
+        )*
+    };
+}
+
+impl_required_arg_value!(String, PathBuf);
+
+/// Define a struct where every field is parsed from and formatted to command line
+/// arguments using an [`ArgDef`].
+///
+/// Every field is declared as `<name>: <type> = <arg def>`, optionally followed by
+/// `; default = <value>` which is used when the argument is not present. The type of
+/// the field must implement [`ArgValue`], which determines how many times the argument
+/// may occur.
+///
+/// The struct gets two methods:
+/// - `parse_from(args: &mut Vec<String>) -> Result<Self, ArgsError>` which removes all
+///   arguments matching any field from `args` and leaves all other arguments intact
+///   (see [`ParseFrom`](crate::cli::ParseFrom)),
+/// - `to_args(&self) -> Vec<String>` which formats all fields back into arguments (see
+///   [`ArgDef::format`]).
+///
+/// # Examples
+/// ```
+/// use embuild::cli::{Arg, ArgOpts};
+///
+/// embuild::cli_args! {
+///     #[derive(Debug)]
+///     pub struct LinkerArgs {
+///         /// The output file.
+///         pub output: String = Arg::option("o").short(); default = "a.out",
+///         pub libs: Vec<String> = Arg::option("l").with_opts(ArgOpts::VALUE_SEP_NO_SPACE),
+///         pub verbose: bool = Arg::flag("verbose").with_alias(&[("v", None)]),
+///     }
+/// }
+///
+/// let mut args = ["-lc", "main.o", "-v", "-lm"].map(String::from).to_vec();
+/// let parsed = LinkerArgs::parse_from(&mut args).unwrap();
+///
+/// assert_eq!(parsed.output, "a.out");
+/// assert_eq!(parsed.libs, ["c", "m"]);
+/// assert!(parsed.verbose);
+/// assert_eq!(args, ["main.o"]);
+/// assert_eq!(parsed.to_args(), ["-o", "a.out", "-lc", "-lm", "--verbose"]);
+/// ```
+#[macro_export]
+macro_rules! cli_args {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $(
+                $(#[$field_meta:meta])*
+                $field_vis:vis $field:ident : $ty:ty = $def:expr $(; default = $default:expr)?
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $(
+                $(#[$field_meta])*
+                $field_vis $field: $ty,
+            )*
+        }
+
+        impl $name {
+            /// Parse all fields from `args` and remove all arguments that match any
+            /// field from `args`.
+            #[allow(dead_code)]
+            pub fn parse_from(
+                args: &mut ::std::vec::Vec<::std::string::String>,
+            ) -> ::std::result::Result<Self, $crate::cli::ArgsError> {
+                use $crate::cli::ParseFrom as _;
+
+                let [$($field),*] = [$(&$def),*].parse_from(args);
+
+                ::std::result::Result::Ok(Self {
+                    $(
+                        $field: <$ty as $crate::cli::ArgValue>::from_parsed(
+                            $field,
+                            &$def,
+                            $crate::__cli_args_default!($($default)?),
+                        )?,
+                    )*
+                })
+            }
+
+            /// Format all fields as arguments.
+            #[allow(dead_code)]
+            pub fn to_args(&self) -> ::std::vec::Vec<::std::string::String> {
+                let mut args = ::std::vec::Vec::new();
+                $(
+                    for value in $crate::cli::ArgValue::to_values(&self.$field) {
+                        args.extend($def.format(value.as_deref()));
+                    }
+                )*
+                args
+            }
+        }
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __cli_args_default {
+    () => {
+        ::std::option::Option::None
+    };
+    ($default:expr) => {
+        ::std::option::Option::Some(::std::convert::Into::into($default))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::super::{Arg, ArgOpts};
+    use super::*;
+
+    const ALIAS: &[(&str, Option<ArgOpts>)] = &[("dir", None)];
+
+    crate::cli_args! {
+        #[derive(Debug)]
+        struct TestArgs {
+            flag: bool = Arg::flag("flag").long(),
+            required: String = Arg::option("r"),
+            defaulted: String = Arg::option("d"); default = "default",
+            optional: Option<PathBuf> = Arg::option("directory").long().with_alias(ALIAS),
+            multiple: Vec<String> = Arg::option("m").with_opts(ArgOpts::VALUE_SEP_EQUALS),
+        }
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|&s| s.to_owned()).collect()
+    }
+
+    #[test]
+    fn parse_struct() {
+        let mut rest = args(&[
+            "arg0", "-m=1", "--dir", "a", "-r", "x", "--flag", "-r", "y", "-m=2", "arg1",
+        ]);
+
+        let parsed = TestArgs::parse_from(&mut rest).unwrap();
+
+        assert!(parsed.flag);
+        assert_eq!(parsed.required, "y");
+        assert_eq!(parsed.defaulted, "default");
+        assert_eq!(parsed.optional, Some(PathBuf::from("a")));
+        assert_eq!(parsed.multiple, ["1", "2"]);
+        assert_eq!(rest, ["arg0", "arg1"]);
+
+        assert_eq!(
+            parsed.to_args(),
+            [
+                "--flag",
+                "-r",
+                "y",
+                "-d",
+                "default",
+                "--directory",
+                "a",
+                "-m=1",
+                "-m=2"
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_struct_missing() {
+        let mut rest = args(&["--flag", "-d", "value"]);
+
+        let err = TestArgs::parse_from(&mut rest).unwrap_err();
+
+        assert!(matches!(err, ArgsError::Missing(name) if name == "r"));
+    }
+}
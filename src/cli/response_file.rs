@@ -0,0 +1,247 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::{UnixCommandArgs, WindowsCommandArgs};
+
+/// The quoting rules used to split the contents of a response file into arguments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ResponseFileFlavor {
+    /// Arguments are separated by any whitespace (including newlines) and can be quoted
+    /// using unix shell rules (see [`UnixCommandArgs`]).
+    ///
+    /// This is what gcc, ld and most other gnu tools use.
+    Unix,
+    /// Every line is parsed as a windows command line (see [`WindowsCommandArgs`]).
+    ///
+    /// Files starting with an UTF-16LE byte order mark are decoded as UTF-16.
+    Windows,
+}
+
+impl ResponseFileFlavor {
+    /// The flavor native to the host platform.
+    pub const fn native() -> Self {
+        if cfg!(windows) {
+            Self::Windows
+        } else {
+            Self::Unix
+        }
+    }
+
+    /// Split the `contents` of a response file into separate arguments.
+    ///
+    /// Returns [`None`] if `contents` is malformed (e.g. has an unterminated quote).
+    pub fn split(self, contents: &str) -> Option<Vec<String>> {
+        match self {
+            Self::Unix => {
+                let mut lexer = UnixCommandArgs::new(contents);
+                let args = lexer.by_ref().collect();
+                (!lexer.had_error).then(|| args)
+            }
+            Self::Windows => Some(contents.lines().flat_map(WindowsCommandArgs::new).collect()),
+        }
+    }
+}
+
+impl Default for ResponseFileFlavor {
+    fn default() -> Self {
+        Self::native()
+    }
+}
+
+/// The error when expanding response files.
+#[derive(Debug, thiserror::Error)]
+pub enum ResponseFileError {
+    /// The response file could not be read.
+    #[error("could not read response file '{0}'")]
+    Read(PathBuf, #[source] io::Error),
+    /// The response file (directly or indirectly) references itself.
+    #[error("response file '{0}' recursively includes itself")]
+    Recursive(PathBuf),
+    /// The contents of the response file could not be split into arguments (e.g. because
+    /// of an unterminated quote).
+    #[error("malformed response file '{0}'")]
+    Malformed(PathBuf),
+    /// An error in the response file `0` which was included by another response file.
+    #[error("in response file '{0}'")]
+    Nested(PathBuf, #[source] Box<ResponseFileError>),
+}
+
+/// Expand all `@file` arguments in `args` with the arguments contained in `file`.
+///
+/// Response files may themselves contain `@file` arguments which are expanded
+/// recursively, an error is returned if a response file includes itself. Relative paths
+/// are resolved against the current working directory. Like gcc, an `@file` argument
+/// where `file` does not exist is left unchanged.
+///
+/// See the `@file` section of
+/// <https://gcc.gnu.org/onlinedocs/gcc-11.2.0/gcc/Overall-Options.html>.
+pub fn expand_response_files(
+    args: impl IntoIterator<Item = impl Into<String>>,
+    flavor: ResponseFileFlavor,
+) -> Result<Vec<String>, ResponseFileError> {
+    let cwd = std::env::current_dir().map_err(|e| ResponseFileError::Read(".".into(), e))?;
+    expand_response_files_in(args, flavor, cwd)
+}
+
+/// Expand all `@file` arguments in `args` with relative paths resolved against `cwd`.
+///
+/// See [`expand_response_files`].
+pub fn expand_response_files_in(
+    args: impl IntoIterator<Item = impl Into<String>>,
+    flavor: ResponseFileFlavor,
+    cwd: impl AsRef<Path>,
+) -> Result<Vec<String>, ResponseFileError> {
+    let mut result = Vec::new();
+    let mut stack = Vec::new();
+
+    for arg in args {
+        expand_arg(arg.into(), flavor, cwd.as_ref(), &mut stack, &mut result)?;
+    }
+
+    Ok(result)
+}
+
+fn expand_arg(
+    arg: String,
+    flavor: ResponseFileFlavor,
+    cwd: &Path,
+    stack: &mut Vec<PathBuf>,
+    result: &mut Vec<String>,
+) -> Result<(), ResponseFileError> {
+    let rsp_file = match arg.strip_prefix('@') {
+        Some(rsp_file) if !rsp_file.is_empty() => cwd.join(rsp_file),
+        _ => {
+            result.push(arg);
+            return Ok(());
+        }
+    };
+
+    // Leave the argument as is if the file doesn't exist.
+    let canonical = match rsp_file.canonicalize() {
+        Ok(p) if p.is_file() => p,
+        _ => {
+            result.push(arg);
+            return Ok(());
+        }
+    };
+
+    if stack.contains(&canonical) {
+        return Err(ResponseFileError::Recursive(rsp_file));
+    }
+
+    let contents = read_response_file(&canonical, flavor)
+        .map_err(|e| ResponseFileError::Read(rsp_file.clone(), e))?;
+    log::debug!("Contents of {}: {}", rsp_file.display(), contents);
+
+    let args = flavor
+        .split(&contents)
+        .ok_or_else(|| ResponseFileError::Malformed(rsp_file.clone()))?;
+
+    stack.push(canonical);
+    for arg in args {
+        expand_arg(arg, flavor, cwd, stack, result)
+            .map_err(|e| ResponseFileError::Nested(rsp_file.clone(), Box::new(e)))?;
+    }
+    stack.pop();
+
+    Ok(())
+}
+
+fn read_response_file(path: &Path, flavor: ResponseFileFlavor) -> io::Result<String> {
+    let bytes = std::fs::read(path)?;
+
+    match (flavor, &bytes[..]) {
+        (ResponseFileFlavor::Windows, [0xFF, 0xFE, rest @ ..]) => {
+            let wide = rest
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect::<Vec<_>>();
+            String::from_utf16(&wide).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+        (_, bytes) => {
+            let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+            String::from_utf8(bytes.to_vec())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_nested() {
+        let dir = tempfile::tempdir().unwrap();
+        let cwd = dir.path();
+
+        std::fs::create_dir(cwd.join("sub")).unwrap();
+        std::fs::write(cwd.join("outer.rsp"), "-a 'b c'\n@sub/inner.rsp\n-d").unwrap();
+        std::fs::write(cwd.join("sub/inner.rsp"), "\"-e f\" @missing.rsp").unwrap();
+
+        let args = expand_response_files_in(
+            ["first", "@outer.rsp", "@", "last"],
+            ResponseFileFlavor::Unix,
+            cwd,
+        )
+        .unwrap();
+
+        assert_eq!(
+            args,
+            [
+                "first",
+                "-a",
+                "b c",
+                "-e f",
+                "@missing.rsp",
+                "-d",
+                "@",
+                "last"
+            ]
+        );
+    }
+
+    #[test]
+    fn expand_windows() {
+        let dir = tempfile::tempdir().unwrap();
+        let cwd = dir.path();
+
+        let contents = "\"C:\\a b\\c.o\"\r\n/OUT:x.exe";
+        let mut bytes = vec![0xFF, 0xFE];
+        bytes.extend(contents.encode_utf16().flat_map(u16::to_le_bytes));
+        std::fs::write(cwd.join("link.rsp"), bytes).unwrap();
+
+        let args =
+            expand_response_files_in(["@link.rsp"], ResponseFileFlavor::Windows, cwd).unwrap();
+
+        assert_eq!(args, ["C:\\a b\\c.o", "/OUT:x.exe"]);
+    }
+
+    #[test]
+    fn expand_recursive() {
+        let dir = tempfile::tempdir().unwrap();
+        let cwd = dir.path();
+
+        std::fs::write(cwd.join("a.rsp"), "-x @b.rsp").unwrap();
+        std::fs::write(cwd.join("b.rsp"), "-y @a.rsp").unwrap();
+
+        let err = expand_response_files_in(["@a.rsp"], ResponseFileFlavor::Unix, cwd).unwrap_err();
+
+        let mut err = &err;
+        while let ResponseFileError::Nested(_, inner) = err {
+            err = inner;
+        }
+        assert!(matches!(err, ResponseFileError::Recursive(p) if p.ends_with("a.rsp")));
+    }
+
+    #[test]
+    fn expand_malformed() {
+        let dir = tempfile::tempdir().unwrap();
+        let cwd = dir.path();
+
+        std::fs::write(cwd.join("a.rsp"), "-x 'unterminated").unwrap();
+
+        let err = expand_response_files_in(["@a.rsp"], ResponseFileFlavor::Unix, cwd).unwrap_err();
+        assert!(matches!(err, ResponseFileError::Malformed(p) if p.ends_with("a.rsp")));
+    }
+}
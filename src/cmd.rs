@@ -1,10 +1,29 @@
 //! Command building and running utilities.
 
+use std::collections::VecDeque;
 use std::ffi::OsStr;
-use std::io;
-use std::process::{self, Command, ExitStatus};
+use std::io::{self, Read, Write};
+use std::process::{self, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The default amount of bytes of stdout and stderr retained in [tee
+/// mode](Cmd::tee).
+pub const DEFAULT_RETAINED_OUTPUT: usize = 64 * 1024;
+
+mod executor;
+mod pool;
+mod record;
+
+pub use executor::*;
+pub use pool::*;
+pub use record::*;
 
 /// Error when trying to execute a command.
+///
+/// If the output of the command was captured it is attached as the source of the error
+/// and can be retrieved with [`CmdError::output`].
 #[derive(Debug, thiserror::Error)]
 pub enum CmdError {
     /// The command failed to start.
@@ -16,6 +35,12 @@ pub enum CmdError {
     /// The command was terminated unexpectedly.
     #[error("command '{0}' was terminated unexpectedly")]
     Terminated(String),
+    /// The command was terminated by a signal (unix only).
+    #[error("command '{0}' was terminated by signal {1}")]
+    Signaled(String, i32, #[source] Option<anyhow::Error>),
+    /// The command did not finish within its [timeout](Cmd::timeout) and was killed.
+    #[error("command '{0}' timed out after {1:?}")]
+    TimedOut(String, Duration, #[source] Option<anyhow::Error>),
 }
 
 impl CmdError {
@@ -29,27 +54,186 @@ impl CmdError {
         status: process::ExitStatus,
         cmd: &process::Command,
         cmd_output: impl FnOnce() -> Option<String>,
+    ) -> Result<(), Self> {
+        Self::from_status(status, cmd, || cmd_output().map(anyhow::Error::msg))
+    }
+
+    fn from_status(
+        status: process::ExitStatus,
+        cmd: &process::Command,
+        cmd_output: impl FnOnce() -> Option<anyhow::Error>,
     ) -> Result<(), Self> {
         if status.success() {
-            Ok(())
+            return Ok(());
         } else if let Some(code) = status.code() {
-            Err(CmdError::Unsuccessful(
+            return Err(CmdError::Unsuccessful(
                 format!("{cmd:?}"),
                 code,
-                cmd_output().map(anyhow::Error::msg),
-            ))
+                cmd_output(),
+            ));
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+
+            if let Some(signal) = status.signal() {
+                return Err(CmdError::Signaled(format!("{cmd:?}"), signal, cmd_output()));
+            }
+        }
+
+        Err(CmdError::Terminated(format!("{cmd:?}")))
+    }
+
+    /// Get the captured output of the command if it is attached to this error.
+    pub fn output(&self) -> Option<&CmdOutput> {
+        match self {
+            Self::Unsuccessful(_, _, Some(e))
+            | Self::Signaled(_, _, Some(e))
+            | Self::TimedOut(_, _, Some(e)) => e.downcast_ref::<CmdOutput>(),
+            _ => None,
+        }
+    }
+}
+
+/// The (potentially truncated) stdout and stderr output of a command.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CmdOutput {
+    /// The stdout output.
+    pub stdout: String,
+    /// The stderr output.
+    pub stderr: String,
+}
+
+impl CmdOutput {
+    fn new(stdout: &[u8], stderr: &[u8]) -> Self {
+        CmdOutput {
+            stdout: String::from_utf8_lossy(stdout).trim_end().to_string(),
+            stderr: String::from_utf8_lossy(stderr).trim_end().to_string(),
+        }
+    }
+}
+
+/// Displays the stderr output, or the stdout output if there was no stderr output.
+impl std::fmt::Display for CmdOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.stderr.is_empty() {
+            f.write_str(&self.stdout)
         } else {
-            Err(CmdError::Terminated(format!("{cmd:?}")))
+            f.write_str(&self.stderr)
         }
     }
 }
 
+impl std::error::Error for CmdOutput {}
+
+/// Where the output of a command is streamed to in [tee mode](Cmd::tee).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TeeSink {
+    /// Write the stdout and stderr output of the command to the stdout and stderr of
+    /// this process.
+    Parent,
+    /// Log every line of the stdout and stderr output of the command with the [`log`]
+    /// crate at the given level.
+    Log(log::Level),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// The last bytes read from a stream, shared between the reader thread and [`Cmd`].
+type Tail = Arc<Mutex<VecDeque<u8>>>;
+
+/// Spawn a thread which reads `reader` until the end, forwards everything read to
+/// `sink` and retains at most the last `retain` bytes in the returned [`Tail`].
+fn tee_stream(
+    mut reader: impl Read + Send + 'static,
+    stream: Stream,
+    sink: Option<TeeSink>,
+    retain: Option<usize>,
+) -> (Tail, JoinHandle<()>) {
+    let tail = Tail::default();
+    let thread_tail = tail.clone();
+
+    let handle = thread::spawn(move || {
+        let mut buf = [0; 8192];
+        let mut line = Vec::new();
+
+        let log_line = |level, line: &mut Vec<u8>| {
+            if !line.is_empty() {
+                log::log!(level, "{}", String::from_utf8_lossy(line));
+                line.clear();
+            }
+        };
+
+        loop {
+            let chunk = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => &buf[..n],
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            };
+
+            match sink {
+                Some(TeeSink::Parent) if stream == Stream::Stdout => {
+                    let stdout = io::stdout();
+                    let mut stdout = stdout.lock();
+                    stdout.write_all(chunk).ok();
+                    stdout.flush().ok();
+                }
+                Some(TeeSink::Parent) => {
+                    io::stderr().write_all(chunk).ok();
+                }
+                Some(TeeSink::Log(level)) => {
+                    for &b in chunk {
+                        if b == b'\n' || b == b'\r' {
+                            log_line(level, &mut line);
+                        } else {
+                            line.push(b);
+                        }
+                    }
+                }
+                None => (),
+            }
+
+            let mut tail = thread_tail.lock().unwrap();
+            tail.extend(chunk);
+            if let Some(retain) = retain {
+                let excess = tail.len().saturating_sub(retain);
+                tail.drain(..excess);
+            }
+        }
+
+        if let Some(TeeSink::Log(level)) = sink {
+            log_line(level, &mut line);
+        }
+    });
+
+    (tail, handle)
+}
+
 /// A wrapper over a [`std::process::Command`] with more features.
+///
+/// Commands can be executed by a different [`Executor`] (e.g. a [`FakeExecutor`] in
+/// tests) with [`with_executor`].
+///
+/// All commands run can be recorded with `start_recording` or the `EMBUILD_CMD_RECORD`
+/// environment variable (feature `cmd-record`), and printed instead of executed with
+/// [`set_dry_run`] or the [`DRY_RUN_VAR`] environment variable.
 #[derive(Debug)]
 pub struct Cmd {
     /// The actual [`std::process::Command`] wrapped.
     pub cmd: std::process::Command,
     ignore_exitcode: bool,
+    tee: Option<TeeSink>,
+    retained_output: usize,
+    timeout: Option<Duration>,
+    /// Whether to print the output of a failed command, disabled by [`JobPool`] which
+    /// prints the output itself.
+    print_output: bool,
 }
 
 impl std::ops::Deref for Cmd {
@@ -71,6 +255,10 @@ impl From<std::process::Command> for Cmd {
         Cmd {
             cmd,
             ignore_exitcode: false,
+            tee: None,
+            retained_output: DEFAULT_RETAINED_OUTPUT,
+            timeout: None,
+            print_output: true,
         }
     }
 }
@@ -85,10 +273,7 @@ impl Cmd {
     /// Construct a new [`Cmd`] for launching `program` (see
     /// [`std::process::Command::new`]).
     pub fn new(program: impl AsRef<OsStr>) -> Self {
-        Self {
-            cmd: Command::new(program),
-            ignore_exitcode: false,
-        }
+        Command::new(program).into()
     }
 
     /// Ignore the exit code when executing this command.
@@ -103,35 +288,208 @@ impl Cmd {
         self
     }
 
+    /// Stream the stdout and stderr output of the command to `sink` while it is running,
+    /// and retain the last [`retain_output`](Self::retain_output) bytes of each to be
+    /// attached to a [`CmdError`].
+    ///
+    /// Applies to:
+    /// - [`Cmd::run`]
+    /// - [`Cmd::output`] (the output is not truncated)
+    /// - [`Cmd::stdout`]
+    /// - [`Cmd::stderr`]
+    ///
+    /// Note: This overrides the stdout and stderr configuration of the underlying
+    /// [`std::process::Command`]: both are set to [`Stdio::piped`] when the command runs,
+    /// replacing any [`Stdio`] set before.
+    pub fn tee(&mut self, sink: TeeSink) -> &mut Self {
+        self.tee = Some(sink);
+        self
+    }
+
+    /// Set the amount of bytes of stdout and stderr output that is retained in [tee
+    /// mode](Self::tee) (default: [`DEFAULT_RETAINED_OUTPUT`]).
+    pub fn retain_output(&mut self, bytes: usize) -> &mut Self {
+        self.retained_output = bytes;
+        self
+    }
+
+    /// Kill the command if it doesn't finish within `timeout` and return a
+    /// [`CmdError::TimedOut`] error.
+    ///
+    /// Applies to:
+    /// - [`Cmd::run`] (which will also use [tee mode](Self::tee) with
+    ///   [`TeeSink::Parent`] if not set otherwise)
+    /// - [`Cmd::output`]
+    /// - [`Cmd::stdout`]
+    /// - [`Cmd::stderr`]
+    ///
+    /// Note: Like [tee mode](Self::tee), this overrides the stdout and stderr
+    /// configuration of the underlying [`std::process::Command`] with [`Stdio::piped`].
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
     /// Run the command to completion.
     ///
     /// If [`Cmd::ignore_exitcode`] has been called a program that exited with an error
     /// will also return [`Ok`], otherwise it will return [`Err`].
     /// A program that failed to start will always return an [`Err`].
     ///
-    /// [`std::process::Command::status`] is used internally.
+    /// [`std::process::Command::status`] is used internally, unless [tee
+    /// mode](Self::tee) or a [timeout](Self::timeout) is set.
     pub fn run(&mut self) -> Result<(), CmdError> {
-        self.cmd
-            .status()
-            .map_err(|e| CmdError::no_run(&self.cmd, e))
-            .and_then(|v| {
-                if self.ignore_exitcode {
-                    Ok(())
-                } else {
-                    CmdError::status_into_result(v, &self.cmd, || None)
-                }
+        self.exec_recorded(Self::run_inner, || ())
+    }
+
+    fn run_inner(&mut self) -> Result<(), CmdError> {
+        if self.tee.is_none() && self.timeout.is_none() && current_executor().is_none() {
+            return self
+                .cmd
+                .status()
+                .map_err(|e| CmdError::no_run(&self.cmd, e))
+                .and_then(|v| {
+                    if self.ignore_exitcode {
+                        Ok(())
+                    } else {
+                        CmdError::from_status(v, &self.cmd, || None)
+                    }
+                });
+        }
+
+        let sink = self.tee.unwrap_or(TeeSink::Parent);
+        let output = self.execute(Some(sink), Some(self.retained_output))?;
+
+        if self.ignore_exitcode {
+            Ok(())
+        } else {
+            CmdError::from_status(output.status, &self.cmd, || {
+                Some(CmdOutput::new(&output.stdout, &output.stderr).into())
             })
+        }
+    }
+
+    /// Spawn the command with piped stdout and stderr, forward both to `sink` and wait
+    /// for it to finish, killing it if it exceeds the timeout.
+    ///
+    /// If an [`Executor`] is installed it is used instead.
+    fn execute(
+        &mut self,
+        sink: Option<TeeSink>,
+        retain: Option<usize>,
+    ) -> Result<process::Output, CmdError> {
+        if let Some(executor) = current_executor() {
+            return executor
+                .execute(&self.cmd)
+                .map_err(|e| CmdError::no_run(&self.cmd, e));
+        }
+
+        let mut child = self
+            .cmd
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| CmdError::no_run(&self.cmd, e))?;
+
+        let (stdout, stdout_thread) =
+            tee_stream(child.stdout.take().unwrap(), Stream::Stdout, sink, retain);
+        let (stderr, stderr_thread) =
+            tee_stream(child.stderr.take().unwrap(), Stream::Stderr, sink, retain);
+
+        let take = |tail: &Tail| Vec::from(std::mem::take(&mut *tail.lock().unwrap()));
+
+        let status = if let Some(timeout) = self.timeout {
+            let deadline = Instant::now() + timeout;
+            loop {
+                if let Some(status) = child
+                    .try_wait()
+                    .map_err(|e| CmdError::no_run(&self.cmd, e))?
+                {
+                    break status;
+                }
+
+                let now = Instant::now();
+                if now >= deadline {
+                    child.kill().ok();
+                    child.wait().ok();
+
+                    // Don't join the reader threads, the pipes could still be held open
+                    // by child processes of the killed command.
+                    let output = CmdOutput::new(&take(&stdout), &take(&stderr));
+                    return Err(CmdError::TimedOut(
+                        format!("{:?}", self.cmd),
+                        timeout,
+                        Some(output.into()),
+                    ));
+                }
+                thread::sleep((deadline - now).min(Duration::from_millis(50)));
+            }
+        } else {
+            child.wait().map_err(|e| CmdError::no_run(&self.cmd, e))?
+        };
+
+        stdout_thread.join().ok();
+        stderr_thread.join().ok();
+
+        Ok(process::Output {
+            status,
+            stdout: take(&stdout),
+            stderr: take(&stderr),
+        })
     }
 
     /// Run the command and get its [`ExitStatus`].
     pub fn status(&mut self) -> Result<ExitStatus, CmdError> {
-        self.cmd
-            .status()
-            .map_err(|e| CmdError::no_run(&self.cmd, e))
+        self.exec_recorded(
+            |this| {
+                if current_executor().is_some() {
+                    return this.execute(None, None).map(|output| output.status);
+                }
+
+                this.cmd
+                    .status()
+                    .map_err(|e| CmdError::no_run(&this.cmd, e))
+            },
+            || exit_status(0),
+        )
+    }
+
+    /// Run `exec` and record the command (see `start_recording`), or print the command
+    /// and return the result of `dry_run` in dry-run mode (see [`set_dry_run`]).
+    fn exec_recorded<T>(
+        &mut self,
+        exec: impl FnOnce(&mut Self) -> Result<T, CmdError>,
+        dry_run: impl FnOnce() -> T,
+    ) -> Result<T, CmdError> {
+        let start = Instant::now();
+        let is_dry_run = is_dry_run();
+
+        let result = if is_dry_run {
+            eprintln!("[dry-run] {:?}", self.cmd);
+            Ok(dry_run())
+        } else {
+            exec(self)
+        };
+
+        let error = result.as_ref().err().map(|e| {
+            let mut msg = e.to_string();
+            let mut source = std::error::Error::source(e);
+            while let Some(e) = source {
+                msg = format!("{msg}: {e}");
+                source = e.source();
+            }
+            msg
+        });
+        record(&self.cmd, start.elapsed(), is_dry_run, error);
+
+        result
     }
 
     fn print_output(&self, output: &std::process::Output) {
-        // TODO: add some way to quiet this output
+        if !self.print_output {
+            return;
+        }
+
         use std::io::Write;
         std::io::stdout().write_all(&output.stdout[..]).ok();
         std::io::stderr().write_all(&output.stderr[..]).ok();
@@ -143,31 +501,51 @@ impl Cmd {
     /// will also return [`Ok`], otherwise it will return [`Err`].
     /// A program that failed to start will always return an [`Err`].
     ///
-    /// [`std::process::Command::output`] is used internally.
+    /// [`std::process::Command::output`] is used internally, unless [tee
+    /// mode](Self::tee) or a [timeout](Self::timeout) is set.
     pub fn output<T>(
         &mut self,
         func: impl FnOnce(std::process::Output) -> T,
     ) -> Result<T, CmdError> {
-        match self.cmd.output() {
-            Err(err) => Err(CmdError::no_run(&self.cmd, err)),
-            Ok(result) => if self.ignore_exitcode {
-                self.print_output(&result);
-                Ok(())
+        self.exec_recorded(Self::output_inner, || process::Output {
+            status: exit_status(0),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        })
+        .map(func)
+    }
+
+    fn output_inner(&mut self) -> Result<process::Output, CmdError> {
+        let (result, streamed) =
+            if self.tee.is_some() || self.timeout.is_some() || current_executor().is_some() {
+                (self.execute(self.tee, None)?, self.tee.is_some())
             } else {
-                CmdError::status_into_result(result.status, &self.cmd, || {
-                    Some(
-                        String::from_utf8_lossy(&result.stderr[..])
-                            .trim_end()
-                            .to_string(),
-                    )
-                })
-            }
-            .map_err(|e| {
-                self.print_output(&result);
-                e
+                let result = self
+                    .cmd
+                    .output()
+                    .map_err(|e| CmdError::no_run(&self.cmd, e))?;
+                (result, false)
+            };
+
+        if self.ignore_exitcode {
+            Ok(())
+        } else {
+            CmdError::from_status(result.status, &self.cmd, || {
+                Some(CmdOutput::new(&result.stdout, &result.stderr).into())
             })
-            .map(|_| func(result)),
         }
+        .map_err(|e| {
+            if !streamed {
+                self.print_output(&result);
+            }
+            e
+        })
+        .map(|_| {
+            if self.ignore_exitcode && !streamed {
+                self.print_output(&result);
+            }
+            result
+        })
     }
 
     /// Run the command to completion and get its stdout output.
@@ -264,3 +642,78 @@ macro_rules! cmd {
         $crate::cmd_build!(cmd $(, $(@$cmdargs,)* $cmdarg)* $(; $($k = $v),* )?)
     }};
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn tee_retains_output() {
+        let err = cmd!("sh", "-c", "echo out; echo 0123456789 >&2; exit 3"; tee=(TeeSink::Log(log::Level::Debug)), retain_output=(5))
+            .run()
+            .unwrap_err();
+
+        assert!(matches!(err, CmdError::Unsuccessful(_, 3, _)));
+        let output = err.output().unwrap();
+        assert_eq!(output.stdout, "out");
+        assert_eq!(output.stderr, "6789");
+    }
+
+    #[test]
+    fn tee_stdout() {
+        let stdout = cmd!("sh", "-c", "echo a; echo b"; tee=(TeeSink::Log(log::Level::Debug)))
+            .stdout()
+            .unwrap();
+
+        assert_eq!(stdout, "a\nb");
+    }
+
+    #[test]
+    fn timeout() {
+        let err = cmd!("sh", "-c", "echo started; sleep 10"; timeout=(Duration::from_millis(200)), tee=(TeeSink::Log(log::Level::Debug)))
+            .run()
+            .unwrap_err();
+
+        assert!(matches!(err, CmdError::TimedOut(..)));
+        assert_eq!(err.output().unwrap().stdout, "started");
+    }
+
+    #[cfg(feature = "cmd-record")]
+    #[test]
+    fn record() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("cmds.jsonl");
+
+        start_recording(Some(file.clone()));
+        cmd!("sh", "-c", "exit 0", "record-test"; env=("A", "\"b\""), current_dir=(dir.path()))
+            .run()
+            .unwrap();
+        let records = stop_recording();
+
+        let record = records
+            .iter()
+            .find(|r| r.args.iter().any(|a| a == "record-test"))
+            .unwrap();
+        assert_eq!(record.program, "sh");
+        assert_eq!(record.cwd.as_deref(), Some(dir.path()));
+        assert_eq!(record.env, [("A".into(), Some("\"b\"".into()))]);
+        assert_eq!(record.error, None);
+
+        let json = std::fs::read_to_string(file).unwrap();
+        let json: serde_json::Value = json
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .find(|v: &serde_json::Value| v["args"][2] == "record-test")
+            .unwrap();
+        assert_eq!(json["env"], serde_json::json!({"A": "\"b\""}));
+    }
+
+    #[test]
+    fn signal() {
+        let err = cmd!("sh", "-c", "kill -9 $$").run().unwrap_err();
+
+        assert!(matches!(err, CmdError::Signaled(_, 9, _)));
+    }
+}
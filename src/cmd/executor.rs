@@ -0,0 +1,298 @@
+use std::cell::RefCell;
+use std::io;
+use std::path::Path;
+use std::process::{self, Command, ExitStatus};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Executes the commands run with [`Cmd`](super::Cmd) instead of spawning processes.
+///
+/// Executors are installed per thread with [`with_executor`] or [`set_executor`], if no
+/// executor is installed commands are spawned as actual processes. Threads spawned with
+/// [`spawn_with_executor`] (e.g. the workers of a [`JobPool`](super::JobPool)) inherit
+/// the executor of the spawning thread, so that tests running in parallel don't see each
+/// other's executors.
+pub trait Executor: Send + Sync {
+    /// Execute `cmd` and return its output.
+    ///
+    /// Returning an [`Err`] means that the command could not be started.
+    fn execute(&self, cmd: &Command) -> io::Result<process::Output>;
+}
+
+thread_local! {
+    static EXECUTOR: RefCell<Option<Arc<dyn Executor>>> = RefCell::new(None);
+}
+
+/// Get the [`Executor`] installed for the current thread.
+pub fn current_executor() -> Option<Arc<dyn Executor>> {
+    EXECUTOR.with(|e| e.borrow().clone())
+}
+
+/// Install `executor` for the current thread and return the previously installed one.
+///
+/// If `executor` is [`None`] commands are spawned as actual processes.
+pub fn set_executor(executor: Option<Arc<dyn Executor>>) -> Option<Arc<dyn Executor>> {
+    EXECUTOR.with(|e| e.replace(executor))
+}
+
+/// Run `f` with `executor` installed for the current thread.
+///
+/// The previously installed executor is restored afterwards, even if `f` panics.
+pub fn with_executor<R>(executor: Arc<dyn Executor>, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<Arc<dyn Executor>>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            set_executor(self.0.take());
+        }
+    }
+
+    let _restore = Restore(set_executor(Some(executor)));
+    f()
+}
+
+/// Spawn a thread running `f` with the [`Executor`] installed for the current thread.
+pub fn spawn_with_executor<T, F>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let executor = current_executor();
+    thread::spawn(move || {
+        set_executor(executor);
+        f()
+    })
+}
+
+/// Create an [`ExitStatus`] for a process that exited with `code`.
+pub fn exit_status(code: i32) -> ExitStatus {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        ExitStatus::from_raw((code & 0xff) << 8)
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::ExitStatusExt;
+        ExitStatus::from_raw(code as u32)
+    }
+}
+
+/// A custom matcher for the arguments of a [`FakeCmd`].
+type ArgsMatcherFn = Box<dyn Fn(&[String]) -> bool + Send + Sync>;
+
+/// The effect of a [`FakeCmd`] executed by a [`FakeExecutor`].
+type Effect = Box<dyn Fn(&Command) -> io::Result<()> + Send + Sync>;
+
+enum ArgsMatcher {
+    Any,
+    Exact(Vec<String>),
+    Prefix(Vec<String>),
+    Fn(ArgsMatcherFn),
+}
+
+/// A scripted command of a [`FakeExecutor`].
+///
+/// Matches a command by its program and arguments, and returns canned output after
+/// optionally performing some side effect (e.g. creating files).
+#[must_use]
+pub struct FakeCmd {
+    program: String,
+    args: ArgsMatcher,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    code: i32,
+    effect: Option<Effect>,
+    once: bool,
+}
+
+impl FakeCmd {
+    /// Create a fake for commands running `program` with any arguments that exits
+    /// successfully without output.
+    ///
+    /// `program` matches if it is equal to the program of the command or to its file
+    /// name without the executable suffix of the platform, so `python` matches
+    /// `/venv/bin/python` (and `python.exe` on windows) but not `python3.11`.
+    pub fn new(program: impl Into<String>) -> Self {
+        FakeCmd {
+            program: program.into(),
+            args: ArgsMatcher::Any,
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            code: 0,
+            effect: None,
+            once: false,
+        }
+    }
+
+    /// Only match commands with exactly `args`.
+    pub fn args(mut self, args: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.args = ArgsMatcher::Exact(args.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Only match commands whose arguments start with `args`.
+    pub fn args_prefix(mut self, args: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.args = ArgsMatcher::Prefix(args.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Only match commands whose arguments satisfy `matcher`.
+    pub fn args_matching(
+        mut self,
+        matcher: impl Fn(&[String]) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.args = ArgsMatcher::Fn(Box::new(matcher));
+        self
+    }
+
+    /// Set the stdout output of the command.
+    pub fn stdout(mut self, stdout: impl Into<Vec<u8>>) -> Self {
+        self.stdout = stdout.into();
+        self
+    }
+
+    /// Set the stderr output of the command.
+    pub fn stderr(mut self, stderr: impl Into<Vec<u8>>) -> Self {
+        self.stderr = stderr.into();
+        self
+    }
+
+    /// Set the exit code of the command.
+    pub fn exit_code(mut self, code: i32) -> Self {
+        self.code = code;
+        self
+    }
+
+    /// Run `effect` with the matched command before returning its output.
+    ///
+    /// If `effect` returns an error the command is treated as failed to start.
+    pub fn effect(
+        mut self,
+        effect: impl Fn(&Command) -> io::Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.effect = Some(Box::new(effect));
+        self
+    }
+
+    /// Only match a single command, afterwards this fake is removed.
+    pub fn once(mut self) -> Self {
+        self.once = true;
+        self
+    }
+
+    fn matches(&self, program: &str, args: &[String]) -> bool {
+        let file_name = Path::new(program)
+            .file_name()
+            .map(|f| f.to_string_lossy())
+            .unwrap_or_default();
+        let file_name = match std::env::consts::EXE_SUFFIX {
+            "" => &file_name,
+            suffix => file_name.strip_suffix(suffix).unwrap_or(&file_name),
+        };
+        let program_matches = program == self.program || file_name == self.program;
+
+        program_matches
+            && match &self.args {
+                ArgsMatcher::Any => true,
+                ArgsMatcher::Exact(expected) => args == &expected[..],
+                ArgsMatcher::Prefix(prefix) => args.starts_with(prefix),
+                ArgsMatcher::Fn(matcher) => matcher(args),
+            }
+    }
+}
+
+/// An [`Executor`] which executes [`FakeCmd`]s instead of spawning processes.
+///
+/// Every command is matched against all fakes in the order they were added, the first
+/// matching fake is used. Commands that match no fake fail to start.
+///
+/// # Examples
+/// ```
+/// # use std::sync::Arc;
+/// use embuild::cmd;
+/// use embuild::cmd::{with_executor, FakeCmd, FakeExecutor};
+///
+/// let fake = Arc::new(
+///     FakeExecutor::new()
+///         .with(FakeCmd::new("git").args(["--version"]).stdout("git version 2.40.0")),
+/// );
+///
+/// let version = with_executor(fake.clone(), || cmd!("git", "--version").stdout()).unwrap();
+///
+/// assert_eq!(version, "git version 2.40.0");
+/// assert_eq!(fake.calls(), [vec!["git", "--version"]]);
+/// ```
+#[derive(Default)]
+pub struct FakeExecutor {
+    fakes: Mutex<Vec<Arc<FakeCmd>>>,
+    calls: Mutex<Vec<Vec<String>>>,
+}
+
+impl FakeExecutor {
+    /// Create an executor without any fakes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `fake` to this executor.
+    pub fn with(self, fake: FakeCmd) -> Self {
+        self.add(fake);
+        self
+    }
+
+    /// Add `fake` to this executor.
+    pub fn add(&self, fake: FakeCmd) {
+        self.fakes.lock().unwrap().push(Arc::new(fake));
+    }
+
+    /// Get all executed commands, each as the program followed by its arguments.
+    pub fn calls(&self) -> Vec<Vec<String>> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+impl Executor for FakeExecutor {
+    fn execute(&self, cmd: &Command) -> io::Result<process::Output> {
+        let program = cmd.get_program().to_string_lossy().into_owned();
+        let args = cmd
+            .get_args()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+
+        self.calls.lock().unwrap().push(
+            std::iter::once(program.clone())
+                .chain(args.clone())
+                .collect(),
+        );
+
+        let mut fakes = self.fakes.lock().unwrap();
+        let index = fakes
+            .iter()
+            .position(|f| f.matches(&program, &args))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no fake command matches {cmd:?}"),
+                )
+            })?;
+
+        let fake = if fakes[index].once {
+            fakes.remove(index)
+        } else {
+            fakes[index].clone()
+        };
+        // Don't hold the lock while running the effect.
+        drop(fakes);
+
+        if let Some(effect) = &fake.effect {
+            effect(cmd)?;
+        }
+
+        Ok(process::Output {
+            status: exit_status(fake.code),
+            stdout: fake.stdout.clone(),
+            stderr: fake.stderr.clone(),
+        })
+    }
+}
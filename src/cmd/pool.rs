@@ -0,0 +1,378 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::sync::{mpsc, Arc, Mutex};
+use std::{env, thread};
+
+use once_cell::sync::Lazy;
+
+use super::{spawn_with_executor, Cmd, CmdError};
+
+/// The jobserver inherited from cargo or make, if any.
+static JOBSERVER: Lazy<Option<jobserver::Client>> = Lazy::new(|| {
+    // SAFETY: The file descriptors of the jobserver are only ever used through this
+    // client, and this is the only place where the client is created.
+    unsafe { jobserver::Client::from_env() }
+});
+
+/// The error returned by [`JobPool::run`] and [`JobPool::run_fns`] if one or more jobs
+/// failed.
+///
+/// Contains the errors of all failed jobs together with their index in the jobs passed
+/// to the pool. The error of a command that could not be run is a [`CmdError`], unless
+/// running it panicked.
+#[derive(Debug)]
+pub struct JobsError {
+    /// The total number of jobs that were run.
+    pub total: usize,
+    /// The index and error of every failed job, ordered by index.
+    pub errors: Vec<(usize, anyhow::Error)>,
+}
+
+impl std::fmt::Display for JobsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} of {} jobs failed", self.errors.len(), self.total)?;
+        for (_, error) in &self.errors {
+            write!(f, "\n- {error}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for JobsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.errors
+            .first()
+            .map(|(_, e)| e.as_ref() as &(dyn std::error::Error + 'static))
+    }
+}
+
+/// A pool that runs [`Cmd`]s (or closures, see [`run_fns`](JobPool::run_fns))
+/// concurrently with a bounded number of jobs.
+///
+/// If this process was started by cargo (e.g. in a build script) or make with a
+/// jobserver, every job besides the first acquires a token from it, so that the
+/// machine is not oversubscribed.
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct JobPool {
+    jobs: usize,
+    use_jobserver: bool,
+}
+
+impl Default for JobPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JobPool {
+    /// Create a pool with the number of jobs given by cargo's `NUM_JOBS` environment
+    /// variable, or the available parallelism if not set.
+    pub fn new() -> Self {
+        let jobs = env::var("NUM_JOBS")
+            .ok()
+            .and_then(|j| j.parse().ok())
+            .or_else(|| thread::available_parallelism().map(Into::into).ok())
+            .unwrap_or(1);
+
+        Self {
+            jobs,
+            use_jobserver: true,
+        }
+    }
+
+    /// Set the maximum number of jobs that are run concurrently.
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs.max(1);
+        self
+    }
+
+    /// Whether to acquire tokens from an inherited jobserver (default: `true`).
+    pub fn jobserver(mut self, use_jobserver: bool) -> Self {
+        self.use_jobserver = use_jobserver;
+        self
+    }
+
+    /// Run all `cmds` to completion and return their outputs in order.
+    ///
+    /// The output of every command is captured. Where [`Cmd::output`] would print it
+    /// (i.e. for failed commands), it is written to the stdout and stderr of this
+    /// process in the order of `cmds` as soon as all previous commands have finished,
+    /// so that the output of different commands never interleaves. Commands in [tee
+    /// mode](Cmd::tee) still stream their output while running.
+    ///
+    /// All commands are run even if some of them fail, in which case the errors of all
+    /// failed commands are returned.
+    pub fn run(
+        &self,
+        cmds: impl IntoIterator<Item = Cmd>,
+    ) -> Result<Vec<process::Output>, JobsError> {
+        let results = self.run_jobs(
+            cmds,
+            |mut cmd: Cmd| {
+                // Print the output in order instead, like `Cmd::output` would.
+                cmd.print_output = false;
+                let print = Printed {
+                    success: cmd.ignore_exitcode && cmd.tee.is_none(),
+                    failure: cmd.tee.is_none(),
+                };
+                let result = panic::catch_unwind(AssertUnwindSafe(|| cmd.output(|output| output)))
+                    .map_err(|panic| {
+                        anyhow::anyhow!(
+                            "running {:?} panicked: {}",
+                            cmd.cmd,
+                            panic_message(&*panic)
+                        )
+                    })
+                    .and_then(|result| result.map_err(Into::into));
+                (result, print)
+            },
+            |(result, print)| print.print(result),
+        );
+
+        collect(
+            results
+                .into_iter()
+                .map(|result| result.map(|(result, _)| result))
+                .collect(),
+        )
+    }
+
+    /// Run all `jobs` to completion and return their results in order.
+    ///
+    /// Like [`run`](Self::run), but for closures that don't (only) run a [`Cmd`], e.g.
+    /// downloads. They are run on threads that [inherit the
+    /// executor](spawn_with_executor) of this thread. All jobs are run even if some of
+    /// them fail, in which case the errors of all failed jobs are returned.
+    pub fn run_fns<T, F>(&self, jobs: impl IntoIterator<Item = F>) -> Result<Vec<T>, JobsError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> anyhow::Result<T> + Send + 'static,
+    {
+        let results = self.run_jobs(
+            jobs.into_iter().enumerate(),
+            |(index, job): (usize, F)| {
+                panic::catch_unwind(AssertUnwindSafe(job)).unwrap_or_else(|panic| {
+                    Err(anyhow::anyhow!(
+                        "job {index} panicked: {}",
+                        panic_message(&*panic)
+                    ))
+                })
+            },
+            |_| (),
+        );
+        collect(results)
+    }
+
+    /// Run `run` with every job on at most [`jobs`](Self::jobs) threads and return the
+    /// results in order, or [`None`] for jobs that were not run.
+    ///
+    /// `on_result` is called on this thread with every result in order, as soon as all
+    /// previous jobs have finished. `run` must not panic.
+    fn run_jobs<J, R>(
+        &self,
+        jobs: impl IntoIterator<Item = J>,
+        run: impl Fn(J) -> R + Send + Sync + 'static,
+        mut on_result: impl FnMut(&R),
+    ) -> Vec<Option<R>>
+    where
+        J: Send + 'static,
+        R: Send + 'static,
+    {
+        let queue = jobs.into_iter().enumerate().collect::<VecDeque<_>>();
+        let total = queue.len();
+        let queue = Arc::new(Mutex::new(queue));
+        let run = Arc::new(run);
+        let jobserver = JOBSERVER.as_ref().filter(|_| self.use_jobserver);
+
+        let (sender, receiver) = mpsc::channel();
+
+        let workers = (0..self.jobs.min(total))
+            .map(|worker| {
+                let queue = queue.clone();
+                let run = run.clone();
+                let sender = sender.clone();
+                let jobserver = jobserver.cloned();
+
+                spawn_with_executor(move || loop {
+                    let next = queue.lock().unwrap().pop_front();
+                    let (index, job) = match next {
+                        Some(job) => job,
+                        None => break,
+                    };
+
+                    // The first worker uses the implicit token of this process.
+                    let token = match &jobserver {
+                        Some(jobserver) if worker > 0 => jobserver.acquire().ok(),
+                        _ => None,
+                    };
+                    let result = run(job);
+                    drop(token);
+
+                    if sender.send((index, result)).is_err() {
+                        break;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(sender);
+
+        let mut results = (0..total).map(|_| None).collect::<Vec<_>>();
+        let mut next_to_report = 0;
+        for (index, result) in receiver {
+            results[index] = Some(result);
+
+            while let Some(Some(result)) = results.get(next_to_report) {
+                on_result(result);
+                next_to_report += 1;
+            }
+        }
+
+        for worker in workers {
+            worker.join().ok();
+        }
+        results
+    }
+}
+
+/// Collect the values of `results`, or else the errors of all failed or not run jobs.
+fn collect<T>(results: Vec<Option<anyhow::Result<T>>>) -> Result<Vec<T>, JobsError> {
+    let total = results.len();
+    let mut values = Vec::with_capacity(total);
+    let mut errors = Vec::new();
+    for (index, result) in results.into_iter().enumerate() {
+        match result {
+            Some(Ok(value)) => values.push(value),
+            Some(Err(e)) => errors.push((index, e)),
+            None => errors.push((index, anyhow::anyhow!("job {index} was not run"))),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(values)
+    } else {
+        Err(JobsError { total, errors })
+    }
+}
+
+/// The message of a caught `panic`.
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(|s| &s[..]))
+        .unwrap_or("unknown panic")
+}
+
+/// Which results of a job are printed.
+struct Printed {
+    success: bool,
+    failure: bool,
+}
+
+impl Printed {
+    fn print(&self, result: &Result<process::Output, anyhow::Error>) {
+        let (stdout, stderr) = match result {
+            Ok(output) if self.success => (&output.stdout[..], &output.stderr[..]),
+            Err(e) if self.failure => match e.downcast_ref::<CmdError>().and_then(CmdError::output)
+            {
+                Some(output) => (output.stdout.as_bytes(), output.stderr.as_bytes()),
+                None => return,
+            },
+            _ => return,
+        };
+
+        io::stdout().write_all(stdout).ok();
+        io::stderr().write_all(stderr).ok();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::cmd;
+    use crate::cmd::{with_executor, FakeCmd, FakeExecutor};
+
+    #[test]
+    fn run_in_order() {
+        let cmds = (0..8).map(|i| {
+            let exit = if i % 3 == 0 { 1 } else { 0 };
+            cmd!(
+                "sh",
+                "-c",
+                format!("sleep 0.0{}; echo {i}; exit {exit}", 8 - i)
+            )
+        });
+
+        let err = JobPool::new().jobs(4).run(cmds).unwrap_err();
+
+        assert_eq!(err.total, 8);
+        assert_eq!(
+            err.errors.iter().map(|(i, _)| *i).collect::<Vec<_>>(),
+            [0, 3, 6]
+        );
+        let error = err.errors[1].1.downcast_ref::<CmdError>().unwrap();
+        assert_eq!(error.output().unwrap().stdout, "3");
+
+        let outputs = JobPool::new()
+            .jobs(3)
+            .run((0..5).map(|i| cmd!("echo", i.to_string())))
+            .unwrap();
+
+        let outputs = outputs
+            .iter()
+            .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(outputs, ["0", "1", "2", "3", "4"]);
+    }
+
+    #[test]
+    fn panicking_job() {
+        let fake = Arc::new(
+            FakeExecutor::new()
+                .with(FakeCmd::new("ok"))
+                .with(FakeCmd::new("panic").effect(|_| panic!("effect panicked"))),
+        );
+
+        let err = with_executor(fake, || {
+            JobPool::new()
+                .jobs(2)
+                .run([Cmd::new("ok"), Cmd::new("panic"), Cmd::new("ok")])
+        })
+        .unwrap_err();
+
+        assert_eq!(err.total, 3);
+        assert_eq!(err.errors.len(), 1);
+        assert_eq!(err.errors[0].0, 1);
+        assert!(err.errors[0].1.to_string().contains("effect panicked"));
+    }
+
+    #[test]
+    fn run_fns() {
+        let values = JobPool::new()
+            .jobs(2)
+            .run_fns((0..5).map(|i| move || Ok(i * 2)))
+            .unwrap();
+        assert_eq!(values, [0, 2, 4, 6, 8]);
+
+        let err = JobPool::new()
+            .jobs(2)
+            .run_fns((0..4).map(|i| {
+                move || match i {
+                    1 => anyhow::bail!("failed"),
+                    2 => panic!("job panicked"),
+                    _ => Ok(i),
+                }
+            }))
+            .unwrap_err();
+        assert_eq!(err.total, 4);
+        assert_eq!(
+            err.errors.iter().map(|(i, _)| *i).collect::<Vec<_>>(),
+            [1, 2]
+        );
+        assert!(err.errors[1].1.to_string().contains("job panicked"));
+    }
+}
@@ -0,0 +1,214 @@
+use std::env;
+#[cfg(feature = "cmd-record")]
+use std::fs::File;
+#[cfg(feature = "cmd-record")]
+use std::io::Write;
+#[cfg(feature = "cmd-record")]
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+
+/// Environment variable that enables recording of all commands into
+/// `$OUT_DIR/`[`RECORD_FILE_NAME`] if set to anything other than `0` or `false`.
+#[cfg(feature = "cmd-record")]
+pub const RECORD_VAR: &str = "EMBUILD_CMD_RECORD";
+/// Environment variable that enables the dry-run mode if set to anything other than `0`
+/// or `false` (see [`set_dry_run`]).
+pub const DRY_RUN_VAR: &str = "EMBUILD_CMD_DRY_RUN";
+/// The name of the file in `OUT_DIR` that commands are recorded to when [`RECORD_VAR`]
+/// is set.
+#[cfg(feature = "cmd-record")]
+pub const RECORD_FILE_NAME: &str = "embuild-cmds.jsonl";
+
+/// A single command run by [`Cmd`](super::Cmd) while recording.
+#[cfg(feature = "cmd-record")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CmdRecord {
+    /// The program that was run.
+    pub program: String,
+    /// The arguments of the program.
+    pub args: Vec<String>,
+    /// The working directory if it was set explicitly.
+    pub cwd: Option<PathBuf>,
+    /// All environment variables that were set (or removed if [`None`]) explicitly.
+    pub env: Vec<(String, Option<String>)>,
+    /// How long the command ran.
+    pub duration: Duration,
+    /// Whether the command was only printed because of the dry-run mode.
+    pub dry_run: bool,
+    /// The error if the command failed.
+    pub error: Option<String>,
+}
+
+#[cfg(feature = "cmd-record")]
+impl CmdRecord {
+    fn new(cmd: &Command, duration: Duration, dry_run: bool, error: Option<String>) -> Self {
+        let lossy = |s: &std::ffi::OsStr| s.to_string_lossy().into_owned();
+
+        CmdRecord {
+            program: lossy(cmd.get_program()),
+            args: cmd.get_args().map(lossy).collect(),
+            cwd: cmd.get_current_dir().map(Path::to_owned),
+            env: cmd
+                .get_envs()
+                .map(|(k, v)| (lossy(k), v.map(lossy)))
+                .collect(),
+            duration,
+            dry_run,
+            error,
+        }
+    }
+
+    /// Serialize this record as a single line of JSON.
+    fn to_json_line(&self) -> String {
+        let env: serde_json::Map<_, _> = self
+            .env
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone().into()))
+            .collect();
+
+        serde_json::json!({
+            "program": self.program,
+            "args": self.args,
+            "cwd": self.cwd,
+            "env": env,
+            "duration_ms": self.duration.as_millis() as u64,
+            "dry_run": self.dry_run,
+            "error": self.error,
+        })
+        .to_string()
+    }
+}
+
+#[cfg(feature = "cmd-record")]
+struct Recorder {
+    file: Option<(PathBuf, File)>,
+    /// The records kept in memory, only if recording was started with
+    /// [`start_recording`].
+    records: Option<Vec<CmdRecord>>,
+}
+
+#[cfg(feature = "cmd-record")]
+impl Recorder {
+    /// Create a recorder that appends to `file`, which is truncated first, and keeps the
+    /// records in memory if `keep` is `true`.
+    fn new(file: Option<PathBuf>, keep: bool) -> Self {
+        let file = file.and_then(|path| match File::create(&path) {
+            Ok(file) => Some((path, file)),
+            Err(e) => {
+                log::warn!(
+                    "Could not create command record file '{}': {e}",
+                    path.display()
+                );
+                None
+            }
+        });
+
+        Recorder {
+            file,
+            records: keep.then(Vec::new),
+        }
+    }
+}
+
+/// The recorder, which is `None` if not yet initialized from the environment.
+#[cfg(feature = "cmd-record")]
+static RECORDER: Lazy<Mutex<Option<Option<Recorder>>>> = Lazy::new(|| Mutex::new(None));
+
+static DRY_RUN: Lazy<Mutex<Option<bool>>> = Lazy::new(|| Mutex::new(None));
+
+fn env_flag(var: &str) -> bool {
+    env::var(var)
+        .map(|v| !v.is_empty() && v != "0" && !v.eq_ignore_ascii_case("false"))
+        .unwrap_or(false)
+}
+
+#[cfg(feature = "cmd-record")]
+fn recorder_from_env() -> Option<Recorder> {
+    if !env_flag(RECORD_VAR) {
+        return None;
+    }
+
+    match env::var_os("OUT_DIR") {
+        Some(out_dir) => Some(Recorder::new(
+            Some(Path::new(&out_dir).join(RECORD_FILE_NAME)),
+            false,
+        )),
+        None => {
+            log::warn!("${RECORD_VAR} is set but $OUT_DIR is not, commands are not recorded");
+            None
+        }
+    }
+}
+
+/// Start recording all commands run by [`Cmd`](super::Cmd) in this process.
+///
+/// The commands are kept in memory until [`stop_recording`]. If `file` is [`Some`], it
+/// is truncated and every command is also appended to it as a line of JSON (JSON Lines)
+/// as soon as it finished. Any previous recording is discarded.
+#[cfg(feature = "cmd-record")]
+pub fn start_recording(file: Option<PathBuf>) {
+    *RECORDER.lock().unwrap() = Some(Some(Recorder::new(file, true)));
+}
+
+/// Stop recording commands and return all commands recorded since [`start_recording`].
+///
+/// Commands recorded because of [`RECORD_VAR`] are only written to the record file, so
+/// none are returned for them. The record file is complete after every command, so
+/// there is nothing left to write and it is simply not appended to anymore.
+#[cfg(feature = "cmd-record")]
+pub fn stop_recording() -> Vec<CmdRecord> {
+    match RECORDER.lock().unwrap().replace(None) {
+        Some(Some(recorder)) => recorder.records.unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+/// Enable or disable the dry-run mode, overriding [`DRY_RUN_VAR`].
+///
+/// In dry-run mode commands run with [`Cmd`](super::Cmd) are printed to stderr instead
+/// of being executed, and behave as if they exited successfully with no output.
+///
+/// Note that this will likely break code that depends on the output of commands.
+pub fn set_dry_run(enabled: bool) {
+    *DRY_RUN.lock().unwrap() = Some(enabled);
+}
+
+/// Whether the dry-run mode is enabled (see [`set_dry_run`]).
+pub fn is_dry_run() -> bool {
+    *DRY_RUN
+        .lock()
+        .unwrap()
+        .get_or_insert_with(|| env_flag(DRY_RUN_VAR))
+}
+
+/// Record `cmd` if recording is enabled.
+#[cfg(feature = "cmd-record")]
+pub(super) fn record(cmd: &Command, duration: Duration, dry_run: bool, error: Option<String>) {
+    let mut recorder = RECORDER.lock().unwrap();
+    let recorder = match recorder.get_or_insert_with(recorder_from_env) {
+        Some(recorder) => recorder,
+        None => return,
+    };
+
+    let record = CmdRecord::new(cmd, duration, dry_run, error);
+    if let Some((path, file)) = &mut recorder.file {
+        let line = format!("{}\n", record.to_json_line());
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            log::warn!(
+                "Could not write command record to '{}': {e}",
+                path.display()
+            );
+        }
+    }
+    if let Some(records) = &mut recorder.records {
+        records.push(record);
+    }
+}
+
+/// Record `cmd` if recording is enabled.
+#[cfg(not(feature = "cmd-record"))]
+pub(super) fn record(_cmd: &Command, _duration: Duration, _dry_run: bool, _error: Option<String>) {}
@@ -13,14 +13,13 @@ use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
 use std::sync::Arc;
 use std::{env, fs};
 
 use anyhow::{anyhow, Context, Error, Result};
 use serde::{Deserialize, Serialize};
 
-use crate::python::PYTHON;
+use crate::cmd::TeeSink;
 use crate::{cmd, git, path_buf, python};
 
 use self::tools_schema::{
@@ -30,6 +29,7 @@ use self::tools_schema::{
 #[cfg(feature = "elf")]
 pub mod ulp_fsm;
 
+mod tools_install;
 mod tools_schema;
 
 pub const DEFAULT_ESP_IDF_REPOSITORY: &str = "https://github.com/espressif/esp-idf.git";
@@ -41,10 +41,19 @@ pub const IDF_PATH_VAR: &str = "IDF_PATH";
 pub const IDF_TOOLS_PATH_VAR: &str = "IDF_TOOLS_PATH";
 
 const IDF_PYTHON_ENV_PATH_VAR: &str = "IDF_PYTHON_ENV_PATH";
+const IDF_MIRROR_PREFIX_MAP_VAR: &str = "IDF_MIRROR_PREFIX_MAP";
 
 /// The global install dir of the esp-idf and its tools, relative to the user home dir.
 pub const GLOBAL_INSTALL_DIR: &str = ".espressif";
 
+/// The lock file in the install dir that is held while installing (see
+/// [`FileLock`](crate::fs::FileLock)).
+pub const INSTALL_LOCK_FILENAME: &str = ".embuild-install.lock";
+
+/// The directory in the install dir of the [`git::ObjectStore`] used if
+/// [`Installer::git_object_store`] is enabled.
+pub const GIT_OBJECT_STORE_DIR: &str = "git-store";
+
 /// Default filename for the file that contains [`EspIdfBuildInfo`].
 pub const BUILD_INFO_FILENAME: &str = "esp-idf-build.json";
 
@@ -53,7 +62,9 @@ pub const BUILD_INFO_FILENAME: &str = "esp-idf-build.json";
 pub struct Tools {
     /// An optional path to the `tools.json` tools index to be used`.
     ///
-    /// This file is passed to the `idf_tools.py` python script.
+    /// The tools are installed from this index (which is passed to the `idf_tools.py`
+    /// python script unless the tools are [installed
+    /// natively](Installer::native_tools_install)).
     pub index: Option<PathBuf>,
     /// All names of the tools that should be installed.
     pub tools: Vec<String>,
@@ -111,7 +122,7 @@ impl Tools {
 }
 
 /// A tool instance describing its properties.
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 struct Tool {
     name: String,
     /// url to obtain the Tool as an compressed binary
@@ -122,6 +133,11 @@ struct Tool {
     sha256: String,
     /// size of the compressed file
     size: i64,
+    /// file name under which the compressed file is downloaded instead of the one in
+    /// the url
+    rename_dist: Option<String>,
+    /// number of top level directories removed when extracting
+    strip_container_dirs: usize,
     /// Base absolute install dir as absolute Path
     install_dir: PathBuf,
     /// Path relative to install dir
@@ -150,17 +166,24 @@ impl Tool {
         }
 
         if let Some(mut test_command) = self.test_command() {
-            log::debug!("Run cmd: {test_command:?} to get current tool version");
-
-            let output = test_command.output().unwrap_or_else(|e| {
-                panic!("Failed to run command: {test_command:?}; error: {e:?}")
-            });
+            log::debug!(
+                "Run cmd: {:?} to get current tool version",
+                test_command.cmd
+            );
+
+            let output = match test_command.stdout() {
+                Ok(output) => output,
+                Err(e) => {
+                    log::debug!("Failed to get the version of tool '{}': {e}", self.name);
+                    return false;
+                }
+            };
 
             if !self.version_regex.is_empty() {
                 let regex =
                     regex::Regex::new(&self.version_regex).expect("Invalid regex pattern provided");
 
-                if let Some(capture) = regex.captures(&String::from_utf8_lossy(&output.stdout)) {
+                if let Some(capture) = regex.captures(&output) {
                     if let Some(var) = capture.get(0) {
                         log::debug!("Match: {:?}, Version: {:?}", var.as_str(), self.version);
                         return true;
@@ -193,13 +216,13 @@ impl Tool {
     /// Creates a Command that will echo back the current version of the tool
     ///
     /// Since Command is non clonable this helper is provided
-    fn test_command(&self) -> Option<Command> {
+    fn test_command(&self) -> Option<cmd::Cmd> {
         (!self.version_cmd_args.is_empty() && !self.version_cmd_args[0].is_empty()).then(|| {
             let cmd_abs_path = self
                 .abs_export_path()
                 .join(self.version_cmd_args[0].clone());
 
-            let mut version_cmd = std::process::Command::new(cmd_abs_path);
+            let mut version_cmd = cmd::Cmd::new(cmd_abs_path);
             version_cmd.args(self.version_cmd_args[1..].iter().cloned());
             version_cmd
         })
@@ -235,6 +258,7 @@ fn parse_tools(
             install_dir: install_dir.clone(),
             version_cmd_args: tool_info.version_cmd.to_vec(),
             version_regex: tool_info.version_regex.to_string(),
+            strip_container_dirs: tool_info.strip_container_dirs.unwrap_or(0).max(0) as usize,
             export_vars: tool_info.export_vars.as_ref().map(|v| v.0.clone()).unwrap_or_default(),
             ..Default::default()
         };
@@ -274,6 +298,7 @@ fn parse_tools(
             tool.url = info.url;
             tool.sha256 = info.sha256;
             tool.size = info.size;
+            tool.rename_dist = info.rename_dist;
             tool.version.clone_from(version.name.as_ref().unwrap());
 
             tool.export_path = PathBuf::new().join("tools").join(&tool.name).join(&tool.version);
@@ -330,6 +355,9 @@ fn parse_tools(
                     if let Some(version_regex) = &info.version_regex {
                         tool.version_regex = version_regex.to_string();
                     }
+                    if let Some(strip) = info.strip_container_dirs.as_ref().and_then(|s| s.parse().ok()) {
+                        tool.strip_container_dirs = strip;
+                    }
                 });
         }
 
@@ -385,6 +413,8 @@ pub struct EspIdf {
     /// Whether [`EspIdf::tree`] is a repository installed and managed by
     /// [`Installer`] and **not** provided by the user.
     pub is_managed_espidf: bool,
+    /// The checked out commit of the esp-idf, if it is a git repository.
+    pub esp_idf_commit: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -406,11 +436,23 @@ impl SourceTree {
             SourceTree::Plain(path) => path,
         }
     }
+
+    /// The checked out commit if this is a git repository.
+    pub fn head_commit(&self) -> Option<String> {
+        match self {
+            SourceTree::Git(repo) => repo.head_commit().ok(),
+            SourceTree::Plain(_) => None,
+        }
+    }
 }
 
 impl EspIdf {
     /// Try to load an activated esp-idf from at the given path.
     /// `idf_path`: Path to an existing `esp-idf` source tree.
+    ///
+    /// The python dependencies are checked against the metadata of the python virtual
+    /// env (see [`python::Venv::check_requirements`]), falling back to the esp-idf
+    /// `check_python_dependencies.py` script if they are not satisfied.
     pub fn try_from(idf_path: &Path) -> Result<EspIdf, NotActivatedError> {
         let esp_idf_dir = SourceTree::open(idf_path);
 
@@ -460,15 +502,34 @@ impl EspIdf {
         let python = which::which_in("python", Some(&path_var), "")
             .with_context(|| anyhow!("python not found in $PATH"))
             .map_err(not_activated)?;
-        let check_python_deps_py =
-            path_buf![esp_idf_dir.path(), "tools", "check_python_dependencies.py"];
-        cmd!(&python, &check_python_deps_py)
-            .stdout()
-            .with_context(|| anyhow!("failed to check python dependencies"))
-            .map_err(not_activated)?;
+        let version = EspIdfVersion::try_from(esp_idf_dir.path());
+        let venv = python
+            .parent()
+            .and_then(Path::parent)
+            .map(python::Venv::new);
+        let tools_dir = env::var_os(IDF_TOOLS_PATH_VAR)
+            .map(PathBuf::from)
+            .unwrap_or_else(Installer::global_install_dir);
+        let is_satisfied = match (&venv, &version) {
+            (Some(venv), Ok(version)) => {
+                python_env_is_satisfied(venv, esp_idf_dir.path(), version, &tools_dir)
+            }
+            _ => false,
+        };
+
+        // fall back to the esp-idf script, which also reports what is missing
+        if !is_satisfied {
+            let check_python_deps_py =
+                path_buf![esp_idf_dir.path(), "tools", "check_python_dependencies.py"];
+            cmd!(&python, &check_python_deps_py)
+                .stdout()
+                .with_context(|| anyhow!("failed to check python dependencies"))
+                .map_err(not_activated)?;
+        }
 
         Ok(EspIdf {
-            version: EspIdfVersion::try_from(esp_idf_dir.path()),
+            version,
+            esp_idf_commit: esp_idf_dir.head_commit(),
             esp_idf_dir,
             exported_path: path_var,
             // Env vars are already set by the parent process
@@ -593,6 +654,8 @@ pub type EspIdfRemote = git::sdk::RemoteSdk;
 pub struct Installer {
     esp_idf_origin: EspIdfOrigin,
     custom_install_dir: Option<PathBuf>,
+    git_object_store: bool,
+    native_tools_install: bool,
     #[allow(clippy::type_complexity)]
     tools_provider:
         Option<Box<dyn FnOnce(&SourceTree, &Result<EspIdfVersion>) -> Result<Vec<Tools>>>>,
@@ -605,6 +668,8 @@ impl Installer {
             esp_idf_origin,
             tools_provider: None,
             custom_install_dir: None,
+            git_object_store: false,
+            native_tools_install: false,
         }
     }
 
@@ -627,6 +692,33 @@ impl Installer {
         self
     }
 
+    /// Share the git objects of all managed esp-idf versions in the install dir.
+    ///
+    /// If enabled, a managed esp-idf and its submodules are cloned with a
+    /// [`git::ObjectStore`] in `<install directory>/git-store` (see
+    /// [`GIT_OBJECT_STORE_DIR`]), so that installing another esp-idf version only fetches
+    /// the objects it does not share with the others. Use [`git::ObjectStore::gc`] to
+    /// remove versions that are no longer used.
+    #[must_use]
+    pub fn git_object_store(mut self, enabled: bool) -> Self {
+        self.git_object_store = enabled;
+        self
+    }
+
+    /// Download, verify and extract the tools natively instead of installing them with
+    /// `idf_tools.py install` (the default).
+    ///
+    /// Tools are installed into `<install directory>/tools/<name>/<version>` like
+    /// `idf_tools.py` does, downloading them concurrently (in a
+    /// [`JobPool`](crate::cmd::JobPool)) into
+    /// `<install directory>/dist` (from the configured mirrors, see
+    /// [`Mirrors::from_env`](crate::utils::Mirrors::from_env)).
+    #[must_use]
+    pub fn native_tools_install(mut self, enabled: bool) -> Self {
+        self.native_tools_install = enabled;
+        self
+    }
+
     /// Install the esp-idf source if a managed ESP-IDF reference was supplied by the user and then install all tools added with [`with_tools`](Self::with_tools).
     ///
     /// The install directory, where the esp-idf source and tools are installed into, is
@@ -640,14 +732,19 @@ impl Installer {
     ///    matching the specified remote repo. If not found, clone it into `<install
     ///    directory>/esp-idf[-<esp-idf-git-url-hash>]/<esp-idf version string>` where
     ///    `esp-idf version string` is the branch name, tag name, or the hash of the
-    ///    commit, if a specific commit was used. Otherwise if it is a
-    ///    [`EspIdfOrigin::Custom`] use that esp-idf repository instead.
+    ///    commit, if a specific commit was used (sharing its objects with the other
+    ///    versions if [`git_object_store`](Self::git_object_store) is enabled). The
+    ///    commit locked in the `embuild.lock` lockfile is used if there is one (see
+    ///    [`git::Lockfile::from_env`]).
+    ///    Otherwise if it is a [`EspIdfOrigin::Custom`] use that esp-idf repository instead.
     /// 2. Create a python virtual env using the system `python` and `idf_tools.py
-    ///    install-python-env` in the install directory.
-    /// 3. Install all tools with `idf_tools.py --tools-json <tools_json> install
-    ///    <tools...>` per [`Tools`] instance added with [`with_tools`](Self::with_tools).
-    ///    `tools_json` is the optional [`Tools::index`] path, if [`None`] the `tools.json`
-    ///    of the esp-idf is used.
+    ///    install-python-env` in the install directory, unless it already exists and
+    ///    satisfies the python requirements of the esp-idf.
+    /// 3. Install all tools of the [`Tools`] instances added with
+    ///    [`with_tools`](Self::with_tools) that are not installed yet, natively (see
+    ///    [`native_tools_install`](Self::native_tools_install)) or with `idf_tools.py
+    ///    --tools-json <tools_json> install <tools...>`. `tools_json` is the optional
+    ///    [`Tools::index`] path, if [`None`] the `tools.json` of the esp-idf is used.
     pub fn install(self) -> Result<EspIdf> {
         let install_dir = self
             .custom_install_dir
@@ -661,11 +758,23 @@ impl Installer {
         })?;
         crate::fs::exclude_from_backups(&install_dir);
 
+        // Other builds may install into the same directory concurrently.
+        let _lock = crate::fs::LockOptions::new()
+            .message("Waiting for another build to finish installing the esp-idf")
+            .lock(install_dir.join(INSTALL_LOCK_FILENAME))?;
+
+        let clone_options = if self.git_object_store {
+            git::CloneOptions::new().object_store(git::ObjectStore::new(
+                install_dir.join(GIT_OBJECT_STORE_DIR),
+            ))
+        } else {
+            git::CloneOptions::new().depth(1)
+        };
         let (esp_idf_dir, managed_repo) = match self.esp_idf_origin {
             EspIdfOrigin::Managed(managed) => (
                 SourceTree::Git(managed.open_or_clone(
                     &install_dir,
-                    git::CloneOptions::new().depth(1),
+                    clone_options,
                     DEFAULT_ESP_IDF_REPOSITORY,
                     MANAGED_ESP_IDF_REPOS_DIR_BASE,
                 )?),
@@ -680,41 +789,41 @@ impl Installer {
 
         // The systems minimal python version for bootstrepping the virtuelenv
         // - By "system python" we refer to the current python executable that is provided to this processs that is
-        //   first found in the env PATH
+        //   first found in the env PATH (or set in `$IDF_PYTHON`/`$PYTHON`)
         // - This will also be the python version used inside the virtualenv
-        let python_version = python::check_python_at_least(3, 6)?;
+        let interpreter = python::Interpreter::find_at_least(3, 6)?;
+        let python_version = interpreter.version;
 
         // Using the idf_tools.py script version that comes with the esp-idf git repository
         let idf_tools_py = path_buf![esp_idf_dir.path(), "tools", "idf_tools.py"];
 
-        // TODO: add virtual_env check to skip install-python-env
-        // running the command cost 2-3 seconds but always makes sure that everything is installed correctly and is up-to-date
-
-        // assumes that the command can be run repeatedly
-        // whenalready installed -> checks for updates and a working state
-        cmd!(PYTHON, &idf_tools_py, "--idf-path", esp_idf_dir.path(), "--non-interactive", "install-python-env";
-        env=(IDF_TOOLS_PATH_VAR, &install_dir), env_remove=("MSYSTEM"), env_remove=(IDF_PYTHON_ENV_PATH_VAR)).run()?;
-
-        // since the above command exited sucessfully -> there should be a virt_env dir
-
         // the idf_tools.py templating name according to https://github.com/espressif/esp-idf/blob/master/tools/idf_tools.py#L99
         // uses always the systems python version -> idf{ESP_IDF_MAJOR_MINOR_VERSION}_py{SYSTEM_PYTHON_MAJOR_MINOR}_env,
 
         // with above knowladge -> construct the python_env_dir implicitly
         let idf_major_minor = format!("{}.{}", esp_version.major, esp_version.minor);
-        let python_major_minor = format!("{}.{}", python_version.major, python_version.minor);
-
-        let python_env_dir_template = format!("idf{idf_major_minor}_py{python_major_minor}_env");
+        let python_env_dir_template = format!("idf{idf_major_minor}_py{python_version}_env");
 
         let python_env_dir = path_buf![&install_dir, "python_env", python_env_dir_template];
+        let venv = python::Venv::new(python_env_dir);
+
+        // running `install-python-env` costs 2-3 seconds, so skip it if the virtual env
+        // already satisfies all python requirements of the esp-idf
+        if python_env_is_satisfied(&venv, esp_idf_dir.path(), &esp_version, &install_dir) {
+            log::debug!(
+                "Python virtual env '{}' is up-to-date",
+                venv.dir().display()
+            );
+        } else {
+            // assumes that the command can be run repeatedly
+            // whenalready installed -> checks for updates and a working state
+            cmd!(&interpreter.program, @&interpreter.args, &idf_tools_py, "--idf-path", esp_idf_dir.path(), "--non-interactive", "install-python-env";
+            env=(IDF_TOOLS_PATH_VAR, &install_dir), env_remove=("MSYSTEM"), env_remove=(IDF_PYTHON_ENV_PATH_VAR), tee=(TeeSink::Parent)).run()?;
+        }
 
         let esp_version = Ok(esp_version);
 
-        #[cfg(windows)]
-        let venv_python = PathBuf::from(python_env_dir).join("Scripts/python");
-
-        #[cfg(not(windows))]
-        let venv_python = python_env_dir.join("bin/python");
+        let venv_python = venv.python();
 
         log::debug!("Start installing tools");
 
@@ -726,26 +835,34 @@ impl Installer {
             .map(|p| p(&esp_idf_dir, &esp_version))
             .unwrap_or(Ok(Vec::new()))?;
 
-        let tools_wanted = tools.clone();
-        let tools_wanted: Vec<&str> = tools_wanted
-            .iter()
-            .flat_map(|tool| tool.tools.iter().map(|s| s.as_str()))
-            .collect();
-
         let tools_json = esp_idf_dir.path().join("tools/tools.json");
 
-        let tools_vec = parse_tools(
-            tools_wanted.clone(),
-            tools_json.clone(),
-            install_dir.clone(),
-        )
-        .unwrap();
-
-        //let tools_vec = parse_into_tools(tools_wanted, tools_json, install_dir.clone())?;
+        let mut tools_vec = Vec::new();
+        for tool_set in &tools {
+            tools_vec.extend(parse_tools(
+                tool_set.tools.iter().map(String::as_str).collect(),
+                tool_set.index.clone().unwrap_or_else(|| tools_json.clone()),
+                install_dir.clone(),
+            )?);
+        }
 
         let all_tools_installed = tools_vec.iter().all(|tool| tool.test());
 
-        if !all_tools_installed {
+        if !all_tools_installed && self.native_tools_install {
+            let missing: Vec<_> = tools_vec
+                .iter()
+                .filter(|tool| !tool.test())
+                .cloned()
+                .collect();
+            tools_install::install_tools(&missing, &crate::utils::Downloader::new())?;
+
+            if !tools_vec.iter().all(|tool| tool.test()) {
+                return Err(anyhow::Error::msg("Could not install all requested Tools"));
+            }
+        } else if !all_tools_installed {
+            // Make `idf_tools.py` download the tools from the configured mirrors.
+            let mirror_prefix_map = crate::utils::Mirrors::from_env()?.to_idf_mirror_prefix_map();
+
             for tool_set in tools {
                 let tools_json = tool_set
                     .index
@@ -757,7 +874,8 @@ impl Installer {
                     .flatten();
 
                 cmd!(&venv_python, &idf_tools_py, "--idf-path", esp_idf_dir.path(), @tools_json.clone(), "install"; 
-                     env=(IDF_TOOLS_PATH_VAR, &install_dir), args=(tool_set.tools)).run()?;
+                     env=(IDF_TOOLS_PATH_VAR, &install_dir), args=(tool_set.tools), tee=(TeeSink::Parent),
+                     envs=(mirror_prefix_map.iter().map(|map| (IDF_MIRROR_PREFIX_MAP_VAR, map)))).run()?;
             }
 
             // Test again if all tools are now installed correctly
@@ -796,6 +914,7 @@ impl Installer {
         log::debug!("Using PATH='{}'", paths.to_string_lossy());
 
         Ok(EspIdf {
+            esp_idf_commit: esp_idf_dir.head_commit(),
             esp_idf_dir,
             exported_path: paths,
             exported_env_vars: env_vars,
@@ -815,11 +934,71 @@ impl Installer {
     }
 }
 
+/// Get the python requirements of the esp-idf in `esp_idf_dir` (with the constraints
+/// downloaded by `idf_tools.py` into `tools_dir` for esp-idf 5.0 and later).
+fn python_requirements(
+    esp_idf_dir: &Path,
+    version: &EspIdfVersion,
+    tools_dir: &Path,
+) -> Result<python::Requirements> {
+    if version.major < 5 {
+        return python::Requirements::from_file(esp_idf_dir.join("requirements.txt"));
+    }
+
+    let mut requirements = python::Requirements::from_file(path_buf![
+        esp_idf_dir,
+        "tools",
+        "requirements",
+        "requirements.core.txt"
+    ])?;
+    requirements.add_constraints_file(tools_dir.join(format!(
+        "espidf.constraints.v{}.{}.txt",
+        version.major, version.minor
+    )))?;
+    Ok(requirements)
+}
+
+/// Whether the python virtual env `venv` satisfies all python requirements of the
+/// esp-idf, checked without running python.
+fn python_env_is_satisfied(
+    venv: &python::Venv,
+    esp_idf_dir: &Path,
+    version: &EspIdfVersion,
+    tools_dir: &Path,
+) -> bool {
+    let unsatisfied = python_requirements(esp_idf_dir, version, tools_dir)
+        .and_then(|requirements| venv.check_requirements(&requirements));
+
+    match unsatisfied {
+        Ok(unsatisfied) if unsatisfied.is_empty() => venv.python().exists(),
+        Ok(unsatisfied) => {
+            let unsatisfied = unsatisfied
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>();
+            log::debug!(
+                "Python virtual env '{}' does not satisfy {}",
+                venv.dir().display(),
+                unsatisfied.join(", ")
+            );
+            false
+        }
+        Err(e) => {
+            log::debug!(
+                "Could not check python virtual env '{}': {e:#}",
+                venv.dir().display()
+            );
+            false
+        }
+    }
+}
+
 /// Parse a [`git::Ref`] from an esp-idf version string.
 ///
 /// The version string can have the following format:
-/// - `commit:<hash>`: Uses the commit `<hash>` of the `esp-idf` repository. Note that
-///   this will clone the whole `esp-idf` not just one commit.
+/// - `commit:<hash>`: Uses the commit `<hash>` of the `esp-idf` repository. Only this
+///   commit is fetched if `<hash>` is a full commit hash, otherwise this will clone the
+///   whole `esp-idf`.
 /// - `tag:<tag>`: Uses the tag `<tag>` of the `esp-idf` repository.
 /// - `branch:<branch>`: Uses the branch `<branch>` of the `esp-idf` repository.
 /// - `v<major>.<minor>` or `<major>.<minor>`: Uses the tag `v<major>.<minor>` of the `esp-idf` repository.
@@ -833,6 +1012,10 @@ pub fn parse_esp_idf_git_ref(version: &str) -> git::Ref {
 pub struct EspIdfBuildInfo {
     /// The directory of the local cloned esp-idf repository that was used for the build.
     pub esp_idf_dir: PathBuf,
+    /// The commit of the esp-idf that was used for the build (see
+    /// [`EspIdf::esp_idf_commit`]).
+    #[serde(default)]
+    pub esp_idf_commit: Option<String>,
     /// The exported PATH environment variable containing all tools.
     pub exported_path_var: String,
     /// Path to the python executable in the esp-idf virtual environment.
@@ -862,9 +1045,9 @@ impl EspIdfBuildInfo {
 
     /// Save as a JSON file at `path`.
     pub fn save_json(&self, path: impl AsRef<Path>) -> Result<()> {
-        let file = std::fs::File::create(&path)
+        let json = serde_json::to_vec_pretty(self)?;
+        crate::fs::write_atomic_if_changed(&path, json)
             .with_context(|| anyhow!("Could not write {}", path.as_ref().display()))?;
-        serde_json::to_writer_pretty(file, self)?;
         Ok(())
     }
 }
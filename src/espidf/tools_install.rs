@@ -0,0 +1,238 @@
+//! Native installation of esp-idf tools, replacing `idf_tools.py install`.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use sha2::{Digest, Sha256};
+
+use super::Tool;
+use crate::cmd::JobPool;
+use crate::fs::{extract, ExtractOptions};
+use crate::utils::Downloader;
+
+/// The directory in the install dir where `idf_tools.py` stores the downloaded archives.
+const DIST_DIR: &str = "dist";
+
+impl Tool {
+    /// The directory this tool is installed to: `<install dir>/tools/<name>/<version>`.
+    fn version_dir(&self) -> PathBuf {
+        self.install_dir
+            .join("tools")
+            .join(&self.name)
+            .join(&self.version)
+    }
+
+    /// Download this tool into `<install dir>/dist`, verify its checksum and size and
+    /// extract it into its [version dir](Self::version_dir), like `idf_tools.py install`.
+    ///
+    /// An archive that was already downloaded is reused if it is intact.
+    fn install(&self, downloader: &Downloader) -> Result<()> {
+        if self.url.is_empty() {
+            bail!("no recommended version for this platform");
+        }
+        let file_name = match &self.rename_dist {
+            Some(name) => name.as_str(),
+            None => self.url.rsplit('/').next().unwrap_or_default(),
+        };
+        let archive = self.install_dir.join(DIST_DIR).join(file_name);
+        let size = u64::try_from(self.size).ok();
+
+        if is_intact(&archive, &self.sha256, size)? {
+            log::debug!("Using downloaded archive '{}'", archive.display());
+        } else {
+            log::info!(
+                "Downloading {} {} from {}",
+                self.name,
+                self.version,
+                self.url
+            );
+            downloader.download_checked(&self.url, &archive, &self.sha256, size)?;
+        }
+
+        // Extract into a temporary directory first, so that an interrupted build does
+        // not leave a partially installed tool behind.
+        let dest = self.version_dir();
+        let tmp = dest.with_file_name(format!("{}.tmp", self.version));
+        if tmp.exists() {
+            remove_dir_all::remove_dir_all(&tmp)?;
+        }
+        extract(
+            &archive,
+            &tmp,
+            &ExtractOptions::new().strip_components(self.strip_container_dirs),
+        )?;
+        if dest.exists() {
+            remove_dir_all::remove_dir_all(&dest)?;
+        }
+        fs::rename(&tmp, &dest)
+            .with_context(|| format!("could not move '{}' to '{}'", tmp.display(), dest.display()))
+    }
+}
+
+/// Install all `tools` concurrently in a [`JobPool`] and return the errors of all tools
+/// that could not be installed.
+pub(super) fn install_tools(tools: &[Tool], downloader: &Downloader) -> Result<()> {
+    let mut version_dirs = HashSet::new();
+    let jobs = tools
+        .iter()
+        // Tools may be requested more than once.
+        .filter(|tool| version_dirs.insert(tool.version_dir()))
+        .cloned()
+        .map(|tool| {
+            let downloader = downloader.clone();
+            move || {
+                tool.install(&downloader).with_context(|| {
+                    format!("could not install tool {} {}", tool.name, tool.version)
+                })
+            }
+        });
+
+    let errors = match JobPool::new().run_fns(jobs) {
+        Ok(_) => return Ok(()),
+        Err(e) => e.errors,
+    };
+    if errors.len() == 1 {
+        return Err(errors.into_iter().next().unwrap().1);
+    }
+    Err(anyhow!(
+        "could not install {} tools:\n{}",
+        errors.len(),
+        errors
+            .iter()
+            .map(|(_, e)| format!("{e:#}"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    ))
+}
+
+/// Whether `path` exists with the `sha256` checksum and `size`.
+fn is_intact(path: &Path, sha256: &str, size: Option<u64>) -> Result<bool> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    if size.map_or(false, |size| size != metadata.len()) {
+        return Ok(false);
+    }
+
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    let hash: String = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    Ok(hash.eq_ignore_ascii_case(sha256))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::cmd::{with_executor, FakeCmd, FakeExecutor};
+    use crate::utils::Mirrors;
+
+    /// Create `tool-1.0.tar.gz` in `dir`, containing `tool-1.0/bin/tool`, and return
+    /// its sha256 and size.
+    fn create_archive(dir: &Path) -> (String, u64) {
+        let archive = dir.join("tool-1.0.tar.gz");
+        let encoder = flate2::write::GzEncoder::new(
+            File::create(&archive).unwrap(),
+            flate2::Compression::default(),
+        );
+        let mut builder = tar::Builder::new(encoder);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o755);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "tool-1.0/bin/tool", &b"tool"[..])
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let data = fs::read(&archive).unwrap();
+        let sha256 = Sha256::digest(&data)
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        (sha256, data.len() as u64)
+    }
+
+    #[test]
+    fn install() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = dir.path().join("server");
+        fs::create_dir(&server).unwrap();
+        let (sha256, size) = create_archive(&server);
+        let install_dir = dir.path().join("install");
+
+        let downloader = Downloader::new().mirrors(
+            Mirrors::new()
+                .with("https://example.com/dl", server.to_str().unwrap())
+                .unwrap(),
+        );
+        let tool = Tool {
+            name: "tool".into(),
+            version: "1.0".into(),
+            url: "https://example.com/dl/tool-1.0.tar.gz".into(),
+            sha256,
+            size: size as i64,
+            strip_container_dirs: 1,
+            install_dir: install_dir.clone(),
+            ..Default::default()
+        };
+        let installed = install_dir.join("tools/tool/1.0/bin/tool");
+
+        install_tools(std::slice::from_ref(&tool), &downloader).unwrap();
+        assert_eq!(fs::read(&installed).unwrap(), b"tool");
+        assert!(install_dir.join("dist/tool-1.0.tar.gz").exists());
+
+        // The downloaded archive is reused.
+        fs::remove_file(server.join("tool-1.0.tar.gz")).unwrap();
+        fs::write(&installed, "modified").unwrap();
+        install_tools(std::slice::from_ref(&tool), &downloader).unwrap();
+        assert_eq!(fs::read(&installed).unwrap(), b"tool");
+
+        // A corrupt archive is downloaded again, which fails here.
+        fs::write(install_dir.join("dist/tool-1.0.tar.gz"), "corrupt").unwrap();
+        let error = install_tools(&[tool.clone(), tool], &downloader).unwrap_err();
+        assert!(format!("{error:#}").starts_with("could not install tool tool 1.0"));
+    }
+
+    #[test]
+    fn test_installed_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let tool = Tool {
+            name: "tool".into(),
+            version: "1.0".into(),
+            install_dir: dir.path().to_owned(),
+            export_path: "tools/tool/1.0/bin".into(),
+            version_cmd_args: vec!["tool".into(), "--version".into()],
+            version_regex: r"[0-9]+\.[0-9]+".into(),
+            ..Default::default()
+        };
+        let fake_version = |stdout: &str, code| {
+            Arc::new(
+                FakeExecutor::new().with(
+                    FakeCmd::new("tool")
+                        .args(["--version"])
+                        .stdout(stdout)
+                        .exit_code(code),
+                ),
+            )
+        };
+
+        // The tool is not installed.
+        assert!(!with_executor(fake_version("tool 1.0", 0), || tool.test()));
+
+        fs::create_dir_all(tool.abs_export_path()).unwrap();
+        assert!(with_executor(fake_version("tool 1.0", 0), || tool.test()));
+        assert!(!with_executor(fake_version("unknown", 0), || tool.test()));
+        assert!(!with_executor(fake_version("tool 1.0", 1), || tool.test()));
+    }
+}
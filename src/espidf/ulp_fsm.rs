@@ -113,25 +113,36 @@ impl Builder {
     where
         I: IntoIterator<Item = &'a Path>,
     {
-        for ulp_source in ulp_sources {
-            std::fs::create_dir_all(out_dir)?;
+        std::fs::create_dir_all(out_dir)?;
 
+        let mut preprocess = Vec::new();
+        let mut assemble = Vec::new();
+        for ulp_source in ulp_sources {
             let ulp_preprocessed_source = Self::resuffix(ulp_source, out_dir, "ulp.S")?;
-
-            self.preprocess_one(ulp_source, include_args, &ulp_preprocessed_source)?;
-
             let ulp_object = Self::resuffix(ulp_source, out_dir, "o")?;
 
-            self.compile_one(&ulp_preprocessed_source, &ulp_object)?;
+            preprocess.push(self.preprocess_cmd(
+                ulp_source,
+                include_args,
+                &ulp_preprocessed_source,
+            )?);
+            assemble.push(self.compile_cmd(&ulp_preprocessed_source, &ulp_object)?);
         }
 
+        let pool = cmd::JobPool::new();
+        pool.run(preprocess)?;
+        pool.run(assemble)?;
+
         Ok(())
     }
 
-    fn compile_one(&self, ulp_source: &Path, out_file: &Path) -> anyhow::Result<()> {
-        cmd![self.tool("esp32ulp-elf-as")?, "-o", out_file, ulp_source].run()?;
-
-        Ok(())
+    fn compile_cmd(&self, ulp_source: &Path, out_file: &Path) -> anyhow::Result<cmd::Cmd> {
+        Ok(cmd![
+            self.tool("esp32ulp-elf-as")?,
+            "-o",
+            out_file,
+            ulp_source
+        ])
     }
 
     fn preprocess_one(
@@ -140,7 +151,18 @@ impl Builder {
         include_args: &[impl AsRef<OsStr>],
         out_file: &Path,
     ) -> anyhow::Result<()> {
-        cmd![
+        self.preprocess_cmd(source, include_args, out_file)?.run()?;
+
+        Ok(())
+    }
+
+    fn preprocess_cmd(
+        &self,
+        source: &Path,
+        include_args: &[impl AsRef<OsStr>],
+        out_file: &Path,
+    ) -> anyhow::Result<cmd::Cmd> {
+        Ok(cmd![
             self.tool(self.gcc.as_deref().unwrap_or("xtensa-esp32-elf-gcc"))?,
             "-E",
             "-P",
@@ -150,10 +172,7 @@ impl Builder {
             "-o",
             out_file,
             source
-        ]
-        .run()?;
-
-        Ok(())
+        ])
     }
 
     fn link(
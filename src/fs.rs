@@ -1,11 +1,20 @@
 //! Filesystem utilities.
 
 use std::fs::{self, File};
-use std::io::{self, Read};
+use std::io::{self, Read, Write};
 use std::path::Path;
 
 use anyhow::Result;
 
+#[cfg(feature = "archive")]
+mod extract;
+mod lock;
+mod sync;
+#[cfg(feature = "archive")]
+pub use extract::*;
+pub use lock::*;
+pub use sync::*;
+
 /// Marks the directory as excluded from archives/backups by writing a `CACHEDIR.TAG` file.
 ///
 /// This prevents derived/temporary files from bloating backups. Errors are ignored since
@@ -33,8 +42,7 @@ pub fn exclude_from_backups(path: &Path) {
 /// Copy `src_file` to `dest_file_or_dir` if `src_file` is different or the destination
 /// file doesn't exist.
 ///
-/// ### Panics
-/// If `src_file` is not a file this function will panic.
+/// Returns an error if `src_file` is not a file.
 pub fn copy_file_if_different(
     src_file: impl AsRef<Path>,
     dest_file_or_dir: impl AsRef<Path>,
@@ -42,7 +50,9 @@ pub fn copy_file_if_different(
     let src_file: &Path = src_file.as_ref();
     let dest_file_or_dir: &Path = dest_file_or_dir.as_ref();
 
-    assert!(src_file.is_file());
+    if !src_file.is_file() {
+        anyhow::bail!("'{}' is not a file", src_file.display());
+    }
 
     let src_fd = fs::File::open(src_file)?;
 
@@ -76,6 +86,54 @@ pub fn copy_file_if_different(
     Ok(())
 }
 
+/// Write `contents` to `path` atomically, unless the file already has exactly these
+/// contents.
+///
+/// The contents are first written to a temporary file in the same directory which is
+/// then renamed to `path`, so that an interrupted build never leaves a truncated file
+/// behind. Leaving unchanged files untouched keeps their mtime, which would otherwise
+/// trigger recompilation of everything that depends on them.
+///
+/// Returns whether the file was written.
+pub fn write_atomic_if_changed(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<bool> {
+    let (path, contents) = (path.as_ref(), contents.as_ref());
+
+    match fs::metadata(path) {
+        Ok(meta) if meta.is_file() && meta.len() == contents.len() as u64 => {
+            if fs::read(path)? == contents {
+                return Ok(false);
+            }
+        }
+        Ok(_) => (),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("'{}' is not a file path", path.display()))?;
+    let tmp_path = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+
+    let result = (|| {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&tmp_path, path)
+    })();
+
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(anyhow::Error::new(e).context(format!("Could not write '{}'", path.display())));
+    }
+
+    Ok(true)
+}
+
 /// Whether the file type and contents of `file` are equal to `other`.
 pub fn is_file_eq(file: &File, other: &File) -> Result<bool> {
     let file_meta = file.metadata()?;
@@ -125,3 +183,24 @@ pub fn copy_with_metadata(src_file: impl AsRef<Path>, dest_file: impl AsRef<Path
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_atomic() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.rs");
+
+        assert!(write_atomic_if_changed(&path, "a").unwrap());
+        let mtime = fs::metadata(&path).unwrap().modified().unwrap();
+
+        assert!(!write_atomic_if_changed(&path, "a").unwrap());
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), mtime);
+
+        assert!(write_atomic_if_changed(&path, "b").unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "b");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,532 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+/// The format of an archive that can be extracted with [`extract`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// An uncompressed `.tar` archive.
+    Tar,
+    /// A gzip compressed `.tar.gz` or `.tgz` archive.
+    TarGz,
+    /// A xz compressed `.tar.xz` or `.txz` archive.
+    TarXz,
+    /// A bzip2 compressed `.tar.bz2` or `.tbz2` archive.
+    TarBz2,
+    /// A `.zip` archive.
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Detect the format of `archive` from its file name.
+    pub fn from_path(archive: impl AsRef<Path>) -> Option<Self> {
+        let name = archive.as_ref().file_name()?.to_str()?.to_ascii_lowercase();
+
+        [
+            (".tar", Self::Tar),
+            (".tar.gz", Self::TarGz),
+            (".tgz", Self::TarGz),
+            (".tar.xz", Self::TarXz),
+            (".txz", Self::TarXz),
+            (".tar.bz2", Self::TarBz2),
+            (".tbz2", Self::TarBz2),
+            (".zip", Self::Zip),
+        ]
+        .into_iter()
+        .find(|(ext, _)| name.ends_with(ext))
+        .map(|(_, format)| format)
+    }
+}
+
+/// The options of [`extract`].
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct ExtractOptions {
+    format: Option<ArchiveFormat>,
+    strip_components: usize,
+    preserve_permissions: bool,
+}
+
+impl Default for ExtractOptions {
+    fn default() -> Self {
+        Self {
+            format: None,
+            strip_components: 0,
+            preserve_permissions: true,
+        }
+    }
+}
+
+impl ExtractOptions {
+    /// Create options that detect the format from the file name and extract all entries
+    /// with their permissions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use `format` instead of detecting the format from the file name of the archive.
+    pub fn format(mut self, format: ArchiveFormat) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    /// Remove the first `count` components from the path of every entry, entries with
+    /// fewer components are skipped.
+    ///
+    /// This is useful for archives which contain all files in a single top-level
+    /// directory.
+    pub fn strip_components(mut self, count: usize) -> Self {
+        self.strip_components = count;
+        self
+    }
+
+    /// Whether to set the unix permissions of extracted files (default: `true`).
+    ///
+    /// This has no effect on windows.
+    pub fn preserve_permissions(mut self, preserve: bool) -> Self {
+        self.preserve_permissions = preserve;
+        self
+    }
+}
+
+/// Extract the `.tar(.gz|.xz|.bz2)` or `.zip` `archive` into the directory `dest`.
+///
+/// Entries with absolute paths or `..` components, and symlinks that point outside of
+/// `dest` are rejected with an error. On windows, where creating symlinks usually
+/// requires special privileges, symlinks are replaced by a copy of their target if it
+/// is part of the archive.
+pub fn extract(
+    archive: impl AsRef<Path>,
+    dest: impl AsRef<Path>,
+    options: &ExtractOptions,
+) -> Result<()> {
+    let (archive, dest) = (archive.as_ref(), dest.as_ref());
+
+    let format = options
+        .format
+        .or_else(|| ArchiveFormat::from_path(archive))
+        .ok_or_else(|| anyhow!("Unknown archive format of '{}'", archive.display()))?;
+
+    let file = BufReader::new(
+        File::open(archive).with_context(|| format!("Could not open '{}'", archive.display()))?,
+    );
+
+    fs::create_dir_all(dest)?;
+    let mut extractor = Extractor {
+        dest,
+        options,
+        deferred_links: Vec::new(),
+    };
+
+    match format {
+        ArchiveFormat::Tar => extractor.extract_tar(file),
+        ArchiveFormat::TarGz => extractor.extract_tar(flate2::read::GzDecoder::new(file)),
+        ArchiveFormat::TarXz => extractor.extract_tar(xz2::read::XzDecoder::new(file)),
+        ArchiveFormat::TarBz2 => extractor.extract_tar(bzip2::read::BzDecoder::new(file)),
+        ArchiveFormat::Zip => extractor.extract_zip(file),
+    }
+    .and_then(|_| extractor.finish())
+    .with_context(|| {
+        format!(
+            "Failed to extract '{}' to '{}'",
+            archive.display(),
+            dest.display()
+        )
+    })
+}
+
+enum Link {
+    Symbolic,
+    Hard,
+}
+
+struct Extractor<'a> {
+    dest: &'a Path,
+    options: &'a ExtractOptions,
+    /// Links that are created after all files were extracted (`(link, target, kind)`).
+    deferred_links: Vec<(PathBuf, PathBuf, Link)>,
+}
+
+impl Extractor<'_> {
+    fn extract_tar(&mut self, reader: impl Read) -> Result<()> {
+        let mut archive = tar::Archive::new(reader);
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = match self.dest_path(&entry.path()?)? {
+                Some(path) => path,
+                None => continue,
+            };
+            let header = entry.header();
+            let mode = header.mode().ok();
+            let mtime = header.mtime().ok();
+
+            match header.entry_type() {
+                tar::EntryType::Directory => fs::create_dir_all(&path)?,
+                tar::EntryType::Regular | tar::EntryType::Continuous => {
+                    self.write_file(&path, &mut entry, mode)?;
+                    if let Some(mtime) = mtime {
+                        let mtime = filetime::FileTime::from_unix_time(mtime as i64, 0);
+                        filetime::set_file_mtime(&path, mtime)?;
+                    }
+                }
+                tar::EntryType::Symlink => {
+                    let target = entry
+                        .link_name()?
+                        .ok_or_else(|| anyhow!("Symlink '{}' has no target", path.display()))?
+                        .into_owned();
+                    self.add_symlink(path, target)?;
+                }
+                tar::EntryType::Link => {
+                    let target = entry
+                        .link_name()?
+                        .ok_or_else(|| anyhow!("Hard link '{}' has no target", path.display()))?;
+                    if let Some(target) = self.dest_path(&target)? {
+                        self.deferred_links.push((path, target, Link::Hard));
+                    }
+                }
+                // Extended headers and other metadata are handled by the tar crate.
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn extract_zip(&mut self, reader: impl Read + io::Seek) -> Result<()> {
+        const S_IFMT: u32 = 0o170000;
+        const S_IFLNK: u32 = 0o120000;
+
+        let mut archive = zip::ZipArchive::new(reader)?;
+
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i)?;
+            let path = match self.dest_path(Path::new(entry.name()))? {
+                Some(path) => path,
+                None => continue,
+            };
+            let mode = entry.unix_mode();
+
+            if entry.is_dir() {
+                fs::create_dir_all(&path)?;
+            } else if matches!(mode, Some(mode) if mode & S_IFMT == S_IFLNK) {
+                let mut target = String::new();
+                entry.read_to_string(&mut target)?;
+                self.add_symlink(path, target.into())?;
+            } else {
+                self.write_file(&path, &mut entry, mode)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the destination of the entry `path`, or [`None`] if it is stripped.
+    fn dest_path(&self, path: &Path) -> Result<Option<PathBuf>> {
+        let mut components = Vec::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(c) => components.push(c),
+                Component::CurDir => (),
+                _ => bail!(
+                    "Refusing to extract entry with unsafe path '{}'",
+                    path.display()
+                ),
+            }
+        }
+
+        if components.len() <= self.options.strip_components {
+            return Ok(None);
+        }
+
+        Ok(Some(
+            components[self.options.strip_components..]
+                .iter()
+                .fold(self.dest.to_owned(), |p, c| p.join(c)),
+        ))
+    }
+
+    fn write_file(&self, path: &Path, reader: &mut impl Read, mode: Option<u32>) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Don't write through an existing symlink.
+        if fs::symlink_metadata(path).is_ok() {
+            fs::remove_file(path)?;
+        }
+
+        io::copy(reader, &mut File::create(path)?)?;
+
+        #[cfg(unix)]
+        if let (true, Some(mode)) = (self.options.preserve_permissions, mode) {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))?;
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+
+        Ok(())
+    }
+
+    fn add_symlink(&mut self, path: PathBuf, target: PathBuf) -> Result<()> {
+        // Reject targets that obviously point outside of `dest` early, the target is
+        // resolved against the links that actually exist when the link is created.
+        let parent = path.parent().unwrap_or(self.dest);
+        if resolve_lexically(self.dest, parent, &target).is_none() {
+            bail!(
+                "Refusing to extract symlink '{}' to '{}' outside of the destination",
+                path.display(),
+                target.display()
+            );
+        }
+
+        self.deferred_links.push((path, target, Link::Symbolic));
+        Ok(())
+    }
+
+    /// Create all links, after their targets were extracted.
+    ///
+    /// Hard links are created first, so that no file is written through a symlink. Every
+    /// symlink is created in the real (canonical) directory of its parent, and its target
+    /// is resolved against the symlinks created before it, so that a chain of links (e.g.
+    /// `a -> .` and `a/b -> ..`) can't escape `dest`. As a later link can change what an
+    /// earlier one points to, all symlinks are checked again at the end.
+    fn finish(mut self) -> Result<()> {
+        let links = std::mem::take(&mut self.deferred_links);
+        let (hard, symbolic): (Vec<_>, Vec<_>) = links
+            .into_iter()
+            .partition(|(_, _, kind)| matches!(kind, Link::Hard));
+
+        for (path, target, _) in hard {
+            self.prepare_link_path(&path)?;
+            if fs::hard_link(&target, &path).is_err() {
+                fs::copy(&target, &path)?;
+            }
+        }
+
+        let dest = fs::canonicalize(self.dest)?;
+        let mut created = Vec::new();
+        for (path, target, _) in symbolic {
+            let rel_parent = path
+                .parent()
+                .and_then(|p| p.strip_prefix(self.dest).ok())
+                .unwrap_or_else(|| Path::new(""));
+            let escape = || {
+                anyhow!(
+                    "Refusing to extract symlink '{}' to '{}' outside of the destination",
+                    path.display(),
+                    target.display()
+                )
+            };
+            let parent = resolve(&dest, &dest, rel_parent).ok_or_else(escape)?;
+            #[cfg_attr(unix, allow(unused_variables))]
+            let resolved = resolve(&dest, &parent, &target).ok_or_else(escape)?;
+
+            let link = parent.join(path.file_name().unwrap());
+            self.prepare_link_path(&link)?;
+            created.push((link.clone(), parent, target.clone()));
+
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(&target, &link)?;
+            #[cfg(not(unix))]
+            if resolved.is_file() {
+                fs::copy(&resolved, &link)?;
+            } else {
+                log::warn!(
+                    "Skipping symlink '{}' to '{}' which is not a file",
+                    path.display(),
+                    target.display()
+                );
+            }
+        }
+
+        for (link, parent, target) in &created {
+            if resolve(&dest, parent, target).is_none() {
+                fs::remove_file(link)?;
+                bail!(
+                    "Refusing to extract symlink in '{}' to '{}' outside of the destination",
+                    parent.display(),
+                    target.display()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Create the parent directories of the link `path` and remove an existing file.
+    fn prepare_link_path(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if fs::symlink_metadata(path).is_ok() {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Resolve `rel` relative to the directory `base` inside of `dest` without accessing the
+/// file system, or return [`None`] if it points outside of `dest`.
+fn resolve_lexically(dest: &Path, base: &Path, rel: &Path) -> Option<PathBuf> {
+    let mut resolved = base.to_owned();
+    for component in rel.components() {
+        match component {
+            Component::Normal(c) => resolved.push(c),
+            Component::CurDir => (),
+            Component::ParentDir if resolved != dest => {
+                resolved.pop();
+            }
+            _ => return None,
+        }
+    }
+    Some(resolved).filter(|r| r.starts_with(dest))
+}
+
+/// Resolve `rel` relative to the directory `base` inside of the canonical directory
+/// `dest`, following all symlinks that exist, or return [`None`] if it points outside of
+/// `dest`.
+fn resolve(dest: &Path, base: &Path, rel: &Path) -> Option<PathBuf> {
+    let mut resolved = base.to_owned();
+    for component in rel.components() {
+        match component {
+            Component::Normal(c) => {
+                resolved.push(c);
+                if let Ok(canonical) = fs::canonicalize(&resolved) {
+                    resolved = canonical;
+                }
+            }
+            Component::CurDir => (),
+            Component::ParentDir if resolved != dest => {
+                resolved.pop();
+            }
+            _ => return None,
+        }
+        if !resolved.starts_with(dest) {
+            return None;
+        }
+    }
+    Some(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn tar_gz(path: &Path, symlink_target: &str) {
+        let encoder =
+            flate2::write::GzEncoder::new(File::create(path).unwrap(), Default::default());
+        let mut builder = tar::Builder::new(encoder);
+
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o755);
+        builder
+            .append_data(&mut header, "tool-1.0/bin/tool", &b"tool"[..])
+            .unwrap();
+
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "tool-1.0/tool", symlink_target)
+            .unwrap();
+
+        builder.into_inner().unwrap().finish().unwrap();
+    }
+
+    #[test]
+    fn extract_tar_gz() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("tool.tar.gz");
+        let dest = dir.path().join("out");
+        tar_gz(&archive, "bin/tool");
+
+        extract(&archive, &dest, &ExtractOptions::new().strip_components(1)).unwrap();
+
+        assert_eq!(fs::read_to_string(dest.join("bin/tool")).unwrap(), "tool");
+        assert_eq!(fs::read_to_string(dest.join("tool")).unwrap(), "tool");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dest.join("bin/tool"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o755);
+        }
+
+        tar_gz(&archive, "../../escape");
+        assert!(extract(&archive, &dest, &ExtractOptions::new()).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("chain.tar");
+        let dest = dir.path().join("out");
+
+        let create = |links: &[(&str, &str)]| {
+            let mut builder = tar::Builder::new(File::create(&archive).unwrap());
+            for (path, target) in links {
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_size(0);
+                builder.append_link(&mut header, path, target).unwrap();
+            }
+            builder.finish().unwrap();
+        };
+
+        // Each link stays inside of `dest` on its own, but not through `a`.
+        create(&[("a", "."), ("a/b", "..")]);
+        assert!(extract(&archive, &dest, &ExtractOptions::new()).is_err());
+        assert!(fs::symlink_metadata(dest.join("b")).is_err());
+
+        // `c` changes what the dangling `l` points to after it was created.
+        fs::remove_dir_all(&dest).unwrap();
+        create(&[("l", "c/.."), ("c", ".")]);
+        assert!(extract(&archive, &dest, &ExtractOptions::new()).is_err());
+        assert!(fs::symlink_metadata(dest.join("l")).is_err());
+
+        fs::remove_dir_all(&dest).unwrap();
+        create(&[("lib", "sub"), ("sub/x", "../lib"), ("lib/y", "x")]);
+        extract(&archive, &dest, &ExtractOptions::new()).unwrap();
+        assert_eq!(
+            fs::read_link(dest.join("sub/y")).unwrap(),
+            PathBuf::from("x")
+        );
+    }
+
+    #[test]
+    fn extract_zip() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("tool.zip");
+        let dest = dir.path().join("out");
+
+        let mut zip = zip::ZipWriter::new(File::create(&archive).unwrap());
+        zip.add_directory("tool/empty", Default::default()).unwrap();
+        zip.start_file("tool/file.txt", Default::default()).unwrap();
+        zip.write_all(b"content").unwrap();
+        zip.finish().unwrap();
+
+        extract(&archive, &dest, &ExtractOptions::new()).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dest.join("tool/file.txt")).unwrap(),
+            "content"
+        );
+        assert!(dest.join("tool/empty").is_dir());
+
+        let mut zip = zip::ZipWriter::new(File::create(&archive).unwrap());
+        zip.start_file("../escape.txt", Default::default()).unwrap();
+        zip.finish().unwrap();
+
+        assert!(extract(&archive, &dest, &ExtractOptions::new()).is_err());
+        assert!(!dir.path().join("escape.txt").exists());
+    }
+}
@@ -0,0 +1,361 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{fmt, process, thread};
+
+/// The error when acquiring a [`FileLock`].
+#[derive(Debug, thiserror::Error)]
+pub enum LockError {
+    /// The lock was not released before the timeout elapsed.
+    #[error("timed out after {1:?} waiting for lock '{}'", .0.display())]
+    Timeout(PathBuf, Duration, Option<LockOwner>),
+    /// The lock file could not be created, locked or read.
+    #[error("could not acquire lock '{}'", .0.display())]
+    Io(PathBuf, #[source] io::Error),
+}
+
+/// The process holding a [`FileLock`], as written to the lock file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockOwner {
+    /// The id of the process.
+    pub pid: u32,
+    /// The host name of the machine the process runs on.
+    pub host: String,
+}
+
+impl fmt::Display for LockOwner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "process {} on {}", self.pid, self.host)
+    }
+}
+
+impl LockOwner {
+    fn current() -> Self {
+        LockOwner {
+            pid: process::id(),
+            host: hostname(),
+        }
+    }
+
+    fn parse(contents: &str) -> Option<Self> {
+        let mut pid = None;
+        let mut host = None;
+        for line in contents.lines() {
+            match line.split_once('=') {
+                Some(("pid", value)) => pid = value.trim().parse().ok(),
+                Some(("host", value)) => host = Some(value.trim().to_owned()),
+                _ => (),
+            }
+        }
+
+        Some(LockOwner {
+            pid: pid?,
+            host: host?,
+        })
+    }
+}
+
+/// An advisory lock, held with an exclusive OS lock (`flock` on unix, `LockFileEx` on
+/// windows) on a lock file which is released when dropped.
+///
+/// This is used to serialize modifications of shared directories (e.g. the global
+/// esp-idf and PlatformIO installations) between processes. The lock file is never
+/// removed, so that all processes always lock the same file; the OS releases the lock
+/// when the owning process exits, even if it was killed. The lock file contains the id
+/// and host name of the owning process, which is reported to processes waiting for it.
+#[derive(Debug)]
+pub struct FileLock {
+    path: PathBuf,
+    _file: File,
+}
+
+impl FileLock {
+    /// Acquire the lock `path`, waiting until it is released by any other process (see
+    /// [`LockOptions::lock`]).
+    pub fn lock(path: impl AsRef<Path>) -> Result<FileLock, LockError> {
+        LockOptions::new().lock(path)
+    }
+
+    /// Acquire the lock `path` if it is not held by any other process.
+    pub fn try_lock(path: impl AsRef<Path>) -> Result<Option<FileLock>, LockError> {
+        LockOptions::new().try_lock(path)
+    }
+
+    /// The path of the lock file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// The options to acquire a [`FileLock`].
+#[derive(Debug, Clone, Default)]
+#[must_use]
+pub struct LockOptions {
+    timeout: Option<Duration>,
+    message: Option<String>,
+}
+
+impl LockOptions {
+    /// Create options that wait indefinitely.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail with [`LockError::Timeout`] if the lock can't be acquired within `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// The message that is logged once when the lock is held by another process.
+    pub fn message(mut self, message: impl Into<String>) -> Self {
+        self.message = Some(message.into());
+        self
+    }
+
+    /// Acquire the lock `path`, waiting until it is released by any other process.
+    ///
+    /// The parent directories of `path` are created if they don't exist.
+    pub fn lock(&self, path: impl AsRef<Path>) -> Result<FileLock, LockError> {
+        let path = path.as_ref();
+        let start = Instant::now();
+        let mut poll_interval = Duration::from_millis(50);
+        let mut logged = false;
+
+        loop {
+            let owner = match self.try_lock_inner(path)? {
+                Ok(lock) => return Ok(lock),
+                Err(owner) => owner,
+            };
+
+            if let Some(timeout) = self.timeout {
+                if start.elapsed() >= timeout {
+                    return Err(LockError::Timeout(path.to_owned(), timeout, owner));
+                }
+            }
+
+            if !logged {
+                logged = true;
+                let holder = owner
+                    .as_ref()
+                    .map(ToString::to_string)
+                    .unwrap_or_else(|| "another process".into());
+                log::warn!(
+                    "{} (waiting for lock '{}' held by {holder})",
+                    self.message.as_deref().unwrap_or("Blocking"),
+                    path.display()
+                );
+            }
+
+            thread::sleep(poll_interval);
+            poll_interval = (poll_interval * 2).min(Duration::from_secs(1));
+        }
+    }
+
+    /// Acquire the lock `path` if it is not held by any other process.
+    pub fn try_lock(&self, path: impl AsRef<Path>) -> Result<Option<FileLock>, LockError> {
+        Ok(self.try_lock_inner(path.as_ref())?.ok())
+    }
+
+    /// Try to acquire the lock, or return its current owner.
+    fn try_lock_inner(
+        &self,
+        path: &Path,
+    ) -> Result<Result<FileLock, Option<LockOwner>>, LockError> {
+        let io_err = |e| LockError::Io(path.to_owned(), e);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(io_err)?;
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // The owner info is only replaced once the lock is acquired.
+            .truncate(false)
+            .open(path)
+            .map_err(io_err)?;
+
+        if !sys::try_lock_exclusive(&file).map_err(io_err)? {
+            // The owner may not have written its info yet.
+            let owner = fs::read_to_string(path)
+                .ok()
+                .and_then(|c| LockOwner::parse(&c));
+            return Ok(Err(owner));
+        }
+
+        write_owner(&mut file).map_err(io_err)?;
+        Ok(Ok(FileLock {
+            path: path.to_owned(),
+            _file: file,
+        }))
+    }
+}
+
+/// Replace the contents of the locked `file` with the info of this process.
+fn write_owner(file: &mut File) -> io::Result<()> {
+    let owner = LockOwner::current();
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    write!(file, "pid={}\nhost={}\n", owner.pid, owner.host)?;
+    file.flush()
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    /// Try to lock `file` exclusively, returning `false` if it is locked by another open
+    /// file (of any process).
+    pub fn try_lock_exclusive(file: &File) -> io::Result<bool> {
+        loop {
+            // SAFETY: The file descriptor is valid as long as `file` is.
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+                return Ok(true);
+            }
+
+            let error = io::Error::last_os_error();
+            match error.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(libc::EWOULDBLOCK) => return Ok(false),
+                _ => return Err(error),
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::ffi::c_void;
+    use std::fs::File;
+    use std::io;
+    use std::os::windows::io::AsRawHandle;
+
+    const LOCKFILE_FAIL_IMMEDIATELY: u32 = 0x1;
+    const LOCKFILE_EXCLUSIVE_LOCK: u32 = 0x2;
+    const ERROR_LOCK_VIOLATION: i32 = 33;
+
+    #[repr(C)]
+    struct Overlapped {
+        internal: usize,
+        internal_high: usize,
+        offset: u32,
+        offset_high: u32,
+        event: *mut c_void,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn LockFileEx(
+            file: *mut c_void,
+            flags: u32,
+            reserved: u32,
+            len_low: u32,
+            len_high: u32,
+            overlapped: *mut Overlapped,
+        ) -> i32;
+    }
+
+    /// Try to lock `file` exclusively, returning `false` if it is locked by another
+    /// handle (of any process).
+    ///
+    /// Locks on windows are mandatory, so a single byte far beyond the end of the file is
+    /// locked to keep the owner info readable. The lock is released when the file is
+    /// closed.
+    pub fn try_lock_exclusive(file: &File) -> io::Result<bool> {
+        let mut overlapped = Overlapped {
+            internal: 0,
+            internal_high: 0,
+            offset: 0,
+            offset_high: u32::MAX,
+            event: std::ptr::null_mut(),
+        };
+
+        // SAFETY: The handle is valid as long as `file` is and `overlapped` outlives the
+        // synchronous call.
+        let result = unsafe {
+            LockFileEx(
+                file.as_raw_handle().cast(),
+                LOCKFILE_EXCLUSIVE_LOCK | LOCKFILE_FAIL_IMMEDIATELY,
+                0,
+                1,
+                0,
+                &mut overlapped,
+            )
+        };
+        if result != 0 {
+            return Ok(true);
+        }
+
+        let error = io::Error::last_os_error();
+        if error.raw_os_error() == Some(ERROR_LOCK_VIOLATION) {
+            Ok(false)
+        } else {
+            Err(error)
+        }
+    }
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: `buf` is valid for `buf.len()` bytes.
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if result != 0 {
+        return String::new();
+    }
+
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(windows)]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sub").join("test.lock");
+
+        let lock = FileLock::lock(&path).unwrap();
+        assert!(FileLock::try_lock(&path).unwrap().is_none());
+
+        let err = LockOptions::new()
+            .timeout(Duration::from_millis(100))
+            .lock(&path)
+            .unwrap_err();
+        assert!(
+            matches!(err, LockError::Timeout(_, _, Some(owner)) if owner == LockOwner::current())
+        );
+
+        // The lock file stays in place, so that all processes lock the same file.
+        drop(lock);
+        assert!(path.exists());
+        assert!(FileLock::try_lock(&path).unwrap().is_some());
+    }
+
+    #[test]
+    fn left_behind_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.lock");
+
+        // A lock file left behind by a process that is no longer running is not locked.
+        fs::write(&path, "pid=0\nhost=other\nmore\n").unwrap();
+
+        let lock = FileLock::try_lock(&path).unwrap().unwrap();
+        assert_eq!(
+            LockOwner::parse(&fs::read_to_string(lock.path()).unwrap()),
+            Some(LockOwner::current())
+        );
+    }
+}
@@ -0,0 +1,338 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use super::{copy_with_metadata, is_file_eq};
+
+/// The options of [`sync_dir`].
+#[derive(Debug, Clone, Default)]
+#[must_use]
+pub struct SyncOptions {
+    delete: bool,
+    #[cfg(feature = "glob")]
+    include: Vec<Glob>,
+    #[cfg(feature = "glob")]
+    exclude: Vec<Glob>,
+}
+
+impl SyncOptions {
+    /// Create options that sync all files without deleting any.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Delete files and directories in the destination that don't exist in the source.
+    ///
+    /// Only files that are not filtered out by `include` and `exclude` patterns are
+    /// deleted.
+    pub fn delete(mut self, delete: bool) -> Self {
+        self.delete = delete;
+        self
+    }
+
+    /// Only sync files matching `pattern`; if no include pattern is given all files are
+    /// synced.
+    ///
+    /// Patterns are matched against the path relative to the synced directory using `/`
+    /// as separator with the syntax of [`globset::Glob`], where `*` and `?` don't match
+    /// `/` and `**` matches any number of components. A pattern without `/` is matched
+    /// against the file name only.
+    ///
+    /// Returns an error if `pattern` is invalid. Requires the `glob` feature.
+    #[cfg(feature = "glob")]
+    pub fn include(mut self, pattern: impl AsRef<str>) -> Result<Self> {
+        self.include.push(Glob::new(pattern.as_ref())?);
+        Ok(self)
+    }
+
+    /// Don't sync files or directories matching `pattern` (see
+    /// [`include`](Self::include) for the pattern syntax).
+    ///
+    /// Excluded files in the destination are left untouched. Requires the `glob` feature.
+    #[cfg(feature = "glob")]
+    pub fn exclude(mut self, pattern: impl AsRef<str>) -> Result<Self> {
+        self.exclude.push(Glob::new(pattern.as_ref())?);
+        Ok(self)
+    }
+
+    #[cfg(feature = "glob")]
+    fn is_excluded(&self, rel_path: &str) -> bool {
+        self.exclude.iter().any(|g| g.matches(rel_path))
+    }
+
+    #[cfg(not(feature = "glob"))]
+    fn is_excluded(&self, _rel_path: &str) -> bool {
+        false
+    }
+
+    #[cfg(feature = "glob")]
+    fn is_file_included(&self, rel_path: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|g| g.matches(rel_path)))
+            && !self.is_excluded(rel_path)
+    }
+
+    #[cfg(not(feature = "glob"))]
+    fn is_file_included(&self, _rel_path: &str) -> bool {
+        true
+    }
+}
+
+/// The changes made by [`sync_dir`].
+///
+/// All paths are relative to the destination directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    /// Files that didn't exist in the destination.
+    pub created: Vec<PathBuf>,
+    /// Files that were different in the destination.
+    pub updated: Vec<PathBuf>,
+    /// Files and directories that were deleted from the destination.
+    pub deleted: Vec<PathBuf>,
+    /// The number of files that were already up to date.
+    pub unchanged: usize,
+}
+
+impl SyncReport {
+    /// Whether any file in the destination was changed.
+    pub fn is_changed(&self) -> bool {
+        !(self.created.is_empty() && self.updated.is_empty() && self.deleted.is_empty())
+    }
+}
+
+/// Recursively copy all files of the directory `src` to the directory `dest` which are
+/// different or don't exist in `dest` (see [`copy_file_if_different`](super::copy_file_if_different)).
+///
+/// Copied files keep their modification times (see [`copy_with_metadata`]), so that
+/// unchanged files don't trigger rebuilds. Symlinks are followed.
+pub fn sync_dir(
+    src: impl AsRef<Path>,
+    dest: impl AsRef<Path>,
+    options: &SyncOptions,
+) -> Result<SyncReport> {
+    let (src, dest) = (src.as_ref(), dest.as_ref());
+
+    if !src.is_dir() {
+        anyhow::bail!("'{}' is not a directory", src.display());
+    }
+
+    let mut report = SyncReport::default();
+    sync(src, dest, Path::new(""), options, &mut report)
+        .with_context(|| format!("Failed to sync '{}' to '{}'", src.display(), dest.display()))?;
+
+    Ok(report)
+}
+
+fn sorted_entries(dir: &Path) -> Result<Vec<fs::DirEntry>> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    Ok(entries)
+}
+
+fn glob_path(rel_path: &Path) -> String {
+    rel_path
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn sync(
+    src: &Path,
+    dest: &Path,
+    rel_dir: &Path,
+    options: &SyncOptions,
+    report: &mut SyncReport,
+) -> Result<()> {
+    fs::create_dir_all(dest)?;
+
+    for entry in sorted_entries(src)? {
+        let rel_path = rel_dir.join(entry.file_name());
+        let glob_path = glob_path(&rel_path);
+        let (src_path, dest_path) = (entry.path(), dest.join(entry.file_name()));
+
+        if src_path.is_dir() {
+            if !options.is_excluded(&glob_path) {
+                if dest_path.is_file() {
+                    fs::remove_file(&dest_path)?;
+                    report.deleted.push(rel_path.clone());
+                }
+                sync(&src_path, &dest_path, &rel_path, options, report)?;
+            }
+            continue;
+        }
+
+        if !options.is_file_included(&glob_path) {
+            continue;
+        }
+
+        if dest_path.is_dir() {
+            fs::remove_dir_all(&dest_path)?;
+            report.deleted.push(rel_path.clone());
+        }
+
+        if !dest_path.exists() {
+            copy_with_metadata(&src_path, &dest_path)?;
+            report.created.push(rel_path);
+        } else if !is_file_eq(&fs::File::open(&src_path)?, &fs::File::open(&dest_path)?)? {
+            copy_with_metadata(&src_path, &dest_path)?;
+            report.updated.push(rel_path);
+        } else {
+            report.unchanged += 1;
+        }
+    }
+
+    if options.delete {
+        for entry in sorted_entries(dest)? {
+            let rel_path = rel_dir.join(entry.file_name());
+            let glob_path = glob_path(&rel_path);
+            let (src_path, dest_path) = (src.join(entry.file_name()), entry.path());
+
+            if src_path.exists() {
+                continue;
+            }
+
+            if dest_path.is_dir() {
+                if !options.is_excluded(&glob_path)
+                    && delete_stale(&dest_path, &rel_path, options, report)?
+                {
+                    fs::remove_dir(&dest_path)?;
+                    report.deleted.push(rel_path);
+                }
+            } else if options.is_file_included(&glob_path) {
+                fs::remove_file(&dest_path)?;
+                report.deleted.push(rel_path);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Delete all files in `dir` that are not filtered out by `options`, and return whether
+/// `dir` is empty afterwards.
+fn delete_stale(
+    dir: &Path,
+    rel_dir: &Path,
+    options: &SyncOptions,
+    report: &mut SyncReport,
+) -> Result<bool> {
+    let mut empty = true;
+
+    for entry in sorted_entries(dir)? {
+        let rel_path = rel_dir.join(entry.file_name());
+        let glob_path = glob_path(&rel_path);
+        let path = entry.path();
+
+        if path.is_dir() {
+            if !options.is_excluded(&glob_path) && delete_stale(&path, &rel_path, options, report)?
+            {
+                fs::remove_dir(&path)?;
+                report.deleted.push(rel_path);
+            } else {
+                empty = false;
+            }
+        } else if options.is_file_included(&glob_path) {
+            fs::remove_file(&path)?;
+            report.deleted.push(rel_path);
+        } else {
+            empty = false;
+        }
+    }
+
+    Ok(empty)
+}
+
+/// A glob pattern for relative paths with `/` separators.
+#[cfg(feature = "glob")]
+#[derive(Debug, Clone)]
+struct Glob {
+    matcher: globset::GlobMatcher,
+    file_name_only: bool,
+}
+
+#[cfg(feature = "glob")]
+impl Glob {
+    fn new(pattern: &str) -> Result<Self> {
+        let pattern = pattern.trim_start_matches("./").trim_end_matches('/');
+        let glob = globset::GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("Invalid glob pattern '{pattern}'"))?;
+
+        Ok(Self {
+            matcher: glob.compile_matcher(),
+            file_name_only: !pattern.contains('/'),
+        })
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        let path = if self.file_name_only {
+            path.rsplit('/').next().unwrap_or(path)
+        } else {
+            path
+        };
+
+        self.matcher.is_match(path)
+    }
+}
+
+#[cfg(all(test, feature = "glob"))]
+mod tests {
+    use super::*;
+
+    fn write(path: PathBuf, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn glob() {
+        let glob = |p| Glob::new(p).unwrap();
+        assert!(glob("*.c").matches("a/b/main.c"));
+        assert!(!glob("*.c").matches("a/main.h"));
+        assert!(glob("src/**/*.h").matches("src/a/b/x.h"));
+        assert!(glob("src/**/*.h").matches("src/x.h"));
+        assert!(!glob("src/*.h").matches("src/a/x.h"));
+        assert!(glob("build/").matches("build"));
+        assert!(glob("f?o").matches("foo"));
+        assert!(glob("[ab].c").matches("b.c"));
+        assert!(Glob::new("[a").is_err());
+    }
+
+    #[test]
+    fn sync() {
+        let src = tempfile::tempdir().unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        let (src, dest) = (src.path(), dest.path());
+
+        write(src.join("a.c"), "a");
+        write(src.join("sub/b.c"), "b");
+        write(src.join("sub/b.o"), "o");
+        write(dest.join("a.c"), "old");
+        write(dest.join("stale.c"), "stale");
+        write(dest.join("stale/c.c"), "stale");
+        write(dest.join("keep.o"), "keep");
+
+        let options = SyncOptions::new().delete(true).exclude("*.o").unwrap();
+        let report = sync_dir(src, dest, &options).unwrap();
+
+        assert_eq!(report.created, [PathBuf::from("sub/b.c")]);
+        assert_eq!(report.updated, [PathBuf::from("a.c")]);
+        assert_eq!(
+            report.deleted,
+            [
+                PathBuf::from("stale/c.c"),
+                PathBuf::from("stale"),
+                PathBuf::from("stale.c")
+            ]
+        );
+        assert_eq!(fs::read_to_string(dest.join("a.c")).unwrap(), "a");
+        assert!(dest.join("keep.o").exists());
+        assert!(!dest.join("sub/b.o").exists());
+
+        let report = sync_dir(src, dest, &options).unwrap();
+        assert!(!report.is_changed());
+        assert_eq!(report.unchanged, 2);
+    }
+}
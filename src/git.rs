@@ -1,5 +1,4 @@
-//! Git repository manipulation through the git CLI.
-// TODO: maybe use `git2` crate
+//! Git repository manipulation through the git CLI or natively (see [`Backend`]).
 
 use std::ffi::OsStr;
 use std::fmt::Display;
@@ -9,12 +8,32 @@ use std::path::{Path, PathBuf};
 use anyhow::{anyhow, Context};
 
 use crate::cmd;
-use crate::cmd::CmdError;
+use crate::cmd::{CmdError, TeeSink};
 use crate::utils::PathExt;
 
+mod lockfile;
+#[cfg(feature = "git-native")]
+mod native;
+mod patches;
+mod remote;
+mod store;
+mod submodule;
+mod verify;
+
+pub use lockfile::*;
+pub use patches::*;
+pub use remote::*;
+pub use store::*;
+pub use submodule::*;
+pub use verify::*;
+
 /// The git command.
 pub const GIT: &str = "git";
 
+/// Environment variable which selects the [`Backend`] used by [`Repository`]: `cli` (the
+/// default) or `native`.
+pub const BACKEND_VAR: &str = "EMBUILD_GIT_BACKEND";
+
 /// A list of environment variables to set/unset so that git is guaranteed to output
 /// english.
 ///
@@ -22,12 +41,60 @@ pub const GIT: &str = "git";
 /// anything other than `C` (we use `C.UTF-8`).
 const LC_ALL: [(&str, &str); 2] = [("LC_ALL", "C.UTF-8"), ("LANGUAGE", "")];
 
+/// The implementation [`Repository`] uses for git operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// Run the `git` CLI.
+    Cli,
+    /// Use the native git implementation (requires the `git-native` feature).
+    ///
+    /// Applying patches, updating an existing branch (see
+    /// [`CloneOptions::branch_update_action`]), switching the ref of an existing
+    /// repository and cloning with an [`ObjectStore`] still run the `git` CLI. Submodules are
+    /// always cloned with their full history.
+    Native,
+}
+
+impl Backend {
+    /// Whether this backend was compiled in.
+    pub fn is_available(self) -> bool {
+        match self {
+            Self::Cli => true,
+            Self::Native => cfg!(feature = "git-native"),
+        }
+    }
+
+    /// Get the backend selected by [`BACKEND_VAR`].
+    ///
+    /// Defaults to [`Backend::Cli`]; [`Backend::Native`] is only used if it is requested
+    /// explicitly (even if the `git-native` feature is enabled).
+    pub fn from_env() -> Backend {
+        match std::env::var(BACKEND_VAR).as_deref() {
+            Ok("cli") => Self::Cli,
+            Ok("native") if Self::Native.is_available() => Self::Native,
+            Ok("native") => {
+                log::warn!(
+                    "${BACKEND_VAR}: the native git backend requires the `git-native` feature, \
+                     using the git CLI"
+                );
+                Self::Cli
+            }
+            Ok(other) if !other.is_empty() => {
+                log::warn!("Invalid ${BACKEND_VAR} '{other}', expected 'cli' or 'native'");
+                Self::Cli
+            }
+            _ => Self::Cli,
+        }
+    }
+}
+
 /// A logical git repository which may or may not exist.
 #[derive(Debug, Clone)]
 pub struct Repository {
     git_dir: PathBuf,
     worktree: PathBuf,
     remote_name: Option<String>,
+    backend: Backend,
 }
 
 impl Repository {
@@ -40,12 +107,48 @@ impl Repository {
             git_dir: dir.as_ref().join(".git"),
             worktree: dir.as_ref().to_owned(),
             remote_name: None,
+            backend: Backend::from_env(),
         }
     }
 
+    /// Use `backend` for all git operations on this repository.
+    ///
+    /// [`Backend::Cli`] is used if `backend` is not [available](Backend::is_available).
+    #[must_use]
+    pub fn with_backend(mut self, backend: Backend) -> Self {
+        self.backend = if backend.is_available() {
+            backend
+        } else {
+            Backend::Cli
+        };
+        self
+    }
+
+    /// The backend used for git operations on this repository.
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    #[cfg(feature = "git-native")]
+    fn is_native(&self) -> bool {
+        self.backend == Backend::Native
+    }
+
     /// Try to open an existing git repository.
     pub fn open(dir: impl AsRef<Path>) -> anyhow::Result<Repository> {
         let dir = dir.as_ref();
+        let backend = Backend::from_env();
+
+        #[cfg(feature = "git-native")]
+        if backend == Backend::Native {
+            return Ok(Repository {
+                git_dir: native::git_dir(dir)?,
+                worktree: dir.to_owned(),
+                remote_name: None,
+                backend,
+            });
+        }
+
         let base_err = || anyhow::anyhow!("'{}' is not a git respository", dir.display());
 
         let top_level_dir =
@@ -73,6 +176,7 @@ impl Repository {
             git_dir,
             worktree: dir.to_owned(),
             remote_name: None,
+            backend,
         })
     }
 
@@ -97,6 +201,11 @@ impl Repository {
 
     /// Get all remote names and their urls.
     pub fn get_remotes(&self) -> Result<Vec<(String, String)>, CmdError> {
+        #[cfg(feature = "git-native")]
+        if self.is_native() {
+            return native::remotes(&self.worktree).map_err(native_err("remotes"));
+        }
+
         Ok(cmd!(GIT, @self.git_args(), "remote", "show"; envs=(LC_ALL))
             .stdout()?
             .lines()
@@ -112,6 +221,11 @@ impl Repository {
 
     /// Get the default branch name of `remote`.
     pub fn get_default_branch_of(&self, remote: &str) -> Result<String, anyhow::Error> {
+        #[cfg(feature = "git-native")]
+        if self.is_native() {
+            return native::default_branch_of(&self.worktree, remote);
+        }
+
         let output =
             cmd!(GIT, @self.git_args(), "remote", "show", remote; envs=(LC_ALL)).stdout()?;
         output
@@ -136,6 +250,11 @@ impl Repository {
     /// Query whether the work-tree is clean ignoring any untracked files and recursing
     /// through all submodules.
     pub fn is_clean(&self) -> Result<bool, CmdError> {
+        #[cfg(feature = "git-native")]
+        if self.is_native() {
+            return native::is_clean(&self.worktree).map_err(native_err("status"));
+        }
+
         Ok(
             cmd!(GIT, @self.git_args(), "status", "-s", "-uno", "--ignore-submodules=untracked", "--ignored=no"; envs=(LC_ALL))
                 .stdout()?
@@ -152,6 +271,11 @@ impl Repository {
     ///
     /// Calls `git describe --all --exact-match`.
     pub fn describe_exact_ref(&self) -> Result<String, CmdError> {
+        #[cfg(feature = "git-native")]
+        if self.is_native() {
+            return native::describe_exact_ref(&self.worktree).map_err(native_err("describe"));
+        }
+
         cmd!(GIT, @self.git_args(), "describe", "--all", "--exact-match"; envs=(LC_ALL)).stdout()
     }
 
@@ -159,6 +283,25 @@ impl Repository {
     ///
     /// Calls `git describe --all --exact-match --always --abbrev=40`
     pub fn get_ref(&self) -> Result<Ref, CmdError> {
+        #[cfg(feature = "git-native")]
+        if self.is_native() {
+            let ref_or_commit = native::describe_exact_ref(&self.worktree)
+                .or_else(|_| native::head_commit(&self.worktree).map(|c| format!("commit/{c}")))
+                .map_err(native_err("describe"))?;
+            return if let Some(branch) = ref_or_commit.strip_prefix("heads/") {
+                Ok(Ref::Branch(branch.to_owned()))
+            } else if let Some(tag) = ref_or_commit.strip_prefix("tags/") {
+                Ok(Ref::Tag(tag.to_owned()))
+            } else if let Some(commit) = ref_or_commit.strip_prefix("commit/") {
+                Ok(Ref::Commit(commit.to_owned()))
+            } else {
+                Err(native_err("describe")(anyhow!(
+                    "could not parse ref '{}': not a branch, tag or commit",
+                    ref_or_commit
+                )))
+            };
+        }
+
         let mut cmd = cmd!(GIT, @self.git_args(), "describe", "--all", "--exact-match", "--always", "--abbrev=40"; envs=(LC_ALL));
         let ref_or_commit = cmd.stdout()?;
         if let Some(branch) = ref_or_commit.strip_prefix("heads/") {
@@ -179,6 +322,18 @@ impl Repository {
         }
     }
 
+    /// Get the hash of the checked out commit.
+    ///
+    /// Calls `git rev-parse HEAD`.
+    pub fn head_commit(&self) -> Result<String, CmdError> {
+        #[cfg(feature = "git-native")]
+        if self.is_native() {
+            return native::head_commit(&self.worktree).map_err(native_err("rev-parse"));
+        }
+
+        cmd!(GIT, @self.git_args(), "rev-parse", "HEAD"; envs=(LC_ALL)).stdout()
+    }
+
     /// Get the current branch name if the current checkout is the top of the branch.
     pub fn get_branch_name(&self) -> Result<Option<String>, CmdError> {
         Ok(self
@@ -187,13 +342,35 @@ impl Repository {
             .map(Into::into))
     }
 
-    /// Clone the repository with the default options and return if the repository was modified.
-    pub fn clone(&mut self, url: &str) -> Result<bool, anyhow::Error> {
+    /// Clone the repository with the default options and return what was done.
+    pub fn clone(&mut self, url: &str) -> Result<CloneOutcome, anyhow::Error> {
         self.clone_ext(url, CloneOptions::default())
     }
 
     /// Whether the repository has currently checked out `git_ref`.
     pub fn is_ref(&self, git_ref: &Ref) -> bool {
+        #[cfg(feature = "git-native")]
+        if self.is_native() {
+            return match git_ref {
+                Ref::Branch(b) => native::branch_name(&self.worktree)
+                    .ok()
+                    .flatten()
+                    .map(|branch| branch == *b),
+                Ref::Tag(_) => None,
+                Ref::Commit(c) => native::head_commit(&self.worktree).ok().map(|s| s == *c),
+            }
+            .or_else(|| {
+                let tag = match git_ref {
+                    Ref::Tag(t) => t,
+                    _ => return None,
+                };
+                self.describe_exact_ref()
+                    .ok()
+                    .map(|s| s == format!("tags/{tag}"))
+            })
+            .unwrap_or(false);
+        }
+
         match git_ref {
             Ref::Branch(b) => self
                 .describe_exact_ref()
@@ -216,10 +393,28 @@ impl Repository {
         self.git_dir.join("shallow").exists()
     }
 
-    /// Clone the repository with `options` and return if the repository was modified.
-    pub fn clone_ext(&mut self, url: &str, options: CloneOptions) -> Result<bool, anyhow::Error> {
-        let (should_remove, should_clone, modified) = if !self.git_dir.exists() {
-            (self.worktree.exists(), true, true)
+    /// Clone the repository with `options` and return what was done.
+    ///
+    /// If the repository already exists with a different ref checked out, the new ref
+    /// is fetched and checked out in place (using the git CLI) and the repository is
+    /// only cloned from scratch if that fails or the work-tree is not clean.
+    ///
+    /// The repository and its submodules are fetched from a mirror if one is configured
+    /// (see [`Mirrors::from_env`](crate::utils::Mirrors::from_env)), but `url` stays the
+    /// url of the remote.
+    pub fn clone_ext(
+        &mut self,
+        url: &str,
+        options: CloneOptions,
+    ) -> Result<CloneOutcome, anyhow::Error> {
+        let mirrors = crate::utils::Mirrors::from_env()?;
+        let mirror_args = mirrors.git_config_args();
+
+        let jobs = jobs()?;
+        let filter = options.submodules.as_ref();
+
+        let reason = if !self.git_dir.exists() {
+            CloneReason::New
         } else if let Some((remote, _)) = self
             .get_remotes()
             .ok()
@@ -233,66 +428,263 @@ impl Repository {
             self.remote_name = Some(remote);
 
             if !self.is_ref(&force_ref) {
-                (true, true, true)
+                if !self.is_clean()? {
+                    CloneReason::Dirty
+                } else {
+                    let update = if let Some(store) = &options.object_store {
+                        store.update_repo(self, url, &mirror_args, filter, || {
+                            Ok(self.checkout_in_place(&force_ref, None, &mirror_args)?)
+                        })
+                    } else {
+                        self.update_in_place(&force_ref, options.depth, filter, &mirror_args, &jobs)
+                    };
+                    match update {
+                        Ok(()) => return Ok(CloneOutcome::Updated),
+                        Err(e) => {
+                            log::warn!(
+                                "Could not update '{}' to {force_ref}, cloning it again: {e:#}",
+                                self.worktree.display()
+                            );
+                            CloneReason::UpdateFailed(format!("{e:#}"))
+                        }
+                    }
+                }
             } else {
-                match force_ref {
+                let outcome = match force_ref {
                     Ref::Branch(_) if !options.force_clean || self.is_clean()? => {
-                        let modified = if let Some(reset_mode) = options.branch_update_action {
+                        if let Some(reset_mode) = options.branch_update_action {
                             cmd!(GIT, @self.git_args(), "reset", reset_mode.to_string()).run()?;
-                            cmd!(GIT, @self.git_args(), "pull", "--ff-only").run()?;
-                            true
+                            cmd!(GIT, @&mirror_args, @self.git_args(), "pull", "--ff-only")
+                                .run()?;
+                            Some(CloneOutcome::Pulled)
                         } else {
-                            false
-                        };
-
-                        (false, false, modified)
+                            Some(CloneOutcome::Unchanged)
+                        }
                     }
                     Ref::Commit(_) | Ref::Tag(_) if !options.force_clean || self.is_clean()? => {
-                        (false, false, false)
+                        Some(CloneOutcome::Unchanged)
+                    }
+                    _ => None,
+                };
+
+                if let Some(outcome) = outcome {
+                    if let Some(store) = &options.object_store {
+                        store.touch(&self.worktree)?;
                     }
-                    _ => (true, true, true),
+                    return Ok(outcome);
                 }
+                CloneReason::Dirty
             }
         } else {
-            (true, true, true)
+            CloneReason::RemoteMismatch
         };
 
-        if should_remove {
+        if self.worktree.exists() {
             remove_dir_all::remove_dir_all(&self.worktree)?;
         }
 
-        if should_clone {
-            let depth = options.depth.map(|i| i.to_string());
-            let (depth, branch) = match &options.force_ref {
-                None | Some(Ref::Commit(_)) => (None, None),
-                Some(Ref::Branch(s) | Ref::Tag(s)) => (
-                    depth
-                        .as_deref()
-                        .map(|i| ["--depth", i, "--shallow-submodules"]),
-                    Some(["--branch", s]),
-                ),
-            };
+        if let Some(store) = &options.object_store {
+            store.clone_repo(self, url, options.force_ref.as_ref(), filter, &mirror_args)?;
+            self.remote_name = Some(String::from("origin"));
+            return Ok(CloneOutcome::Cloned(reason));
+        }
 
-            let depth = depth.iter().flatten();
-            let branch = branch.iter().flatten();
+        #[cfg(feature = "git-native")]
+        if self.is_native() {
+            native::clone(
+                url,
+                &self.worktree,
+                options.force_ref.as_ref(),
+                options.depth,
+                filter,
+                &mirrors.git_config(),
+            )?;
+            self.remote_name = Some(String::from("origin"));
+            return Ok(CloneOutcome::Cloned(reason));
+        }
+
+        let shallow_commit = match (&options.force_ref, options.depth) {
+            (Some(Ref::Commit(commit)), Some(depth)) if is_full_hash(commit) => {
+                Some((commit, depth))
+            }
+            _ => None,
+        };
+        if let Some((commit, depth)) = shallow_commit {
+            match self.fetch_commit(url, commit, depth, filter, &mirror_args, &jobs) {
+                Ok(()) => {
+                    self.remote_name = Some(String::from("origin"));
+                    return Ok(CloneOutcome::Cloned(reason));
+                }
+                Err(e) => {
+                    log::warn!(
+                        "Could not fetch only commit {commit} of '{url}', cloning the whole \
+                         repository instead: {e:#}"
+                    );
+                    if self.worktree.exists() {
+                        remove_dir_all::remove_dir_all(&self.worktree)?;
+                    }
+                }
+            }
+        }
+
+        let depth = options.depth.map(|i| i.to_string());
+        let (depth, branch) = match &options.force_ref {
+            None | Some(Ref::Commit(_)) => (None, None),
+            Some(Ref::Branch(s) | Ref::Tag(s)) => (
+                depth
+                    .as_deref()
+                    .map(|i| ["--depth", i, "--shallow-submodules"]),
+                Some(["--branch", s]),
+            ),
+        };
+
+        let depth = depth.iter().flatten();
+        let branch = branch.iter().flatten();
+        // With a filter the submodules are cloned after checking out the ref.
+        let recursive = filter.map_or(Some("--recursive"), |_| None);
 
-            // Jobs massivly speed up cloning all the submodules.
-            // The --jobs flag was introduced with git 2.9 in 2016, so we assume most people have it.
-            // https://github.blog/2016-06-13-git-2-9-has-been-released/
-            // git itself has a bug so jobs=0 doesnt work to get the number of cores (fixed only in >2.39)
-            // because of that we provide our own estimite via rust std
-            let cores = std::thread::available_parallelism()?;
-            let jobs = format!("--jobs={cores}");
+        cmd!(GIT, @&mirror_args, "clone", &jobs, @recursive, @depth, @branch, &url, &self.worktree; tee=(TeeSink::Parent)).run()?;
 
-            cmd!(GIT, "clone", jobs,"--recursive", @depth, @branch, &url, &self.worktree).run()?;
+        if let Some(Ref::Commit(s)) = &options.force_ref {
+            cmd!(GIT, @self.git_args(), "checkout", s).run()?;
+        }
+        if let Some(filter) = filter {
+            self.update_submodules_filtered(filter, self.is_shallow(), &mirror_args, &jobs)?;
+        }
+        self.remote_name = Some(String::from("origin"));
+
+        Ok(CloneOutcome::Cloned(reason))
+    }
 
-            if let Some(Ref::Commit(s)) = options.force_ref {
-                cmd!(GIT, @self.git_args(), "checkout", s).run()?;
+    /// Fetch `git_ref` from the remote into this existing repository, check it out and
+    /// update the submodules.
+    fn update_in_place(
+        &self,
+        git_ref: &Ref,
+        depth: Option<NonZeroU64>,
+        filter: Option<&SubmoduleFilter>,
+        mirror_args: &[String],
+        jobs: &str,
+    ) -> Result<(), anyhow::Error> {
+        self.checkout_in_place(git_ref, depth, mirror_args)?;
+        self.update_submodules(filter, self.is_shallow(), mirror_args, jobs)
+    }
+
+    /// Fetch `git_ref` from the remote into this existing repository, check it out and
+    /// sync the submodule urls.
+    ///
+    /// Only `depth` commits are fetched if it is set or this repository is shallow.
+    fn checkout_in_place(
+        &self,
+        git_ref: &Ref,
+        depth: Option<NonZeroU64>,
+        mirror_args: &[String],
+    ) -> Result<(), CmdError> {
+        let remote = self.remote_name.as_deref().unwrap_or("origin");
+        let depth = depth
+            .or_else(|| {
+                if self.is_shallow() {
+                    NonZeroU64::new(1)
+                } else {
+                    None
+                }
+            })
+            .map(|d| ["--depth".to_owned(), d.to_string()]);
+        let depth = depth.iter().flatten();
+
+        let refspec = match git_ref {
+            Ref::Branch(b) => format!("+refs/heads/{b}:refs/remotes/{remote}/{b}"),
+            Ref::Tag(t) => format!("+refs/tags/{t}:refs/tags/{t}"),
+            Ref::Commit(c) => c.clone(),
+        };
+        let have_commit = match git_ref {
+            Ref::Commit(c) => cmd!(GIT, @self.git_args(), "cat-file", "-e", format!("{c}^{{commit}}"); envs=(LC_ALL))
+                .status()
+                .map(|s| s.success())
+                .unwrap_or(false),
+            _ => false,
+        };
+        if !have_commit {
+            cmd!(GIT, @mirror_args, @self.git_args(), "fetch", "--no-tags", @depth, remote, refspec; envs=(LC_ALL), tee=(TeeSink::Parent))
+                .run()?;
+        }
+
+        match git_ref {
+            Ref::Branch(b) => {
+                cmd!(GIT, @self.git_args(), "checkout", "--quiet", "-B", b, format!("refs/remotes/{remote}/{b}"); envs=(LC_ALL))
+                    .run()?;
+                // Set the upstream without `--track`, which fails if the remote was cloned
+                // with a single branch.
+                cmd!(GIT, @self.git_args(), "config", format!("branch.{b}.remote"), remote; envs=(LC_ALL))
+                    .run()?;
+                cmd!(GIT, @self.git_args(), "config", format!("branch.{b}.merge"), format!("refs/heads/{b}"); envs=(LC_ALL))
+                    .run()?;
+            }
+            Ref::Tag(t) => {
+                cmd!(GIT, @self.git_args(), "checkout", "--quiet", "--detach", format!("refs/tags/{t}"); envs=(LC_ALL))
+                    .run()?;
+            }
+            Ref::Commit(c) => {
+                cmd!(GIT, @self.git_args(), "checkout", "--quiet", "--detach", c; envs=(LC_ALL))
+                    .run()?;
             }
-            self.remote_name = Some(String::from("origin"));
         }
 
-        Ok(modified)
+        cmd!(GIT, "submodule", "sync", "--quiet", "--recursive"; current_dir=(&self.worktree), envs=(LC_ALL))
+            .run()
+    }
+
+    /// Initialize and update all submodules (selected by `filter`) recursively,
+    /// shallowly if `shallow` and the server supports it.
+    fn update_submodules(
+        &self,
+        filter: Option<&SubmoduleFilter>,
+        shallow: bool,
+        mirror_args: &[String],
+        jobs: &str,
+    ) -> Result<(), anyhow::Error> {
+        if let Some(filter) = filter {
+            self.update_submodules_filtered(filter, shallow, mirror_args, jobs)?;
+            return Ok(());
+        }
+
+        if shallow {
+            let result = cmd!(GIT, @mirror_args, "submodule", "update", "--init", "--recursive", "--depth", "1", jobs; current_dir=(&self.worktree), envs=(LC_ALL), tee=(TeeSink::Parent))
+                .run();
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) => log::warn!(
+                    "Could not fetch submodules shallowly, fetching them fully instead: {e}"
+                ),
+            }
+        }
+
+        cmd!(GIT, @mirror_args, "submodule", "update", "--init", "--recursive", jobs; current_dir=(&self.worktree), envs=(LC_ALL), tee=(TeeSink::Parent))
+            .run()?;
+        Ok(())
+    }
+
+    /// Fetch only `commit` (with `depth` commits of history) from `url` into a new
+    /// repository, check it out and fetch its submodules shallowly if possible.
+    ///
+    /// This requires the server to allow fetching commits by hash (which most do).
+    fn fetch_commit(
+        &self,
+        url: &str,
+        commit: &str,
+        depth: NonZeroU64,
+        filter: Option<&SubmoduleFilter>,
+        mirror_args: &[String],
+        jobs: &str,
+    ) -> Result<(), anyhow::Error> {
+        cmd!(GIT, "init", "--quiet", &self.worktree; envs=(LC_ALL)).run()?;
+        cmd!(GIT, @self.git_args(), "remote", "add", "origin", url; envs=(LC_ALL)).run()?;
+        cmd!(GIT, @mirror_args, @self.git_args(), "fetch", "--depth", depth.to_string(), "origin", commit; envs=(LC_ALL), tee=(TeeSink::Parent))
+            .run()?;
+        cmd!(GIT, @self.git_args(), "checkout", "--quiet", "--detach", "FETCH_HEAD"; envs=(LC_ALL))
+            .run()?;
+
+        self.update_submodules(filter, true, mirror_args, jobs)
     }
 
     /// Apply all patches to this repository.
@@ -307,6 +699,8 @@ impl Repository {
     /// Apply all patches to this repository only if they were not applied already.
     ///
     /// Uses [`is_applied`](Self::is_applied) to determine if the patches were already applied.
+    /// Use [`apply_series`](Self::apply_series) to record which patches were applied and
+    /// to update them when the patches change.
     pub fn apply_once(
         &self,
         patches: impl Iterator<Item = impl AsRef<OsStr>> + Clone,
@@ -336,6 +730,28 @@ impl Repository {
     }
 }
 
+/// The `--jobs` argument for cloning submodules.
+fn jobs() -> std::io::Result<String> {
+    // Jobs massivly speed up cloning all the submodules.
+    // The --jobs flag was introduced with git 2.9 in 2016, so we assume most people have it.
+    // https://github.blog/2016-06-13-git-2-9-has-been-released/
+    // git itself has a bug so jobs=0 doesnt work to get the number of cores (fixed only in >2.39)
+    // because of that we provide our own estimite via rust std
+    let cores = std::thread::available_parallelism()?;
+    Ok(format!("--jobs={cores}"))
+}
+
+/// Whether `commit` is a full (SHA-1 or SHA-256) commit hash.
+fn is_full_hash(commit: &str) -> bool {
+    matches!(commit.len(), 40 | 64) && commit.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Convert an error of the native backend into a [`CmdError`].
+#[cfg(feature = "git-native")]
+fn native_err(operation: &'static str) -> impl FnOnce(anyhow::Error) -> CmdError {
+    move |e| CmdError::Unsuccessful(format!("git {operation} (native)"), -1, Some(e))
+}
+
 /// The mode passed to `git reset HEAD --<mode>`.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ResetMode {
@@ -359,7 +775,7 @@ impl std::fmt::Display for ResetMode {
 }
 
 /// A reference to a git tag, branch or commit.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Ref {
     Tag(String),
     Branch(String),
@@ -371,11 +787,15 @@ impl Ref {
     ///
     /// The ref string can have the following format:
     /// - `commit:<hash>`: Uses the commit `<hash>` of the repository. Note that
-    ///   this will clone the whole repository not just one commit.
+    ///   this will clone the whole repository, unless a [depth](CloneOptions::depth) is
+    ///   used and `<hash>` is a full commit hash.
     /// - `tag:<tag>`: Uses the tag `<tag>` of the repository.
     /// - `branch:<branch>`: Uses the branch `<branch>` of the repository.
     /// - `v<major>.<minor>` or `<major>.<minor>`: Uses the tag `v<major>.<minor>` of the repository.
     /// - `<branch>`: Uses the branch `<branch>` of the repository.
+    ///
+    /// Without a prefix, whether the name is a branch or a tag is only guessed, use
+    /// [`Repository::resolve_remote_ref`] to ask the remote.
     pub fn parse(ref_str: impl AsRef<str>) -> Self {
         let ref_str = ref_str.as_ref().trim();
         assert!(
@@ -411,14 +831,71 @@ impl Display for Ref {
     }
 }
 
+/// What [`Repository::clone_ext`] did to the repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloneOutcome {
+    /// The requested ref was already checked out and nothing was changed.
+    Unchanged,
+    /// The checked out branch was updated with `git pull` (see
+    /// [`CloneOptions::branch_update_action`]).
+    Pulled,
+    /// The requested ref was fetched and checked out in the existing repository.
+    Updated,
+    /// The repository was cloned from scratch.
+    Cloned(CloneReason),
+}
+
+impl CloneOutcome {
+    /// Whether the repository was modified.
+    pub fn is_modified(&self) -> bool {
+        !matches!(self, Self::Unchanged)
+    }
+}
+
+impl Display for CloneOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unchanged => write!(f, "unchanged"),
+            Self::Pulled => write!(f, "pulled"),
+            Self::Updated => write!(f, "updated in place"),
+            Self::Cloned(reason) => write!(f, "cloned ({reason})"),
+        }
+    }
+}
+
+/// Why [`Repository::clone_ext`] cloned a repository from scratch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloneReason {
+    /// The repository did not exist.
+    New,
+    /// The repository has no remote with the requested url.
+    RemoteMismatch,
+    /// The work-tree was not clean.
+    Dirty,
+    /// Updating the repository in place failed with this error.
+    UpdateFailed(String),
+}
+
+impl Display for CloneReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::New => write!(f, "new repository"),
+            Self::RemoteMismatch => write!(f, "remote url changed"),
+            Self::Dirty => write!(f, "work-tree not clean"),
+            Self::UpdateFailed(e) => write!(f, "update failed: {e}"),
+        }
+    }
+}
+
 /// Options for how a repository should be cloned by [`Repository::clone_ext`].
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 #[must_use]
 pub struct CloneOptions {
     /// Force the working directory to be this specific tag, branch or commit.
     ///
-    /// On a missmatch between this value and the state of the physical repository, it is
-    /// deleted and cloned from scratch.
+    /// On a missmatch between this value and the state of the physical repository, the ref
+    /// is fetched and checked out in place. If that fails or the work-tree is not clean, the
+    /// repository is deleted and cloned from scratch.
     ///
     /// If this option specifies a branch name which maches the current branch of the
     /// physical repository and [`branch_update_action`](Self::branch_update_action) is
@@ -438,9 +915,17 @@ pub struct CloneOptions {
     pub force_clean: bool,
     /// The depth that should be cloned, if `None` the full repository is cloned.
     ///
-    /// Note that this option is ignored when [`force_ref`](Self::force_ref) specifies a
-    /// commit.
+    /// If [`force_ref`](Self::force_ref) specifies a commit by its full hash, only that
+    /// commit (and `depth - 1` of its ancestors) is fetched, if the server supports
+    /// fetching commits by hash, otherwise the full repository is cloned.
     pub depth: Option<NonZeroU64>,
+    /// Share the objects of the repository and its submodules with the mirrors in this
+    /// store instead of cloning them (see [`ObjectStore`]); [`depth`](Self::depth) is
+    /// ignored if set.
+    pub object_store: Option<ObjectStore>,
+    /// Only check out the submodules selected by this filter, if `None` all submodules
+    /// are checked out.
+    pub submodules: Option<SubmoduleFilter>,
 }
 
 impl CloneOptions {
@@ -450,8 +935,9 @@ impl CloneOptions {
 
     /// Force the working directory to be this specific tag, branch or commit.
     ///
-    /// On a missmatch between this value and the state of the physical repository, it is
-    /// deleted and cloned from scratch.
+    /// On a missmatch between this value and the state of the physical repository, the ref
+    /// is fetched and checked out in place. If that fails or the work-tree is not clean, the
+    /// repository is deleted and cloned from scratch.
     ///
     /// If this option specifies a branch name which maches the current branch of the
     /// physical repository and [`branch_update_action`](Self::branch_update_action) is
@@ -487,12 +973,32 @@ impl CloneOptions {
     ///
     /// `depth` must be greater than zero or else this method will panic.
     ///
-    /// Note that this option is ignored when [`force_ref`](Self::force_ref) specifies a
-    /// commit.
+    /// If [`force_ref`](Self::force_ref) specifies a commit by its full hash, only that
+    /// commit (and `depth - 1` of its ancestors) is fetched, if the server supports
+    /// fetching commits by hash, otherwise the full repository is cloned.
     pub fn depth(mut self, depth: u64) -> Self {
         self.depth = Some(NonZeroU64::new(depth).expect("depth must be greater than zero"));
         self
     }
+
+    /// Share the objects of the repository and its submodules with the mirrors in
+    /// `store` instead of cloning them.
+    ///
+    /// The repository is always cloned with the git CLI and [`depth`](Self::depth) is
+    /// ignored, as the mirrors contain the whole history anyway.
+    pub fn object_store(mut self, store: ObjectStore) -> Self {
+        self.object_store = Some(store);
+        self
+    }
+
+    /// Only check out the submodules selected by `filter` (see [`SubmoduleFilter`]).
+    ///
+    /// More submodules can be checked out later with
+    /// [`Repository::init_submodules`].
+    pub fn submodules(mut self, filter: SubmoduleFilter) -> Self {
+        self.submodules = Some(filter);
+        self
+    }
 }
 
 pub mod sdk {
@@ -502,8 +1008,9 @@ pub mod sdk {
     use std::path::Path;
 
     use anyhow::{anyhow, Context, Result};
+    use sha2::{Digest, Sha256};
 
-    use crate::git;
+    use crate::{cargo, git};
 
     /// A distinct version of the SDK repository to be installed.
     #[derive(Debug, Clone)]
@@ -516,21 +1023,156 @@ pub mod sdk {
 
     impl RemoteSdk {
         /// Clone the repository or open if it exists and matches [`RemoteSdk::git_ref`].
+        ///
+        /// The repository is cloned into `<install_dir>/<managed_repo_dir_base>/<ref>`, or
+        /// into `<install_dir>/<managed_repo_dir_base>-<hash>/<ref>` for a custom
+        /// `repo_url` (see [`url_hash`]). Repositories stored under the
+        /// hash of previous versions are moved to the new directory.
+        ///
+        /// Unless the ref is checked out already, the remote is asked whether it is a
+        /// branch or a tag (see [`resolve`](Self::resolve)). A clone that was interrupted
+        /// (see [`git::Repository::verify_head`]) is repaired before it is used.
+        ///
+        /// If there is a lockfile (see [`git::Lockfile::from_env`]), the commit locked in
+        /// it is checked out (see [`open_or_clone_locked`](Self::open_or_clone_locked)),
+        /// unless an update is requested with [`git::UPDATE_LOCK_VAR`]. Both
+        /// [`git::LOCKFILE_VAR`] and [`git::UPDATE_LOCK_VAR`], and the lockfile if it
+        /// exists, are tracked so that cargo runs the build script again if they change.
         pub fn open_or_clone(
             &self,
             install_dir: &Path,
             options: git::CloneOptions,
             default_repo: &str,
             managed_repo_dir_base: &str,
+        ) -> Result<git::Repository> {
+            cargo::track_env_var(git::LOCKFILE_VAR);
+            cargo::track_env_var(git::UPDATE_LOCK_VAR);
+
+            match git::Lockfile::from_env()? {
+                Some(mut lockfile) => {
+                    let repository = self.open_or_clone_locked(
+                        install_dir,
+                        options,
+                        default_repo,
+                        managed_repo_dir_base,
+                        &mut lockfile,
+                        git::Lockfile::update_requested(),
+                    )?;
+                    if lockfile.path().exists() {
+                        cargo::track_file(lockfile.path());
+                    }
+                    Ok(repository)
+                }
+                None => self.open_or_clone_at(
+                    install_dir,
+                    options,
+                    default_repo,
+                    managed_repo_dir_base,
+                    None,
+                    false,
+                ),
+            }
+        }
+
+        /// Like [`open_or_clone`](Self::open_or_clone), but check out the commit locked
+        /// in `lockfile` for the url and [`git_ref`](Self::git_ref).
+        ///
+        /// If the ref is not locked yet, the commit an existing clone has checked out is
+        /// locked; only if there is no clone yet the ref is resolved by the remote (see
+        /// [`resolve`](Self::resolve)). If `update` is `true`, the ref is always resolved
+        /// by the remote and its commit is checked out and locked. `lockfile` is saved if
+        /// the lock changed.
+        pub fn open_or_clone_locked(
+            &self,
+            install_dir: &Path,
+            options: git::CloneOptions,
+            default_repo: &str,
+            managed_repo_dir_base: &str,
+            lockfile: &mut git::Lockfile,
+            update: bool,
+        ) -> Result<git::Repository> {
+            let url = self.repo_url(default_repo);
+            let locked = if update {
+                None
+            } else {
+                lockfile.get(url, &self.git_ref).cloned()
+            };
+
+            let commit = match &locked {
+                Some(locked) => Some(locked.commit.clone()),
+                None if update => Some(self.resolve(default_repo)?.commit),
+                None => None,
+            };
+            let repository = self.open_or_clone_at(
+                install_dir,
+                options,
+                default_repo,
+                managed_repo_dir_base,
+                commit.map(git::Ref::Commit),
+                true,
+            )?;
+
+            match locked {
+                // The submodules of a lock made by `Lockfile::update` are only known
+                // after the checkout.
+                Some(locked) if locked.submodules.is_empty() => {
+                    if lockfile.lock(url, &self.git_ref, &repository)? != &locked {
+                        lockfile.save()?;
+                    }
+                }
+                Some(locked) => {
+                    // The submodule commits follow from the locked commit, unless they
+                    // were changed in the work-tree.
+                    for submodule in repository.submodules()? {
+                        let locked_commit = locked.submodules.get(&submodule.path);
+                        match (locked_commit, &submodule.checked_out) {
+                            (Some(locked), Some(commit)) if locked != commit => log::warn!(
+                                "Submodule '{}' has commit {commit} checked out instead of the \
+                                 locked commit {locked}",
+                                submodule.path
+                            ),
+                            _ => (),
+                        }
+                    }
+                }
+                None => {
+                    lockfile.lock(url, &self.git_ref, &repository)?;
+                    lockfile.save()?;
+                }
+            }
+
+            Ok(repository)
+        }
+
+        /// Clone or open the repository and check out `git_ref` (or else
+        /// [`git_ref`](Self::git_ref)).
+        ///
+        /// If `keep_checkout` is `true` and `git_ref` is [`None`], an existing clone keeps
+        /// the commit it has checked out.
+        fn open_or_clone_at(
+            &self,
+            install_dir: &Path,
+            options: git::CloneOptions,
+            default_repo: &str,
+            managed_repo_dir_base: &str,
+            git_ref: Option<git::Ref>,
+            keep_checkout: bool,
         ) -> Result<git::Repository> {
             // Only append a hash of the git remote URL to the parent folder name of the
             // repository if this is not the default remote.
-            let folder_name = if let Some(hash) = self.url_hash() {
-                format!("{managed_repo_dir_base}-{hash}")
+            let folder_name = if let Some(url) = &self.repo_url {
+                format!("{managed_repo_dir_base}-{}", url_hash(url))
             } else {
                 managed_repo_dir_base.to_owned()
             };
             let repos_dir = install_dir.join(folder_name);
+            if let Some(url) = self.repo_url.as_ref().filter(|_| !repos_dir.exists()) {
+                let legacy_dir =
+                    install_dir.join(format!("{managed_repo_dir_base}-{}", legacy_url_hash(url)));
+                if legacy_dir.is_dir() {
+                    migrate(&legacy_dir, &repos_dir, &options);
+                }
+            }
             if !repos_dir.exists() {
                 fs::create_dir(&repos_dir).with_context(|| {
                     anyhow!("could not create folder '{}'", repos_dir.display())
@@ -540,36 +1182,52 @@ pub mod sdk {
             let repo_path = repos_dir.join(self.repo_dir());
             let mut repository = git::Repository::new(repo_path);
 
-            repository.clone_ext(
-                self.repo_url(default_repo),
-                options.force_ref(self.git_ref.clone()),
-            )?;
+            let checked_out = if keep_checkout && repository.git_dir.exists() {
+                repository.head_commit().ok()
+            } else {
+                None
+            };
+            // Whether the ref is a branch or a tag is only guessed by `Ref::parse`, so ask
+            // the remote unless the ref is already checked out.
+            let git_ref = if let Some(git_ref) = git_ref {
+                git_ref
+            } else if let Some(commit) = checked_out {
+                git::Ref::Commit(commit)
+            } else if repository.git_dir.exists() && repository.is_ref(&self.git_ref) {
+                self.git_ref.clone()
+            } else {
+                match self.resolve(default_repo) {
+                    Ok(resolved) => resolved.git_ref,
+                    Err(e) => {
+                        log::warn!("Could not resolve {}: {e:#}", self.git_ref);
+                        self.git_ref.clone()
+                    }
+                }
+            };
+            let options = options.force_ref(git_ref);
+
+            // An interrupted clone leaves a half-initialized repository behind, which
+            // `clone_ext` would use as is. Only the cheap checks are done on every build.
+            if repository.git_dir.exists() && !repository.verify_head()?.is_empty() {
+                repository.repair(self.repo_url(default_repo), options.clone())?;
+            }
+            repository.clone_ext(self.repo_url(default_repo), options)?;
 
             Ok(repository)
         }
 
+        /// Ask the remote whether [`git_ref`](Self::git_ref) is a branch or a tag and
+        /// which commit it points to (see [`git::Repository::resolve_remote_ref`]).
+        pub fn resolve(&self, default_repo: &str) -> Result<git::ResolvedRef> {
+            git::Repository::resolve_remote_ref(self.repo_url(default_repo), &self.git_ref)
+        }
+
         /// Return the URL of the GIT repository.
         /// If `repo_url` is [`None`], then the default SDK repository is returned.
         fn repo_url<'a>(&'a self, default_repo: &'a str) -> &'a str {
             self.repo_url.as_deref().unwrap_or(default_repo)
         }
 
-        /// Create a hash when a custom repo_url is specified.
-        fn url_hash(&self) -> Option<String> {
-            // This uses the default hasher from the standard library, which is not guaranteed
-            // to be the same across versions, but if the hash algorithm changes and assuming
-            // a different hash, the logic above will happily clone the repo in a different
-            // directory. It also uses a 64 bit hash by which the chance for collisions is
-            // pretty small (assuming a good hash function) and even if there is a collision
-            // it will still work (and also even if the ref is the same), though the cloned
-            // repo will be in the same folder as a repo from another remote URL.
-            // Cargo actually does something similar for the out-dirs though it uses the
-            // deprecated `std::hash::SipHasher` instead.
-            let mut hasher = DefaultHasher::new();
-            self.repo_url.as_ref()?.hash(&mut hasher);
-            Some(format!("{:x}", hasher.finish()))
-        }
-
         /// Translate the ref name to a directory name.
         ///
         /// This heaviliy sanitizes that name as it translates an arbitrary git tag, branch or
@@ -600,4 +1258,318 @@ pub mod sdk {
             ref_name
         }
     }
+
+    /// Return a stable hash of the remote `url`, used to name the directory of its clones.
+    ///
+    /// This is the SHA-256 of the url without surrounding whitespace, trailing slashes
+    /// and a `.git` suffix, truncated to 16 hex digits. So `https://host/repo`,
+    /// `https://host/repo/` and `https://host/repo.git` have the same hash.
+    pub fn url_hash(url: &str) -> String {
+        let url = url.trim().trim_end_matches('/');
+        let url = url
+            .strip_suffix(".git")
+            .unwrap_or(url)
+            .trim_end_matches('/');
+
+        Sha256::digest(url.as_bytes())[..8]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    /// The hash of `url` used by previous versions, which is not stable across rust
+    /// versions.
+    fn legacy_url_hash(url: &str) -> String {
+        let mut hasher = DefaultHasher::new();
+        url.hash(&mut hasher);
+        format!("{:x}", hasher.finish())
+    }
+
+    /// Move the clones in `legacy_dir` to `repos_dir`.
+    ///
+    /// If that fails the repository is cloned again into `repos_dir`.
+    fn migrate(legacy_dir: &Path, repos_dir: &Path, options: &git::CloneOptions) {
+        log::info!(
+            "Moving '{}' to '{}'",
+            legacy_dir.display(),
+            repos_dir.display()
+        );
+        let result = fs::rename(legacy_dir, repos_dir)
+            .map_err(anyhow::Error::from)
+            .and_then(|()| match &options.object_store {
+                Some(store) => store.relocate(legacy_dir, repos_dir),
+                None => Ok(()),
+            });
+        if let Err(e) = result {
+            log::warn!(
+                "Could not move '{}' to '{}': {e:#}",
+                legacy_dir.display(),
+                repos_dir.display()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process::{Command, Output};
+    use std::sync::Arc;
+
+    use super::*;
+
+    pub(super) fn git(dir: &Path, args: &[&str]) -> String {
+        cmd!(GIT, "-c", "user.name=test", "-c", "user.email=test@example.com", "-c", "protocol.file.allow=always"; args=(args), current_dir=(dir))
+            .stdout()
+            .unwrap()
+    }
+
+    /// Runs the actual commands, but allows git to clone local `file://` submodules,
+    /// which it refuses by default.
+    struct AllowFileProtocol;
+
+    impl crate::cmd::Executor for AllowFileProtocol {
+        fn execute(&self, cmd: &Command) -> std::io::Result<Output> {
+            let mut command = Command::new(cmd.get_program());
+            command.args(cmd.get_args());
+            if let Some(dir) = cmd.get_current_dir() {
+                command.current_dir(dir);
+            }
+            for (key, value) in cmd.get_envs() {
+                match value {
+                    Some(value) => command.env(key, value),
+                    None => command.env_remove(key),
+                };
+            }
+            command
+                .env("GIT_CONFIG_COUNT", "1")
+                .env("GIT_CONFIG_KEY_0", "protocol.file.allow")
+                .env("GIT_CONFIG_VALUE_0", "always")
+                .output()
+        }
+    }
+
+    /// Allow the git commands of the current test to clone local `file://` submodules.
+    pub(super) fn allow_file_protocol() {
+        crate::cmd::set_executor(Some(Arc::new(AllowFileProtocol)));
+    }
+
+    /// Create the bare repository `origin.git` in `dir` and return its url and the
+    /// commit of tag `v2`.
+    ///
+    /// Tag `v1` has no submodules, `v2` adds submodule `lib` and branch `main` has
+    /// one more commit.
+    pub(super) fn create_origin(dir: &Path) -> (String, String) {
+        let (lib, work) = (dir.join("lib"), dir.join("work"));
+        for (repo, file) in [(&lib, "lib.txt"), (&work, "file.txt")] {
+            fs::create_dir(repo).unwrap();
+            git(repo, &["init", "-q", "-b", "main"]);
+            for i in 0..3 {
+                fs::write(repo.join(file), i.to_string()).unwrap();
+                git(repo, &["add", "."]);
+                git(repo, &["commit", "-q", "-m", &i.to_string()]);
+            }
+        }
+        git(&work, &["tag", "v1"]);
+        git(&work, &["submodule", "add", "-q", "../lib", "lib"]);
+        git(&work, &["commit", "-q", "-m", "submodule"]);
+        git(&work, &["tag", "v2"]);
+        let commit = git(&work, &["rev-parse", "HEAD"]);
+        fs::write(work.join("file.txt"), "3").unwrap();
+        git(&work, &["commit", "-q", "-am", "3"]);
+        git(dir, &["clone", "-q", "--bare", "work", "origin.git"]);
+
+        (
+            crate::utils::path_to_file_url(&dir.join("origin.git")),
+            commit,
+        )
+    }
+
+    #[test]
+    fn clone_commit_shallow() {
+        allow_file_protocol();
+        let dir = tempfile::tempdir().unwrap();
+        let (url, commit) = create_origin(dir.path());
+
+        let mut repo = Repository::new(dir.path().join("shallow")).with_backend(Backend::Cli);
+        let options = CloneOptions::new()
+            .force_ref(Ref::Commit(commit.clone()))
+            .depth(1);
+        assert!(repo.clone_ext(&url, options).unwrap().is_modified());
+        assert!(repo.is_shallow());
+        assert!(repo.is_ref(&Ref::Commit(commit.clone())));
+        assert_eq!(git(repo.worktree(), &["rev-list", "--count", "HEAD"]), "1");
+        assert_eq!(
+            fs::read_to_string(repo.worktree().join("file.txt")).unwrap(),
+            "2"
+        );
+        let lib = repo.worktree().join("lib");
+        assert_eq!(fs::read_to_string(lib.join("lib.txt")).unwrap(), "2");
+        assert_eq!(git(&lib, &["rev-parse", "--is-shallow-repository"]), "true");
+
+        // Abbreviated hashes cannot be fetched, so the whole repository is cloned.
+        let mut repo = Repository::new(dir.path().join("full")).with_backend(Backend::Cli);
+        let options = CloneOptions::new()
+            .force_ref(Ref::Commit(commit[..12].to_owned()))
+            .depth(1);
+        assert!(repo.clone_ext(&url, options).unwrap().is_modified());
+        assert!(!repo.is_shallow());
+        assert_eq!(
+            fs::read_to_string(repo.worktree().join("file.txt")).unwrap(),
+            "2"
+        );
+    }
+
+    #[test]
+    fn update_in_place() {
+        allow_file_protocol();
+        let dir = tempfile::tempdir().unwrap();
+        let (url, commit) = create_origin(dir.path());
+        let read = |repo: &Repository, file: &str| {
+            fs::read_to_string(repo.worktree().join(file)).unwrap_or_default()
+        };
+
+        let mut repo = Repository::new(dir.path().join("repo")).with_backend(Backend::Cli);
+        let mut clone = |git_ref: Ref, options: CloneOptions| {
+            let outcome = repo.clone_ext(&url, options.force_ref(git_ref.clone()).depth(1));
+            assert!(repo.is_ref(&git_ref), "{git_ref} not checked out");
+            (outcome.unwrap(), repo.clone())
+        };
+
+        let (outcome, repo) = clone(Ref::Tag("v1".into()), CloneOptions::new());
+        assert_eq!(outcome, CloneOutcome::Cloned(CloneReason::New));
+        assert_eq!(read(&repo, "lib/lib.txt"), "");
+
+        let (outcome, repo) = clone(Ref::Tag("v2".into()), CloneOptions::new());
+        assert_eq!(outcome, CloneOutcome::Updated);
+        assert!(repo.is_shallow());
+        assert_eq!(read(&repo, "lib/lib.txt"), "2");
+
+        let (outcome, _) = clone(Ref::Tag("v2".into()), CloneOptions::new());
+        assert_eq!(outcome, CloneOutcome::Unchanged);
+
+        let (outcome, repo) = clone(Ref::Branch("main".into()), CloneOptions::new());
+        assert_eq!(outcome, CloneOutcome::Updated);
+        assert_eq!(read(&repo, "file.txt"), "3");
+
+        let options = CloneOptions::new().branch_update_action(ResetMode::Hard);
+        let (outcome, _) = clone(Ref::Branch("main".into()), options);
+        assert_eq!(outcome, CloneOutcome::Pulled);
+
+        let (outcome, repo) = clone(Ref::Commit(commit), CloneOptions::new());
+        assert_eq!(outcome, CloneOutcome::Updated);
+        assert_eq!(read(&repo, "file.txt"), "2");
+
+        fs::write(repo.worktree().join("file.txt"), "changed").unwrap();
+        let (outcome, repo) = clone(Ref::Tag("v1".into()), CloneOptions::new());
+        assert_eq!(outcome, CloneOutcome::Cloned(CloneReason::Dirty));
+        assert_eq!(read(&repo, "file.txt"), "2");
+    }
+
+    #[test]
+    fn sdk_url_hash() {
+        let hash = sdk::url_hash("https://github.com/espressif/esp-idf.git");
+        assert_eq!(hash.len(), 16);
+        for url in [
+            "https://github.com/espressif/esp-idf",
+            "https://github.com/espressif/esp-idf/",
+            "https://github.com/espressif/esp-idf.git/",
+        ] {
+            assert_eq!(sdk::url_hash(url), hash);
+        }
+        assert_ne!(sdk::url_hash("https://github.com/espressif/esp-idf2"), hash);
+    }
+
+    #[test]
+    fn sdk_migrate_legacy_dir() {
+        allow_file_protocol();
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+
+        let dir = tempfile::tempdir().unwrap();
+        let (url, _) = create_origin(dir.path());
+        let install_dir = dir.path().join("install");
+
+        let mut hasher = DefaultHasher::new();
+        url.hash(&mut hasher);
+        let legacy_dir = install_dir.join(format!("sdk-{:x}", hasher.finish()));
+        fs::create_dir_all(&legacy_dir).unwrap();
+        let mut repo = Repository::new(legacy_dir.join("v2"));
+        repo.clone_ext(&url, CloneOptions::new().force_ref(Ref::Tag("v2".into())))
+            .unwrap();
+        fs::write(repo.worktree().join("untracked.txt"), "").unwrap();
+
+        let sdk = sdk::RemoteSdk {
+            repo_url: Some(url.clone()),
+            git_ref: Ref::Tag("v2".into()),
+        };
+        let repo = sdk
+            .open_or_clone(&install_dir, CloneOptions::new(), "unused", "sdk")
+            .unwrap();
+        assert_eq!(
+            repo.worktree(),
+            install_dir
+                .join(format!("sdk-{}", sdk::url_hash(&url)))
+                .join("v2")
+        );
+        assert!(!legacy_dir.exists());
+        // The repository was moved instead of cloned again.
+        assert!(repo.worktree().join("untracked.txt").exists());
+    }
+
+    #[test]
+    fn populate_mirror() {
+        allow_file_protocol();
+        let dir = tempfile::tempdir().unwrap();
+        let (url, v2) = create_origin(dir.path());
+        let mirror_dir = dir.path().join("mirror");
+        let mirrors = crate::utils::Mirrors::new()
+            .with(
+                crate::utils::path_to_file_url(dir.path()) + "/",
+                format!("{}/", mirror_dir.display()),
+            )
+            .unwrap();
+
+        let path = mirrors.populate_git(&url).unwrap();
+        assert_eq!(path, mirror_dir.join("origin.git"));
+        // The submodule `../lib` of tag `v2` is mirrored too.
+        assert!(mirror_dir.join("lib").join("HEAD").exists());
+
+        // The mirrors are complete without the original repositories.
+        fs::rename(dir.path().join("origin.git"), dir.path().join("moved.git")).unwrap();
+        fs::rename(dir.path().join("lib"), dir.path().join("moved")).unwrap();
+        let mut args = mirrors.git_config_args();
+        args.extend(
+            [
+                "clone",
+                "-q",
+                "--recurse-submodules",
+                "-b",
+                "v2",
+                &url,
+                "clone",
+            ]
+            .map(String::from),
+        );
+        git(
+            dir.path(),
+            &args.iter().map(String::as_str).collect::<Vec<_>>(),
+        );
+        let clone = dir.path().join("clone");
+        assert_eq!(git(&clone, &["rev-parse", "HEAD"]), v2);
+        assert_eq!(fs::read_to_string(clone.join("lib/lib.txt")).unwrap(), "2");
+
+        // An existing mirror is updated.
+        fs::rename(dir.path().join("moved.git"), dir.path().join("origin.git")).unwrap();
+        fs::rename(dir.path().join("moved"), dir.path().join("lib")).unwrap();
+        let work = dir.path().join("work");
+        fs::write(work.join("file.txt"), "4").unwrap();
+        git(&work, &["commit", "-q", "-am", "4"]);
+        git(&work, &["push", "-q", "../origin.git", "main"]);
+        mirrors.populate_git(&url).unwrap();
+        assert_eq!(
+            git(&path, &["rev-parse", "main"]),
+            git(&work, &["rev-parse", "HEAD"])
+        );
+    }
 }
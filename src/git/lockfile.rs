@@ -0,0 +1,302 @@
+//! A lockfile pinning the commits that refs of remote repositories resolved to.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{Ref, Repository};
+
+/// The default file name of a [`Lockfile`], which is stored next to `Cargo.lock`.
+pub const LOCKFILE_NAME: &str = "embuild.lock";
+
+/// Environment variable with the path of the [`Lockfile`] used by
+/// [`Lockfile::from_env`]; an empty value disables the lockfile.
+pub const LOCKFILE_VAR: &str = "EMBUILD_LOCKFILE";
+
+/// Environment variable which, if set to anything but `0` or `false`, requests to
+/// resolve all locked refs again and update the [`Lockfile`] (see
+/// [`Lockfile::update_requested`]).
+pub const UPDATE_LOCK_VAR: &str = "EMBUILD_UPDATE_LOCK";
+
+const VERSION: u32 = 1;
+const HEADER: &str = "# This file is generated by embuild, do not edit it manually.\n\
+                      # Set EMBUILD_UPDATE_LOCK=1 to update it.\n";
+
+/// The commit (and the commits of its submodules) a ref of a remote repository was
+/// resolved to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockedRepo {
+    /// The url of the remote repository.
+    pub url: String,
+    /// The requested ref, as `branch:<name>`, `tag:<name>` or `commit:<hash>` (see
+    /// [`Ref::parse`]).
+    #[serde(rename = "ref")]
+    pub git_ref: String,
+    /// The commit the ref was resolved to.
+    pub commit: String,
+    /// The commits of the checked out submodules by their path (relative to the
+    /// work-tree, separated by `/`).
+    #[serde(default)]
+    pub submodules: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LockfileContents {
+    version: u32,
+    #[serde(default, rename = "repo")]
+    repos: Vec<LockedRepo>,
+}
+
+/// A lockfile (`embuild.lock`) that records which commit each ref of a remote
+/// repository resolved to, so that a branch is checked out at the same commit in later
+/// builds until the lock is updated.
+#[derive(Debug, Clone)]
+pub struct Lockfile {
+    path: PathBuf,
+    repos: Vec<LockedRepo>,
+}
+
+impl Lockfile {
+    /// Load the lockfile at `path`, which is empty if it doesn't exist.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let repos = if path.exists() {
+            let contents = fs::read_to_string(path)?;
+            let contents: LockfileContents = toml::from_str(&contents)
+                .with_context(|| format!("could not parse lockfile '{}'", path.display()))?;
+            if contents.version > VERSION {
+                bail!(
+                    "lockfile '{}' has version {}, but only version {VERSION} is supported",
+                    path.display(),
+                    contents.version
+                );
+            }
+            contents.repos
+        } else {
+            Vec::new()
+        };
+
+        Ok(Self {
+            path: path.to_owned(),
+            repos,
+        })
+    }
+
+    /// Load the lockfile at the path in [`LOCKFILE_VAR`], or else [`LOCKFILE_NAME`] in
+    /// the [cargo workspace dir](crate::cargo::workspace_dir).
+    ///
+    /// Returns [`None`] if [`LOCKFILE_VAR`] is empty. If it is not set, a lockfile in
+    /// the workspace dir is only created if an update is
+    /// [requested](Self::update_requested) (and the workspace dir is either set with
+    /// `CARGO_WORKSPACE_DIR` or contains `Cargo.lock`), otherwise only an existing one is
+    /// used, so that no lockfile is written into a workspace that did not opt in.
+    ///
+    /// Note that cargo only runs a build script again if these environment variables or
+    /// the lockfile changed when they are tracked; [`RemoteSdk::open_or_clone`] tracks
+    /// them with [`track_env_var`](crate::cargo::track_env_var) and
+    /// [`track_file`](crate::cargo::track_file).
+    ///
+    /// [`RemoteSdk::open_or_clone`]: super::sdk::RemoteSdk::open_or_clone
+    pub fn from_env() -> Result<Option<Self>> {
+        let path = match env::var_os(LOCKFILE_VAR) {
+            Some(path) if path.is_empty() => return Ok(None),
+            Some(path) => PathBuf::from(path),
+            None => match crate::cargo::workspace_dir() {
+                Some(dir) if dir.join(LOCKFILE_NAME).is_file() => dir.join(LOCKFILE_NAME),
+                Some(dir)
+                    if Self::update_requested()
+                        && (env::var_os("CARGO_WORKSPACE_DIR")
+                            .map_or(false, |d| !d.is_empty())
+                            || dir.join("Cargo.lock").is_file()) =>
+                {
+                    dir.join(LOCKFILE_NAME)
+                }
+                _ => return Ok(None),
+            },
+        };
+        Self::load(path).map(Some)
+    }
+
+    /// Whether an update of the lockfile was requested with [`UPDATE_LOCK_VAR`].
+    pub fn update_requested() -> bool {
+        env::var(UPDATE_LOCK_VAR)
+            .map(|v| !v.is_empty() && v != "0" && !v.eq_ignore_ascii_case("false"))
+            .unwrap_or(false)
+    }
+
+    /// The path of this lockfile.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// All locked repositories.
+    pub fn repos(&self) -> &[LockedRepo] {
+        &self.repos
+    }
+
+    /// Get the locked commit of `git_ref` of `url`.
+    pub fn get(&self, url: &str, git_ref: &Ref) -> Option<&LockedRepo> {
+        let git_ref = ref_spec(git_ref);
+        self.repos
+            .iter()
+            .find(|r| r.url == url && r.git_ref == git_ref)
+    }
+
+    /// Lock `git_ref` of `url` to the commit (and the submodule commits) checked out in
+    /// `repo`, replacing a previous lock.
+    pub fn lock(&mut self, url: &str, git_ref: &Ref, repo: &Repository) -> Result<&LockedRepo> {
+        let submodules = repo
+            .submodules()?
+            .into_iter()
+            .filter_map(|s| s.checked_out.map(|commit| (s.path, commit)))
+            .collect();
+        let locked = LockedRepo {
+            url: url.to_owned(),
+            git_ref: ref_spec(git_ref),
+            commit: repo.head_commit()?,
+            submodules,
+        };
+
+        self.insert(locked);
+        Ok(self.get(url, git_ref).unwrap())
+    }
+
+    /// Ask the remote `url` which commit `git_ref` points to now (see
+    /// [`Repository::resolve_remote_ref`]) and lock it, replacing a previous lock.
+    ///
+    /// The commits of the submodules are not known without a clone, so they are recorded
+    /// by the next [`RemoteSdk::open_or_clone_locked`] that checks out the new commit.
+    /// Call [`save`](Self::save) to write the lockfile.
+    ///
+    /// [`RemoteSdk::open_or_clone_locked`]: super::sdk::RemoteSdk::open_or_clone_locked
+    pub fn update(&mut self, url: &str, git_ref: &Ref) -> Result<&LockedRepo> {
+        let resolved = Repository::resolve_remote_ref(url, git_ref)?;
+        self.insert(LockedRepo {
+            url: url.to_owned(),
+            git_ref: ref_spec(git_ref),
+            commit: resolved.commit,
+            submodules: BTreeMap::new(),
+        });
+        Ok(self.get(url, git_ref).unwrap())
+    }
+
+    fn insert(&mut self, locked: LockedRepo) {
+        self.repos
+            .retain(|r| (&r.url, &r.git_ref) != (&locked.url, &locked.git_ref));
+        self.repos.push(locked);
+        self.repos
+            .sort_by(|a, b| (&a.url, &a.git_ref).cmp(&(&b.url, &b.git_ref)));
+    }
+
+    /// Remove the lock of `git_ref` of `url`, so that it is resolved again.
+    pub fn remove(&mut self, url: &str, git_ref: &Ref) -> Option<LockedRepo> {
+        let git_ref = ref_spec(git_ref);
+        let index = self
+            .repos
+            .iter()
+            .position(|r| r.url == url && r.git_ref == git_ref)?;
+        Some(self.repos.remove(index))
+    }
+
+    /// Write this lockfile to its [path](Self::path), if it changed.
+    pub fn save(&self) -> Result<()> {
+        let contents = LockfileContents {
+            version: VERSION,
+            repos: self.repos.clone(),
+        };
+        let contents = format!("{HEADER}\n{}", toml::to_string(&contents)?);
+        crate::fs::write_atomic_if_changed(&self.path, contents)
+            .with_context(|| format!("could not write lockfile '{}'", self.path.display()))?;
+        Ok(())
+    }
+}
+
+/// Format `git_ref` so that it is parsed to the same [`Ref`] by [`Ref::parse`].
+fn ref_spec(git_ref: &Ref) -> String {
+    match git_ref {
+        Ref::Branch(b) => format!("branch:{b}"),
+        Ref::Tag(t) => format!("tag:{t}"),
+        Ref::Commit(c) => format!("commit:{c}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{allow_file_protocol, create_origin};
+    use super::super::{sdk, CloneOptions};
+    use super::*;
+
+    #[test]
+    fn lock_sdk() {
+        allow_file_protocol();
+        let dir = tempfile::tempdir().unwrap();
+        let (url, v2) = create_origin(dir.path());
+        let install_dir = dir.path().join("install");
+        fs::create_dir(&install_dir).unwrap();
+        let lock_path = dir.path().join(LOCKFILE_NAME);
+        let mut lockfile = Lockfile::load(&lock_path).unwrap();
+
+        let sdk = sdk::RemoteSdk {
+            repo_url: Some(url.clone()),
+            git_ref: Ref::Branch("main".into()),
+        };
+        let open = |lockfile: &mut Lockfile, update| {
+            sdk.open_or_clone_locked(
+                &install_dir,
+                CloneOptions::new(),
+                "unused",
+                "sdk",
+                lockfile,
+                update,
+            )
+            .unwrap()
+        };
+
+        let repo = open(&mut lockfile, false);
+        let main = repo.head_commit().unwrap();
+        let locked = lockfile.get(&url, &sdk.git_ref).unwrap().clone();
+        assert_eq!(locked.commit, main);
+        assert_eq!(locked.git_ref, "branch:main");
+        assert_eq!(locked.submodules.keys().collect::<Vec<_>>(), ["lib"]);
+        assert_eq!(
+            Lockfile::load(&lock_path).unwrap().repos(),
+            std::slice::from_ref(&locked)
+        );
+
+        // The locked commit is used even though the branch moved.
+        lockfile.repos[0].commit = v2.clone();
+        let repo = open(&mut lockfile, false);
+        assert_eq!(repo.head_commit().unwrap(), v2);
+
+        // An update resolves the branch again.
+        let repo = open(&mut lockfile, true);
+        assert_eq!(repo.head_commit().unwrap(), main);
+        assert_eq!(lockfile.get(&url, &sdk.git_ref), Some(&locked));
+
+        // `Lockfile::update` only locks the commit, the submodules are locked on checkout.
+        lockfile.repos[0].commit = v2;
+        let updated = lockfile.update(&url, &sdk.git_ref).unwrap();
+        assert_eq!(updated.commit, main);
+        assert!(updated.submodules.is_empty());
+        lockfile.save().unwrap();
+        let repo = open(&mut lockfile, false);
+        assert_eq!(repo.head_commit().unwrap(), main);
+        assert_eq!(lockfile.get(&url, &sdk.git_ref), Some(&locked));
+        assert_eq!(
+            Lockfile::load(&lock_path).unwrap().repos(),
+            std::slice::from_ref(&locked)
+        );
+
+        // Without a lock, the commit of an existing clone is locked without asking the
+        // remote (which is no longer there).
+        fs::rename(dir.path().join("origin.git"), dir.path().join("moved.git")).unwrap();
+        let mut lockfile = Lockfile::load(dir.path().join("other.lock")).unwrap();
+        let repo = open(&mut lockfile, false);
+        assert_eq!(repo.head_commit().unwrap(), main);
+        assert_eq!(lockfile.get(&url, &sdk.git_ref), Some(&locked));
+    }
+}
@@ -0,0 +1,437 @@
+//! The native git backend (see [`Backend::Native`](super::Backend::Native)), implemented
+//! with [`gix`].
+
+use std::fs;
+use std::io::Write;
+use std::num::{NonZeroU32, NonZeroU64};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+
+use anyhow::{anyhow, bail, Context, Result};
+use gix::bstr::ByteSlice;
+use gix::objs::Kind;
+use gix::progress::Discard;
+use gix::protocol::handshake;
+use gix::refs::transaction::{Change, LogChange, PreviousValue, RefEdit, RefLog};
+use gix::refs::Target;
+use gix::remote::Direction;
+
+use super::submodule::join_path;
+use super::{Ref, SubmoduleFilter};
+
+fn open(worktree: &Path) -> Result<gix::Repository> {
+    gix::open(worktree).with_context(|| format!("'{}' is not a git repository", worktree.display()))
+}
+
+/// Open the repository with the worktree `dir` and return its git dir.
+pub(super) fn git_dir(dir: &Path) -> Result<PathBuf> {
+    let repo = open(dir)?;
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| anyhow!("'{}' is a bare git repository", dir.display()))?;
+
+    if workdir.canonicalize()? != dir.canonicalize()? {
+        bail!(
+            "'{}' is not the top level of a git repository",
+            dir.display()
+        );
+    }
+
+    Ok(repo.git_dir().to_owned())
+}
+
+/// Get all remote names and their urls.
+pub(super) fn remotes(worktree: &Path) -> Result<Vec<(String, String)>> {
+    let repo = open(worktree)?;
+    let config = repo.config_snapshot();
+
+    Ok(repo
+        .remote_names()
+        .iter()
+        .filter_map(|name| {
+            let url = config.string(format!("remote.{name}.url").as_str())?;
+            Some((name.to_string(), url.to_string()))
+        })
+        .collect())
+}
+
+/// Get the default branch name of `remote` by asking the remote where its `HEAD` points
+/// to.
+pub(super) fn default_branch_of(worktree: &Path, remote: &str) -> Result<String> {
+    let repo = open(worktree)?;
+    let remote = repo.find_remote(remote)?;
+
+    let head_refspec = gix::refspec::parse("HEAD".into(), gix::refspec::parse::Operation::Fetch)?;
+    let (ref_map, _) = remote.connect(Direction::Fetch)?.ref_map(
+        Discard,
+        gix::remote::ref_map::Options {
+            extra_refspecs: vec![head_refspec.to_owned()],
+            ..Default::default()
+        },
+    )?;
+
+    ref_map
+        .remote_refs
+        .iter()
+        .find_map(|r| match r {
+            handshake::Ref::Symbolic {
+                full_ref_name,
+                target,
+                ..
+            }
+            | handshake::Ref::Unborn {
+                full_ref_name,
+                target,
+            } if full_ref_name == "HEAD" => target.strip_prefix(b"refs/heads/"),
+            _ => None,
+        })
+        .map(|branch| branch.to_str_lossy().into_owned())
+        .ok_or_else(|| anyhow!("Remote did not advertise its HEAD branch"))
+}
+
+/// Whether the worktree is clean, ignoring untracked files.
+pub(super) fn is_clean(worktree: &Path) -> Result<bool> {
+    Ok(!open(worktree)?.is_dirty()?)
+}
+
+/// Get the full hash of the `HEAD` commit.
+pub(super) fn head_commit(worktree: &Path) -> Result<String> {
+    Ok(open(worktree)?.head_id()?.to_string())
+}
+
+/// Get the current branch name, if `HEAD` points to a branch.
+pub(super) fn branch_name(worktree: &Path) -> Result<Option<String>> {
+    Ok(open(worktree)?.head_name()?.and_then(|name| {
+        name.as_bstr()
+            .strip_prefix(b"refs/heads/")
+            .map(|b| b.to_str_lossy().into_owned())
+    }))
+}
+
+/// Get the ref (without `refs/`) directly referencing the `HEAD` commit, like
+/// `git describe --all --exact-match`: tags are preferred over the current branch, which
+/// is preferred over any other ref.
+pub(super) fn describe_exact_ref(worktree: &Path) -> Result<String> {
+    let repo = open(worktree)?;
+    let head = repo.head_id()?.detach();
+
+    // (priority, name) with 2 = annotated tag, 1 = lightweight tag, 0 = other refs
+    let mut best: Option<(u8, String)> = None;
+    for mut reference in repo.references()?.all()?.filter_map(Result::ok) {
+        let full_name = reference.name().as_bstr().to_owned();
+        let name = match full_name.strip_prefix(b"refs/") {
+            Some(name) => name.to_str_lossy().into_owned(),
+            None => continue,
+        };
+
+        let priority = if name.starts_with("tags/") {
+            let is_annotated = reference
+                .target()
+                .try_id()
+                .and_then(|id| repo.find_header(id).ok())
+                .map(|header| header.kind() == Kind::Tag)
+                .unwrap_or(false);
+            if is_annotated {
+                2
+            } else {
+                1
+            }
+        } else {
+            0
+        };
+
+        let is_better = best.as_ref().map(|(p, _)| priority > *p).unwrap_or(true);
+        if is_better && reference.peel_to_id().map(|id| id == head).unwrap_or(false) {
+            best = Some((priority, name));
+        }
+    }
+
+    match best {
+        Some((priority, name)) if priority > 0 => Ok(name),
+        // The current branch is preferred over any other ref.
+        best => match branch_name(worktree)? {
+            Some(branch) => Ok(format!("heads/{branch}")),
+            None => best
+                .map(|(_, name)| name)
+                .ok_or_else(|| anyhow!("No ref exactly matches HEAD ({head})")),
+        },
+    }
+}
+
+/// Clone `url` into `worktree`, check out `force_ref` (or the default branch) and
+/// recursively clone all submodules (selected by `filter`).
+///
+/// `config` are additional git config values (`<key>=<value>`) used while cloning, e.g.
+/// `url.<base>.insteadOf` rules for mirrors.
+pub(super) fn clone(
+    url: &str,
+    worktree: &Path,
+    force_ref: Option<&Ref>,
+    depth: Option<NonZeroU64>,
+    filter: Option<&SubmoduleFilter>,
+    config: &[String],
+) -> Result<()> {
+    clone_at(url, worktree, force_ref, depth, filter, "", config)
+}
+
+/// Like [`clone`] for the submodule at `path` (relative to the top-level work-tree).
+fn clone_at(
+    url: &str,
+    worktree: &Path,
+    force_ref: Option<&Ref>,
+    depth: Option<NonZeroU64>,
+    filter: Option<&SubmoduleFilter>,
+    path: &str,
+    config: &[String],
+) -> Result<()> {
+    let interrupt = AtomicBool::new(false);
+
+    let mut prepare = gix::prepare_clone(url, worktree)
+        .with_context(|| format!("Could not clone '{url}'"))?
+        .with_in_memory_config_overrides(config.iter().map(String::as_str));
+    if let Some(Ref::Branch(name) | Ref::Tag(name)) = force_ref {
+        prepare = prepare.with_ref_name(Some(name.as_str()))?;
+        if let Some(depth) = depth {
+            let depth = NonZeroU32::new(depth.get().min(u32::MAX as u64) as u32)
+                .expect("depth is non-zero");
+            prepare = prepare.with_shallow(gix::remote::fetch::Shallow::DepthAtRemote(depth));
+        }
+    }
+
+    let (mut checkout, _) = prepare
+        .fetch_then_checkout(Discard, &interrupt)
+        .with_context(|| format!("Could not clone '{url}'"))?;
+
+    if let Some(Ref::Commit(commit)) = force_ref {
+        let repo = checkout.repo();
+        let id = repo
+            .rev_parse_single(commit.as_str())
+            .with_context(|| format!("Commit '{commit}' not found in '{url}'"))?
+            .detach();
+        repo.edit_reference(RefEdit {
+            change: Change::Update {
+                log: LogChange {
+                    mode: RefLog::AndReference,
+                    force_create_reflog: false,
+                    message: format!("checkout: moving to {commit}").into(),
+                },
+                expected: PreviousValue::Any,
+                new: Target::Object(id),
+            },
+            name: "HEAD".try_into()?,
+            deref: false,
+        })?;
+    }
+
+    let (repo, _) = checkout
+        .main_worktree(Discard, &interrupt)
+        .with_context(|| format!("Could not check out '{}'", worktree.display()))?;
+
+    clone_submodules(&repo, url, filter, path, config)
+}
+
+/// Clone all submodules of `repo` (which was cloned from `url` into `path`) selected by
+/// `filter` like `git clone --recursive` and check out the commits recorded in its index.
+fn clone_submodules(
+    repo: &gix::Repository,
+    url: &str,
+    filter: Option<&SubmoduleFilter>,
+    path: &str,
+    config: &[String],
+) -> Result<()> {
+    let submodules = match repo.submodules()? {
+        Some(submodules) => submodules,
+        None => return Ok(()),
+    };
+
+    let mut registered = String::new();
+    for submodule in submodules {
+        if matches!(
+            submodule.update()?,
+            Some(gix::submodule::config::Update::None)
+        ) {
+            continue;
+        }
+        let submodule_path = join_path(path, &submodule.path()?.to_str_lossy());
+        if !filter.map_or(true, |f| f.matches(&submodule_path)) {
+            continue;
+        }
+        let commit = match submodule.index_id()? {
+            Some(commit) => commit,
+            None => continue,
+        };
+
+        let name = submodule.name().to_str_lossy().into_owned();
+        let submodule_url = resolve_submodule_url(url, &submodule.url()?.to_bstring().to_string());
+
+        // The submodule is checked out with an embedded git dir.
+        clone_at(
+            &submodule_url,
+            &submodule.work_dir()?,
+            Some(&Ref::Commit(commit.to_string())),
+            None,
+            filter,
+            &submodule_path,
+            config,
+        )
+        .with_context(|| format!("Could not clone submodule '{name}'"))?;
+
+        registered +=
+            &format!("[submodule \"{name}\"]\n\tactive = true\n\turl = {submodule_url}\n");
+    }
+
+    // Register the submodules like `git submodule init` so that the git CLI treats them
+    // as initialized.
+    if !registered.is_empty() {
+        fs::OpenOptions::new()
+            .append(true)
+            .open(repo.git_dir().join("config"))?
+            .write_all(registered.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// Resolve the submodule `url` relative to the `url` of its superproject, if it is
+/// relative (i.e. starts with `./` or `../`).
+fn resolve_submodule_url(superproject_url: &str, url: &str) -> String {
+    if !url.starts_with("./") && !url.starts_with("../") {
+        return url.to_owned();
+    }
+
+    let mut base = superproject_url.trim_end_matches('/').to_owned();
+    let mut rest = url;
+    loop {
+        if let Some(r) = rest.strip_prefix("./") {
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("../") {
+            base.truncate(base.rfind(['/', ':']).unwrap_or(0));
+            rest = r;
+        } else {
+            break;
+        }
+    }
+
+    format!("{base}/{rest}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd;
+    use crate::git::{Backend, CloneOptions, Repository, GIT};
+
+    #[test]
+    fn submodule_url() {
+        let base = "https://github.com/espressif/esp-idf.git";
+        assert_eq!(
+            resolve_submodule_url(base, "../../espressif/esptool.git"),
+            "https://github.com/espressif/esptool.git"
+        );
+        assert_eq!(
+            resolve_submodule_url(base, "./sub.git"),
+            "https://github.com/espressif/esp-idf.git/sub.git"
+        );
+        assert_eq!(
+            resolve_submodule_url("git@github.com:espressif/esp-idf.git", "../cmock.git"),
+            "git@github.com:espressif/cmock.git"
+        );
+        assert_eq!(
+            resolve_submodule_url(base, "https://example.com/x.git"),
+            "https://example.com/x.git"
+        );
+    }
+
+    #[test]
+    fn clone_and_describe() {
+        let dir = tempfile::tempdir().unwrap();
+        let origin = dir.path().join("origin");
+        fs::create_dir(&origin).unwrap();
+
+        let git = |dir: &Path, args: &[&str]| {
+            cmd!(GIT, "-c", "user.name=test", "-c", "user.email=test@example.com", "-c", "protocol.file.allow=always"; args=(args), current_dir=(dir))
+                .run()
+                .unwrap()
+        };
+        let lib = dir.path().join("lib");
+        fs::create_dir(&lib).unwrap();
+        git(&lib, &["init", "-q", "-b", "main"]);
+        fs::write(lib.join("lib.txt"), "lib").unwrap();
+        git(&lib, &["add", "."]);
+        git(&lib, &["commit", "-q", "-m", "lib"]);
+
+        git(&origin, &["init", "-q", "-b", "main"]);
+        fs::write(origin.join("file.txt"), "1").unwrap();
+        git(&origin, &["add", "."]);
+        git(&origin, &["commit", "-q", "-m", "first"]);
+        git(&origin, &["tag", "-a", "v1.0", "-m", "v1.0"]);
+        fs::write(origin.join("file.txt"), "2").unwrap();
+        git(&origin, &["submodule", "add", "-q", "../lib", "lib"]);
+        git(&origin, &["commit", "-q", "-am", "second"]);
+
+        let url = crate::utils::path_to_file_url(&origin);
+        let mut repo = Repository::new(dir.path().join("clone")).with_backend(Backend::Native);
+        assert!(repo
+            .clone_ext(&url, CloneOptions::new())
+            .unwrap()
+            .is_modified());
+
+        assert_eq!(
+            repo.get_remotes().unwrap(),
+            [("origin".to_owned(), url.clone())]
+        );
+        assert_eq!(repo.get_default_branch().unwrap().as_deref(), Some("main"));
+        assert_eq!(repo.get_branch_name().unwrap().as_deref(), Some("main"));
+        assert!(repo.is_clean().unwrap());
+        assert!(repo.is_ref(&Ref::Branch("main".into())));
+        assert_eq!(
+            fs::read_to_string(repo.worktree().join("lib").join("lib.txt")).unwrap(),
+            "lib"
+        );
+        let status = cmd!(GIT, "submodule", "status"; current_dir=(repo.worktree()))
+            .stdout()
+            .unwrap();
+        assert!(status.starts_with(' '), "{status}");
+
+        fs::write(repo.worktree().join("file.txt"), "changed").unwrap();
+        assert!(!repo.is_clean().unwrap());
+
+        let mut repo = Repository::new(dir.path().join("tag")).with_backend(Backend::Native);
+        repo.clone_ext(
+            &url,
+            CloneOptions::new()
+                .force_ref(Ref::Tag("v1.0".into()))
+                .depth(1),
+        )
+        .unwrap();
+        assert!(repo.is_shallow());
+        assert!(matches!(repo.get_ref().unwrap(), Ref::Tag(t) if t == "v1.0"));
+        assert_eq!(
+            fs::read_to_string(repo.worktree().join("file.txt")).unwrap(),
+            "1"
+        );
+
+        let first = cmd!(GIT, "rev-parse", "v1.0^{commit}"; current_dir=(&origin))
+            .stdout()
+            .unwrap();
+        let mut repo = Repository::new(dir.path().join("commit")).with_backend(Backend::Native);
+        repo.clone_ext(
+            &url,
+            CloneOptions::new().force_ref(Ref::Commit(first.clone())),
+        )
+        .unwrap();
+        assert!(repo.is_ref(&Ref::Commit(first)));
+        assert_eq!(
+            fs::read_to_string(repo.worktree().join("file.txt")).unwrap(),
+            "1"
+        );
+
+        let mut repo = Repository::new(dir.path().join("filter")).with_backend(Backend::Native);
+        repo.clone_ext(
+            &url,
+            CloneOptions::new().submodules(SubmoduleFilter::new().exclude("lib")),
+        )
+        .unwrap();
+        assert!(!repo.worktree().join("lib").join("lib.txt").exists());
+    }
+}
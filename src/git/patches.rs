@@ -0,0 +1,494 @@
+//! Patch series that are applied in order and recorded in the git dir.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use super::{Repository, GIT, LC_ALL};
+use crate::cmd;
+use crate::utils::PathExt;
+
+/// The directory in the git dir in which applied patch series are recorded.
+const PATCHES_DIR: &str = "embuild-patches";
+/// The file of a recorded series with a `<hash> <name>` line per applied patch.
+const SERIES_FILENAME: &str = "series";
+
+/// An ordered list of patch files that are applied to a [`Repository`] with
+/// [`Repository::apply_series`].
+///
+/// The applied patches are recorded (with their hashes) under the name of the series in
+/// the git dir of the repository, so that applying a changed series only reverts the
+/// patches that changed or were removed and applies the new ones.
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct PatchSeries {
+    name: String,
+    patches: Vec<PathBuf>,
+    three_way: bool,
+}
+
+impl PatchSeries {
+    /// Create an empty patch series called `name`.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            patches: Vec::new(),
+            three_way: true,
+        }
+    }
+
+    /// Append the patch file `patch` to this series.
+    pub fn patch(mut self, patch: impl AsRef<Path>) -> Self {
+        self.patches.push(patch.as_ref().to_owned());
+        self
+    }
+
+    /// Append all `patches` to this series.
+    pub fn patches(mut self, patches: impl IntoIterator<Item = impl AsRef<Path>>) -> Self {
+        self.patches
+            .extend(patches.into_iter().map(|p| p.as_ref().to_owned()));
+        self
+    }
+
+    /// Whether a patch that does not apply cleanly is applied with a 3-way merge (`git
+    /// apply --3way`), which is the default.
+    pub fn three_way(mut self, three_way: bool) -> Self {
+        self.three_way = three_way;
+        self
+    }
+
+    /// The name of this series.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// A patch recorded as applied to a [`Repository`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppliedPatch {
+    /// The file name of the patch.
+    pub name: String,
+    /// The git object hash of the contents of the patch.
+    pub hash: String,
+}
+
+/// What [`Repository::apply_series`] did with a patch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchStatus {
+    /// The patch was already applied.
+    AlreadyApplied,
+    /// The patch was applied cleanly.
+    Applied,
+    /// The patch was applied with a 3-way merge.
+    Merged,
+    /// The patch could not be applied and the work-tree was restored.
+    Conflict {
+        /// The files with conflicts, if a 3-way merge was attempted.
+        files: Vec<String>,
+        /// The error of `git apply`.
+        error: String,
+    },
+    /// The patch was not applied because a previous patch could not be applied.
+    Skipped,
+}
+
+/// The result of [`Repository::apply_series`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SeriesReport {
+    /// The previously applied patches that were reverted because they were changed or
+    /// removed from the series.
+    pub reverted: Vec<AppliedPatch>,
+    /// Every patch of the series and what was done with it.
+    pub patches: Vec<(PathBuf, PatchStatus)>,
+}
+
+impl SeriesReport {
+    /// Whether all patches of the series are applied.
+    pub fn is_success(&self) -> bool {
+        self.patches.iter().all(|(_, status)| {
+            matches!(
+                status,
+                PatchStatus::AlreadyApplied | PatchStatus::Applied | PatchStatus::Merged
+            )
+        })
+    }
+
+    /// Return an error describing the conflicts if not all patches are applied.
+    pub fn into_result(self) -> Result<Self> {
+        if self.is_success() {
+            Ok(self)
+        } else {
+            Err(anyhow!("{self}"))
+        }
+    }
+}
+
+impl fmt::Display for SeriesReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for patch in &self.reverted {
+            writeln!(f, "reverted {}", patch.name)?;
+        }
+        for (patch, status) in &self.patches {
+            let patch = patch.display();
+            match status {
+                PatchStatus::AlreadyApplied => writeln!(f, "{patch}: already applied")?,
+                PatchStatus::Applied => writeln!(f, "{patch}: applied")?,
+                PatchStatus::Merged => writeln!(f, "{patch}: applied with 3-way merge")?,
+                PatchStatus::Conflict { files, error } if files.is_empty() => {
+                    writeln!(f, "{patch}: does not apply: {error}")?
+                }
+                PatchStatus::Conflict { files, .. } => {
+                    writeln!(f, "{patch}: conflicts in {}", files.join(", "))?
+                }
+                PatchStatus::Skipped => writeln!(f, "{patch}: skipped")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Repository {
+    /// Apply the patches of `series` in order and record them in the git dir.
+    ///
+    /// Patches that were recorded as applied by a previous call with a series of the
+    /// same name are skipped if they are unchanged and at the same position; all
+    /// recorded patches after the first difference are reverted first. A patch that
+    /// does not apply cleanly is applied with a 3-way merge (see
+    /// [`PatchSeries::three_way`]); if that fails too, the changes of that patch are
+    /// undone and it and all following patches are reported as not applied.
+    ///
+    /// The patches are applied to both the index and the work-tree (`git apply
+    /// --index`).
+    pub fn apply_series(&self, series: &PatchSeries) -> Result<SeriesReport> {
+        let dir = self.series_dir(&series.name);
+        let mut applied = self.applied_patches(&series.name)?;
+
+        if !applied.is_empty() && !self.check_applied(&dir, applied.len()) {
+            if self.check_apply(&dir, applied.len()) {
+                log::warn!(
+                    "The patches of series '{}' recorded as applied were reverted, applying them again",
+                    series.name
+                );
+                self.record(&dir, &[])?;
+                applied.clear();
+            } else {
+                bail!(
+                    "The patches of series '{}' are neither applied nor revertible in '{}', \
+                     was the work-tree changed?",
+                    series.name,
+                    self.worktree.display()
+                );
+            }
+        }
+
+        let patches = series
+            .patches
+            .iter()
+            .map(|p| p.abspath())
+            .collect::<Result<Vec<_>, _>>()?;
+        let hashes = patches
+            .iter()
+            .map(|p| self.hash_object(p))
+            .collect::<Result<Vec<_>>>()?;
+        let common = applied
+            .iter()
+            .zip(&hashes)
+            .take_while(|(a, hash)| a.hash == **hash)
+            .count();
+
+        let mut report = SeriesReport::default();
+        for index in (common..applied.len()).rev() {
+            let patch = copy_path(&dir, index);
+            cmd!(GIT, @self.git_args(), "apply", "--index", "-R", &patch; current_dir=(&self.worktree), envs=(LC_ALL))
+                .run()
+                .with_context(|| format!("could not revert patch '{}'", applied[index].name))?;
+            let reverted = applied.pop().unwrap();
+            self.record(&dir, &applied)?;
+            report.reverted.push(reverted);
+        }
+
+        let mut failed = false;
+        for (index, (patch, hash)) in patches.iter().zip(hashes).enumerate() {
+            let status = if index < common {
+                PatchStatus::AlreadyApplied
+            } else if failed {
+                PatchStatus::Skipped
+            } else {
+                // The index contains all previously applied patches.
+                let tree = cmd!(GIT, @self.git_args(), "write-tree"; envs=(LC_ALL))
+                    .stdout()
+                    .context("the index has conflicts")?;
+
+                let status = self.apply_patch(patch, &tree, series.three_way)?;
+                if let PatchStatus::Conflict { .. } = status {
+                    failed = true;
+                } else {
+                    // Record the changes that were actually made, which can be reverted
+                    // even if the patch was merged.
+                    let diff = cmd!(GIT, @self.git_args(), "diff", "--cached", "--binary", "--full-index", &tree; envs=(LC_ALL))
+                        .stdout()?;
+                    fs::create_dir_all(&dir)?;
+                    fs::write(copy_path(&dir, index), diff + "\n")?;
+                    applied.push(AppliedPatch {
+                        name: patch_name(patch),
+                        hash,
+                    });
+                    self.record(&dir, &applied)?;
+                }
+                status
+            };
+            report.patches.push((patch.clone(), status));
+        }
+
+        Ok(report)
+    }
+
+    /// Revert all patches of the series `name` and return them.
+    pub fn unapply_series(&self, name: &str) -> Result<Vec<AppliedPatch>> {
+        let report = self.apply_series(&PatchSeries::new(name))?;
+        Ok(report.reverted)
+    }
+
+    /// The patches recorded as applied for the series `name`, in order.
+    pub fn applied_patches(&self, name: &str) -> Result<Vec<AppliedPatch>> {
+        let series = self.series_dir(name).join(SERIES_FILENAME);
+        if !series.exists() {
+            return Ok(Vec::new());
+        }
+
+        fs::read_to_string(&series)
+            .with_context(|| format!("could not read '{}'", series.display()))?
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| {
+                let (hash, name) = l
+                    .split_once(' ')
+                    .ok_or_else(|| anyhow!("invalid line '{l}' in '{}'", series.display()))?;
+                Ok(AppliedPatch {
+                    name: name.to_owned(),
+                    hash: hash.to_owned(),
+                })
+            })
+            .collect()
+    }
+
+    fn series_dir(&self, name: &str) -> PathBuf {
+        let name: String = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        self.git_dir.join(PATCHES_DIR).join(name)
+    }
+
+    fn record(&self, dir: &Path, applied: &[AppliedPatch]) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        let mut series = String::new();
+        for patch in applied {
+            series += &format!("{} {}\n", patch.hash, patch.name);
+        }
+        fs::write(dir.join(SERIES_FILENAME), series)?;
+
+        // Remove the copies of patches that are no longer applied.
+        let copies: HashSet<_> = (0..applied.len()).map(|i| copy_path(dir, i)).collect();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(false, |e| e == "patch") && !copies.contains(&path) {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the first `count` recorded patches of `dir` can be reverted.
+    fn check_applied(&self, dir: &Path, count: usize) -> bool {
+        let patches = (0..count).rev().map(|i| copy_path(dir, i));
+        cmd!(GIT, @self.git_args(), "apply", "--index", "--check", "-R"; args=(patches), current_dir=(&self.worktree), envs=(LC_ALL))
+            .stdout()
+            .is_ok()
+    }
+
+    /// Whether the first `count` recorded patches of `dir` can be applied.
+    fn check_apply(&self, dir: &Path, count: usize) -> bool {
+        let patches = (0..count).map(|i| copy_path(dir, i));
+        cmd!(GIT, @self.git_args(), "apply", "--index", "--check"; args=(patches), current_dir=(&self.worktree), envs=(LC_ALL))
+            .stdout()
+            .is_ok()
+    }
+
+    /// Apply `patch` to the index (with the tree `tree`) and work-tree.
+    fn apply_patch(&self, patch: &Path, tree: &str, three_way: bool) -> Result<PatchStatus> {
+        let error = match cmd!(GIT, @self.git_args(), "apply", "--index", patch; current_dir=(&self.worktree), envs=(LC_ALL)).stdout() {
+            Ok(_) => return Ok(PatchStatus::Applied),
+            Err(e) => format!("{e:#}"),
+        };
+        if !three_way {
+            return Ok(PatchStatus::Conflict {
+                files: Vec::new(),
+                error,
+            });
+        }
+
+        let changed_before = self.changed_files(tree)?;
+        match cmd!(GIT, @self.git_args(), "apply", "--3way", patch; current_dir=(&self.worktree), envs=(LC_ALL)).stdout() {
+            Ok(_) => Ok(PatchStatus::Merged),
+            Err(e) => {
+                let files = cmd!(GIT, @self.git_args(), "diff", "--name-only", "--diff-filter=U"; current_dir=(&self.worktree), envs=(LC_ALL))
+                    .stdout()?
+                    .lines()
+                    .map(str::to_owned)
+                    .collect();
+
+                // Undo the partial merge of this patch.
+                cmd!(GIT, @self.git_args(), "read-tree", tree; envs=(LC_ALL)).run()?;
+                for file in self.changed_files(tree)?.difference(&changed_before) {
+                    let path = self.worktree.join(file);
+                    let in_index = cmd!(GIT, @self.git_args(), "ls-files", "--error-unmatch", "--", file; current_dir=(&self.worktree), envs=(LC_ALL))
+                        .stdout()
+                        .is_ok();
+                    if in_index {
+                        cmd!(GIT, @self.git_args(), "checkout", "--", file; current_dir=(&self.worktree), envs=(LC_ALL))
+                            .run()?;
+                    } else if path.exists() {
+                        fs::remove_file(path)?;
+                    }
+                }
+
+                Ok(PatchStatus::Conflict {
+                    files,
+                    error: format!("{e:#}"),
+                })
+            }
+        }
+    }
+
+    /// The files of the work-tree that differ from `tree`, including untracked files.
+    fn changed_files(&self, tree: &str) -> Result<HashSet<String>> {
+        let changed = cmd!(GIT, @self.git_args(), "diff", "--name-only", tree; current_dir=(&self.worktree), envs=(LC_ALL))
+            .stdout()?;
+        let untracked = cmd!(GIT, @self.git_args(), "ls-files", "--others", "--exclude-standard"; current_dir=(&self.worktree), envs=(LC_ALL))
+            .stdout()?;
+        Ok(changed
+            .lines()
+            .chain(untracked.lines())
+            .map(str::to_owned)
+            .collect())
+    }
+
+    fn hash_object(&self, file: &Path) -> Result<String> {
+        cmd!(GIT, @self.git_args(), "hash-object", "--", file; envs=(LC_ALL))
+            .stdout()
+            .with_context(|| format!("could not read patch '{}'", file.display()))
+    }
+}
+
+/// The path of the copy of the `index`th applied patch in the series directory `dir`.
+fn copy_path(dir: &Path, index: usize) -> PathBuf {
+    dir.join(format!("{index:04}.patch"))
+}
+
+fn patch_name(patch: &Path) -> String {
+    patch
+        .file_name()
+        .unwrap_or(patch.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::git;
+    use super::*;
+
+    /// Create a patch that replaces `line` of `file.txt` with `new` in `repo`.
+    fn make_patch(repo: &Path, line: usize, new: &str, name: &str) -> PathBuf {
+        let file = repo.join("file.txt");
+        let original = fs::read_to_string(&file).unwrap();
+        let mut lines: Vec<_> = original.lines().map(str::to_owned).collect();
+        lines[line] = new.to_owned();
+        fs::write(&file, lines.join("\n") + "\n").unwrap();
+        let patch = repo.parent().unwrap().join(name);
+        fs::write(&patch, git(repo, &["diff", "--full-index"]) + "\n").unwrap();
+        fs::write(&file, original).unwrap();
+        patch
+    }
+
+    fn statuses(report: &SeriesReport) -> Vec<PatchStatus> {
+        report.patches.iter().map(|(_, s)| s.clone()).collect()
+    }
+
+    #[test]
+    fn series() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("repo");
+        fs::create_dir(&path).unwrap();
+        git(&path, &["init", "-q", "-b", "main"]);
+        let lines: Vec<_> = (0..30).map(|i| format!("line {i}")).collect();
+        fs::write(path.join("file.txt"), lines.join("\n") + "\n").unwrap();
+        git(&path, &["add", "."]);
+        git(&path, &["commit", "-q", "-m", "base"]);
+
+        // Created against an older version, so that it only applies with a 3-way merge.
+        let p5 = make_patch(&path, 28, "five", "5.patch");
+        let mut changed = lines.clone();
+        changed[25] = "changed".into();
+        fs::write(path.join("file.txt"), changed.join("\n") + "\n").unwrap();
+        git(&path, &["commit", "-q", "-am", "change"]);
+
+        let p1 = make_patch(&path, 1, "one", "1.patch");
+        let p2 = make_patch(&path, 20, "two", "2.patch");
+        let p3 = make_patch(&path, 12, "three", "3.patch");
+        let p4 = make_patch(&path, 1, "four", "4.patch");
+        let repo = Repository::new(&path);
+        let read = || fs::read_to_string(path.join("file.txt")).unwrap();
+
+        let series = PatchSeries::new("test").patches([&p1, &p2]);
+        let report = repo.apply_series(&series).unwrap();
+        assert_eq!(
+            statuses(&report),
+            [PatchStatus::Applied, PatchStatus::Applied]
+        );
+        assert!(read().contains("one") && read().contains("two"));
+        assert_eq!(repo.applied_patches("test").unwrap().len(), 2);
+
+        let report = repo.apply_series(&series).unwrap();
+        assert_eq!(
+            statuses(&report),
+            [PatchStatus::AlreadyApplied, PatchStatus::AlreadyApplied]
+        );
+
+        let series = PatchSeries::new("test").patches([&p1, &p3, &p5]);
+        let report = repo.apply_series(&series).unwrap();
+        assert_eq!(report.reverted.len(), 1);
+        assert_eq!(report.reverted[0].name, "2.patch");
+        assert_eq!(
+            statuses(&report),
+            [
+                PatchStatus::AlreadyApplied,
+                PatchStatus::Applied,
+                PatchStatus::Merged
+            ]
+        );
+        assert!(!read().contains("two") && read().contains("three") && read().contains("five"));
+
+        let before = read();
+        let series = PatchSeries::new("test").patches([&p1, &p3, &p5, &p4, &p2]);
+        let report = repo.apply_series(&series).unwrap();
+        assert!(!report.is_success());
+        match &report.patches[3].1 {
+            PatchStatus::Conflict { files, .. } => assert_eq!(files, &["file.txt"]),
+            other => panic!("unexpected {other:?}"),
+        }
+        assert_eq!(report.patches[4].1, PatchStatus::Skipped);
+        assert_eq!(read(), before);
+        assert_eq!(repo.applied_patches("test").unwrap().len(), 3);
+
+        let reverted = repo.unapply_series("test").unwrap();
+        assert_eq!(reverted.len(), 3);
+        assert_eq!(read(), changed.join("\n") + "\n");
+        assert!(repo.applied_patches("test").unwrap().is_empty());
+        assert!(repo.is_clean().unwrap());
+    }
+}
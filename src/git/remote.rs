@@ -0,0 +1,155 @@
+//! Inspection of remote repositories without cloning them.
+
+use anyhow::{Context, Result};
+
+use super::{Ref, Repository, GIT, LC_ALL};
+use crate::cmd;
+
+/// A branch or tag of a remote repository listed by [`Repository::ls_remote`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteRef {
+    /// The full name of the ref, e.g. `refs/heads/master` or `refs/tags/v5.1`.
+    pub name: String,
+    /// The commit the ref points to (the tagged commit for annotated tags).
+    pub commit: String,
+}
+
+impl RemoteRef {
+    /// The [`Ref`] of this branch or tag, or [`None`] if it is neither.
+    pub fn to_ref(&self) -> Option<Ref> {
+        if let Some(branch) = self.name.strip_prefix("refs/heads/") {
+            Some(Ref::Branch(branch.to_owned()))
+        } else {
+            self.name
+                .strip_prefix("refs/tags/")
+                .map(|tag| Ref::Tag(tag.to_owned()))
+        }
+    }
+}
+
+/// A [`Ref`] resolved by [`Repository::resolve_remote_ref`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedRef {
+    /// The ref, which may be a branch even if a tag was requested, or vice versa.
+    pub git_ref: Ref,
+    /// The commit the ref points to.
+    pub commit: String,
+}
+
+impl Repository {
+    /// List all branches and tags of the remote repository `url`.
+    ///
+    /// The remote is queried from a mirror if one is configured (see
+    /// [`Mirrors::from_env`](crate::utils::Mirrors::from_env)). This always runs the git
+    /// CLI (`git ls-remote`).
+    pub fn ls_remote(url: &str) -> Result<Vec<RemoteRef>> {
+        ls_remote(url, &[])
+    }
+
+    /// Ask the remote repository `url` whether `git_ref` is a branch or a tag and which
+    /// commit it points to.
+    ///
+    /// As [`Ref::parse`] guesses whether a name is a branch or a tag, the other kind is
+    /// used if the remote has no ref of the kind of `git_ref` with its name (e.g. for a
+    /// branch `v5.1-dev`, which is parsed as a tag). If the remote has both, the kind of
+    /// `git_ref` is used. A [`Ref::Commit`] is returned as is.
+    pub fn resolve_remote_ref(url: &str, git_ref: &Ref) -> Result<ResolvedRef> {
+        let name = match git_ref {
+            Ref::Commit(c) => {
+                return Ok(ResolvedRef {
+                    git_ref: git_ref.clone(),
+                    commit: c.clone(),
+                })
+            }
+            Ref::Branch(n) | Ref::Tag(n) => n,
+        };
+
+        let refs = ls_remote(
+            url,
+            &[format!("refs/heads/{name}"), format!("refs/tags/{name}")],
+        )?;
+        let find = |prefix: &str| {
+            refs.iter()
+                .find(|r| r.name.strip_prefix(prefix) == Some(name))
+        };
+        let (branch, tag) = (find("refs/heads/"), find("refs/tags/"));
+        let found = match git_ref {
+            Ref::Tag(_) => tag.or(branch),
+            _ => branch.or(tag),
+        };
+
+        let found = found.with_context(|| format!("'{url}' has no branch or tag '{name}'"))?;
+        Ok(ResolvedRef {
+            git_ref: found.to_ref().unwrap(),
+            commit: found.commit.clone(),
+        })
+    }
+}
+
+/// List the branches and tags of `url` matching `patterns` (all if empty).
+fn ls_remote(url: &str, patterns: &[String]) -> Result<Vec<RemoteRef>> {
+    let mirror_args = crate::utils::Mirrors::from_env()?.git_config_args();
+    let output = cmd!(GIT, @&mirror_args, "ls-remote", "--heads", "--tags", url; args=(patterns), envs=(LC_ALL))
+        .stdout()
+        .with_context(|| format!("could not list the refs of '{url}'"))?;
+
+    let mut refs: Vec<RemoteRef> = Vec::new();
+    for (commit, name) in output.lines().filter_map(|l| l.split_once('\t')) {
+        // Annotated tags are listed twice: as the tag object and peeled as `<tag>^{}`.
+        if let Some(tag) = name.strip_suffix("^{}") {
+            if let Some(r) = refs.iter_mut().find(|r| r.name == tag) {
+                r.commit = commit.to_owned();
+            }
+            continue;
+        }
+        refs.push(RemoteRef {
+            name: name.to_owned(),
+            commit: commit.to_owned(),
+        });
+    }
+    Ok(refs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{create_origin, git};
+    use super::*;
+
+    #[test]
+    fn resolve() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, v2) = create_origin(dir.path());
+        let work = dir.path().join("work");
+        git(
+            &work,
+            &["tag", "-a", "-m", "annotated", "stable-3", "HEAD~1"],
+        );
+        git(&work, &["branch", "v5.1-dev", "HEAD~1"]);
+        git(
+            &work,
+            &["push", "-q", "../origin.git", "stable-3", "v5.1-dev"],
+        );
+
+        let refs = Repository::ls_remote(&url).unwrap();
+        assert!(refs.contains(&RemoteRef {
+            name: "refs/tags/stable-3".into(),
+            commit: v2.clone(),
+        }));
+        assert!(refs.iter().any(|r| r.name == "refs/heads/main"));
+
+        let resolve = |r| Repository::resolve_remote_ref(&url, &Ref::parse(r)).unwrap();
+        // Parsed as a tag, but is a branch.
+        assert_eq!(
+            resolve("v5.1-dev"),
+            ResolvedRef {
+                git_ref: Ref::Branch("v5.1-dev".into()),
+                commit: v2.clone(),
+            }
+        );
+        // Parsed as a branch, but is a tag.
+        assert_eq!(resolve("stable-3").git_ref, Ref::Tag("stable-3".into()));
+        assert_eq!(resolve("v2").commit, v2);
+        assert_eq!(resolve("main").git_ref, Ref::Branch("main".into()));
+        assert!(Repository::resolve_remote_ref(&url, &Ref::parse("missing")).is_err());
+    }
+}
@@ -0,0 +1,411 @@
+//! A store of bare mirrors whose objects are shared by many clones.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+
+use super::submodule::{join_path, read_gitmodules};
+use super::{Ref, Repository, SubmoduleFilter, GIT, LC_ALL};
+use crate::cmd;
+use crate::cmd::TeeSink;
+use crate::fs::{FileLock, LockOptions};
+use crate::utils::PathExt;
+
+const LOCK_FILENAME: &str = ".embuild-store.lock";
+const MIRRORS_DIR: &str = "mirrors";
+const CLONES_DIR: &str = "clones";
+
+/// A directory of bare mirrors of remote repositories, whose objects are shared (through
+/// git alternates) by all repositories cloned with it instead of being copied into
+/// each of them.
+///
+/// Set it with [`CloneOptions::object_store`](super::CloneOptions::object_store) to
+/// keep a single mirror per remote url (and per submodule url) when cloning many refs of
+/// the same repository. Clones made with the store are recorded together with their
+/// last use, so that [`gc`](Self::gc) can remove clones that were not used for a while
+/// and the mirrors no longer needed by any clone.
+///
+/// Note that removing a mirror (or the whole store) breaks all clones made with it.
+#[derive(Debug, Clone)]
+pub struct ObjectStore {
+    dir: PathBuf,
+}
+
+impl ObjectStore {
+    /// Create an object store in `dir`, which is created when it is first used.
+    pub fn new(dir: impl AsRef<Path>) -> ObjectStore {
+        ObjectStore {
+            dir: dir.as_ref().to_owned(),
+        }
+    }
+
+    /// The directory of this store.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The directory of the bare mirror of `url`.
+    ///
+    /// Its name is the readable path of `url` followed by its
+    /// [hash](super::sdk::url_hash), as different urls may have the same sanitized path.
+    pub fn mirror_dir(&self, url: &str) -> PathBuf {
+        self.dir.join(MIRRORS_DIR).join(format!(
+            "{}-{}.git",
+            sanitize(url_path(url)),
+            super::sdk::url_hash(url)
+        ))
+    }
+
+    /// Create or update the mirror of `url` and return its directory.
+    ///
+    /// The mirror is fetched from a mirror if one is configured (see
+    /// [`Mirrors::from_env`](crate::utils::Mirrors::from_env)).
+    pub fn fetch(&self, url: &str) -> Result<PathBuf> {
+        let mirror_args = crate::utils::Mirrors::from_env()?.git_config_args();
+        let _lock = self.lock()?;
+        self.fetch_locked(url, &mirror_args)
+    }
+
+    /// Remove all clones that were not used for longer than `max_unused` and all mirrors
+    /// that are not used by any of the remaining clones, and return the removed clones.
+    ///
+    /// Clones that were already deleted are forgotten.
+    pub fn gc(&self, max_unused: Duration) -> Result<Vec<PathBuf>> {
+        let _lock = self.lock()?;
+
+        let mut used = HashSet::new();
+        let mut removed = Vec::new();
+        let clones_dir = self.dir.join(CLONES_DIR);
+        if clones_dir.exists() {
+            for entry in fs::read_dir(&clones_dir)? {
+                let record = entry?.path();
+                let contents = fs::read_to_string(&record)?;
+                let mut lines = contents.lines().map(PathBuf::from);
+                let worktree = lines.next().unwrap_or_default();
+                let unused_for = SystemTime::now()
+                    .duration_since(fs::metadata(&record)?.modified()?)
+                    .unwrap_or_default();
+
+                if worktree.as_os_str().is_empty() || !worktree.exists() {
+                    fs::remove_file(&record)?;
+                } else if unused_for > max_unused {
+                    log::info!(
+                        "Removing '{}' which was not used for {}s",
+                        worktree.display(),
+                        unused_for.as_secs()
+                    );
+                    remove_dir_all::remove_dir_all(&worktree)?;
+                    fs::remove_file(&record)?;
+                    removed.push(worktree);
+                } else {
+                    used.extend(lines.filter_map(|m| m.file_name().map(ToOwned::to_owned)));
+                }
+            }
+        }
+
+        let mirrors_dir = self.dir.join(MIRRORS_DIR);
+        if mirrors_dir.exists() {
+            for entry in fs::read_dir(&mirrors_dir)? {
+                let entry = entry?;
+                if !used.contains(&entry.file_name()) {
+                    let mirror = entry.path();
+                    log::info!("Removing unused mirror '{}'", mirror.display());
+                    remove_dir_all::remove_dir_all(&mirror)?;
+                }
+            }
+        }
+
+        Ok(removed)
+    }
+
+    fn lock(&self) -> Result<FileLock> {
+        Ok(LockOptions::new()
+            .message("Waiting for another build to finish using the git object store")
+            .lock(self.dir.join(LOCK_FILENAME))?)
+    }
+
+    fn fetch_locked(&self, url: &str, mirror_args: &[String]) -> Result<PathBuf> {
+        let mirror = self.mirror_dir(url);
+        if mirror.exists() {
+            // Never prune: objects that are no longer referenced by the remote may still
+            // be used by clones.
+            cmd!(GIT, @mirror_args, "--git-dir", &mirror, "fetch", "--quiet", "--no-prune", "origin"; envs=(LC_ALL), tee=(TeeSink::Parent))
+                .run()
+                .with_context(|| format!("could not update the mirror of '{url}'"))?;
+        } else {
+            // Clone into a temporary directory first, so that an interrupted clone does
+            // not leave a broken mirror behind.
+            let tmp = mirror.with_extension("tmp");
+            if tmp.exists() {
+                remove_dir_all::remove_dir_all(&tmp)?;
+            }
+            cmd!(GIT, @mirror_args, "clone", "--quiet", "--mirror", url, &tmp; envs=(LC_ALL), tee=(TeeSink::Parent))
+                .run()
+                .with_context(|| format!("could not mirror '{url}'"))?;
+            cmd!(GIT, "--git-dir", &tmp, "config", "gc.pruneExpire", "never"; envs=(LC_ALL))
+                .run()?;
+            fs::rename(&tmp, &mirror)?;
+        }
+        Ok(mirror)
+    }
+
+    /// Clone `url` into the worktree of `repo` sharing the objects of its mirror, check
+    /// out `force_ref` and clone all submodules with their mirrors.
+    pub(super) fn clone_repo(
+        &self,
+        repo: &Repository,
+        url: &str,
+        force_ref: Option<&Ref>,
+        filter: Option<&SubmoduleFilter>,
+        mirror_args: &[String],
+    ) -> Result<()> {
+        let _lock = self.lock()?;
+        let mirror = self.fetch_locked(url, mirror_args)?;
+
+        let branch = match force_ref {
+            Some(Ref::Branch(s) | Ref::Tag(s)) => Some(["--branch", s]),
+            None | Some(Ref::Commit(_)) => None,
+        };
+        let branch = branch.iter().flatten();
+        cmd!(GIT, "clone", "--quiet", "--shared", @branch, &mirror, repo.worktree(); envs=(LC_ALL))
+            .run()?;
+        cmd!(GIT, @repo.git_args(), "remote", "set-url", "origin", url; envs=(LC_ALL)).run()?;
+        if let Some(Ref::Commit(c)) = force_ref {
+            cmd!(GIT, @repo.git_args(), "checkout", "--quiet", c; envs=(LC_ALL)).run()?;
+        }
+
+        let mut mirrors = vec![mirror];
+        self.update_submodules_locked(repo.worktree(), "", filter, mirror_args, &mut mirrors)?;
+        self.register(repo.worktree(), &mirrors)
+    }
+
+    /// Update the mirror of `url` and initialize and update all submodules of `repo`
+    /// with their mirrors.
+    pub(super) fn update_repo(
+        &self,
+        repo: &Repository,
+        url: &str,
+        mirror_args: &[String],
+        filter: Option<&SubmoduleFilter>,
+        checkout: impl FnOnce() -> Result<()>,
+    ) -> Result<()> {
+        let _lock = self.lock()?;
+        let mut mirrors = vec![self.fetch_locked(url, mirror_args)?];
+        checkout()?;
+        self.update_submodules_locked(repo.worktree(), "", filter, mirror_args, &mut mirrors)?;
+        self.register(repo.worktree(), &mirrors)
+    }
+
+    fn update_submodules_locked(
+        &self,
+        worktree: &Path,
+        prefix: &str,
+        filter: Option<&SubmoduleFilter>,
+        mirror_args: &[String],
+        mirrors: &mut Vec<PathBuf>,
+    ) -> Result<()> {
+        let modules = read_gitmodules(worktree)?
+            .into_iter()
+            .filter(|m| m.update.as_deref() != Some("none"))
+            .filter(|m| filter.map_or(true, |f| f.matches(&join_path(prefix, &m.path))));
+        for module in modules {
+            cmd!(GIT, "submodule", "--quiet", "init", "--", &module.path; current_dir=(worktree), envs=(LC_ALL))
+                .run()?;
+            // The url was resolved by `git submodule init`.
+            let url = cmd!(GIT, "config", format!("submodule.{}.url", module.name); current_dir=(worktree), envs=(LC_ALL))
+                .stdout()?;
+            let mirror = self.fetch_locked(&url, mirror_args)?;
+            cmd!(GIT, @mirror_args, "submodule", "--quiet", "update", "--reference", &mirror, "--", &module.path; current_dir=(worktree), envs=(LC_ALL), tee=(TeeSink::Parent))
+                .run()?;
+            mirrors.push(mirror);
+
+            self.update_submodules_locked(
+                &worktree.join(&module.path),
+                &join_path(prefix, &module.path),
+                filter,
+                mirror_args,
+                mirrors,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Record that the clone `worktree` uses `mirrors`.
+    fn register(&self, worktree: &Path, mirrors: &[PathBuf]) -> Result<()> {
+        let worktree = worktree.abspath()?;
+        let record = self.record(&worktree);
+        fs::create_dir_all(record.parent().unwrap())?;
+
+        let mut contents = format!("{}\n", worktree.display());
+        for mirror in mirrors {
+            contents += &format!("{}\n", mirror.display());
+        }
+        fs::write(&record, contents)
+            .with_context(|| format!("could not write '{}'", record.display()))
+    }
+
+    /// Record that the clone `worktree` was used now, if it was cloned with this store.
+    pub(super) fn touch(&self, worktree: &Path) -> Result<()> {
+        let record = self.record(&worktree.abspath()?);
+        if record.exists() {
+            filetime::set_file_mtime(&record, filetime::FileTime::now())?;
+        }
+        Ok(())
+    }
+
+    /// Update the records of all clones in the directory `from` after it was moved to
+    /// `to`.
+    pub(super) fn relocate(&self, from: &Path, to: &Path) -> Result<()> {
+        let clones_dir = self.dir.join(CLONES_DIR);
+        if !clones_dir.exists() {
+            return Ok(());
+        }
+        let (from, to) = (from.abspath()?, to.abspath()?);
+
+        let _lock = self.lock()?;
+        for entry in fs::read_dir(&clones_dir)? {
+            let record = entry?.path();
+            let contents = fs::read_to_string(&record)?;
+            let mut lines = contents.lines();
+            let worktree = match lines.next().map(|w| Path::new(w).strip_prefix(&from)) {
+                Some(Ok(rest)) => to.join(rest),
+                _ => continue,
+            };
+            let mirrors: Vec<_> = lines.map(PathBuf::from).collect();
+            fs::remove_file(&record)?;
+            self.register(&worktree, &mirrors)?;
+        }
+        Ok(())
+    }
+
+    fn record(&self, worktree: &Path) -> PathBuf {
+        self.dir
+            .join(CLONES_DIR)
+            .join(sanitize(&worktree.to_string_lossy()))
+    }
+}
+
+/// Strip the scheme, user and `.git` suffix from `url`.
+fn url_path(url: &str) -> &str {
+    let url = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    let url = url.split_once('@').map(|(_, rest)| rest).unwrap_or(url);
+    let url = url.trim_end_matches('/');
+    url.strip_suffix(".git").unwrap_or(url)
+}
+
+/// Replace all characters that are not ASCII alphanumeric or `.`, `_` with a dash `-`.
+fn sanitize(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect::<String>()
+        .trim_matches(|c| c == '-' || c == '.')
+        .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{allow_file_protocol, create_origin};
+    use super::super::{Backend, CloneOptions, CloneOutcome};
+    use super::*;
+
+    #[test]
+    fn mirror_dir() {
+        let store = ObjectStore::new("store");
+        let url = "https://github.com/espressif/esp-idf.git";
+        let dir = store.mirror_dir(url);
+        assert_eq!(
+            dir,
+            Path::new(&format!(
+                "store/mirrors/github.com-espressif-esp-idf-{}.git",
+                super::super::sdk::url_hash(url)
+            ))
+        );
+        assert_eq!(
+            store.mirror_dir("https://github.com/espressif/esp-idf/"),
+            dir
+        );
+
+        // Urls with the same sanitized path don't share a mirror.
+        assert_ne!(
+            store.mirror_dir("https://host/a-b.git"),
+            store.mirror_dir("https://host/a/b.git")
+        );
+        assert_ne!(
+            store.mirror_dir("git@github.com:espressif/esp-idf.git"),
+            dir
+        );
+    }
+
+    #[test]
+    fn shared_clones() {
+        allow_file_protocol();
+        let dir = tempfile::tempdir().unwrap();
+        let (url, _) = create_origin(dir.path());
+        let store = ObjectStore::new(dir.path().join("store"));
+
+        let clone = |name: &str, tag: &str| {
+            let mut repo = Repository::new(dir.path().join(name)).with_backend(Backend::Cli);
+            let options = CloneOptions::new()
+                .force_ref(Ref::Tag(tag.into()))
+                .object_store(store.clone());
+            let outcome = repo.clone_ext(&url, options).unwrap();
+            assert!(repo.is_ref(&Ref::Tag(tag.into())));
+            (repo, outcome)
+        };
+        let alternates = |git_dir: PathBuf| git_dir.join("objects/info/alternates").exists();
+
+        let (v1, _) = clone("v1", "v1");
+        let (v2, _) = clone("v2", "v2");
+        assert!(alternates(v1.git_dir.clone()));
+        assert!(alternates(v2.git_dir.join("modules/lib")));
+        assert_eq!(
+            fs::read_to_string(v2.worktree().join("lib/lib.txt")).unwrap(),
+            "2"
+        );
+        assert_eq!(
+            v2.get_remotes().unwrap(),
+            [(String::from("origin"), url.clone())]
+        );
+        assert_eq!(
+            fs::read_dir(store.dir().join(MIRRORS_DIR)).unwrap().count(),
+            2
+        );
+
+        let (v1, outcome) = clone("v1", "v2");
+        assert_eq!(outcome, CloneOutcome::Updated);
+        assert!(alternates(v1.git_dir.join("modules/lib")));
+
+        // Nothing is removed while the clones are in use.
+        assert!(store.gc(Duration::from_secs(60)).unwrap().is_empty());
+
+        let old = filetime::FileTime::from_unix_time(0, 0);
+        filetime::set_file_mtime(store.record(v1.worktree()), old).unwrap();
+        filetime::set_file_mtime(store.record(v2.worktree()), old).unwrap();
+        assert_eq!(clone("v2", "v2").1, CloneOutcome::Unchanged);
+
+        let removed = store.gc(Duration::from_secs(60)).unwrap();
+        assert_eq!(removed, [v1.worktree()]);
+        assert!(!v1.worktree().exists());
+        assert_eq!(
+            fs::read_dir(store.dir().join(MIRRORS_DIR)).unwrap().count(),
+            2
+        );
+
+        // The mirrors are removed once no clone uses them anymore.
+        fs::remove_dir_all(v2.worktree()).unwrap();
+        assert!(store.gc(Duration::from_secs(60)).unwrap().is_empty());
+        assert_eq!(
+            fs::read_dir(store.dir().join(MIRRORS_DIR)).unwrap().count(),
+            0
+        );
+    }
+}
@@ -0,0 +1,426 @@
+//! Submodule inspection and selective checkout.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use super::{Repository, GIT, LC_ALL};
+use crate::cmd;
+use crate::cmd::TeeSink;
+
+/// The state of a [`Submodule`] in the work-tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubmoduleStatus {
+    /// The submodule is not initialized (i.e. not checked out).
+    Uninitialized,
+    /// The submodule has the commit recorded in its superproject checked out.
+    UpToDate,
+    /// The submodule has another commit than the recorded one checked out.
+    Modified,
+    /// The submodule has merge conflicts.
+    Conflict,
+}
+
+/// A (possibly nested) submodule of a [`Repository`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Submodule {
+    /// The name of the submodule in the `.gitmodules` of its superproject.
+    pub name: String,
+    /// The path of the submodule relative to the work-tree of the repository, separated
+    /// by `/`.
+    pub path: String,
+    /// The url of the submodule in `.gitmodules`, which may be relative to the url of
+    /// its superproject.
+    pub url: Option<String>,
+    /// The commit recorded for the submodule in its superproject.
+    pub commit: String,
+    /// The commit checked out in the submodule, if it is initialized.
+    pub checked_out: Option<String>,
+    pub status: SubmoduleStatus,
+}
+
+/// Selects submodules by their path relative to the top-level work-tree.
+///
+/// A pattern matches a submodule if it matches its path or one of its parent
+/// directories, where `*` matches any characters except `/`, `?` matches a single
+/// character and `**` matches any number of directories. E.g. the pattern
+/// `components/bt/controller/lib_*` matches the submodule
+/// `components/bt/controller/lib_esp32`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[must_use]
+pub struct SubmoduleFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl SubmoduleFilter {
+    /// Create a filter that selects all submodules.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only select submodules matching `pattern` (or any other include pattern).
+    ///
+    /// Submodules that contain a submodule matching `pattern` are also selected, so that
+    /// the nested submodule can be checked out.
+    pub fn include(mut self, pattern: impl Into<String>) -> Self {
+        self.include.push(pattern.into());
+        self
+    }
+
+    /// Never select submodules matching `pattern`.
+    pub fn exclude(mut self, pattern: impl Into<String>) -> Self {
+        self.exclude.push(pattern.into());
+        self
+    }
+
+    /// Whether the submodule at `path` (relative to the top-level work-tree) is
+    /// selected.
+    pub fn matches(&self, path: &str) -> bool {
+        let path: Vec<_> = path.split('/').filter(|c| !c.is_empty()).collect();
+        let included = self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|p| matches_path(p, &path) || may_match_below(p, &path));
+
+        included && !self.exclude.iter().any(|p| matches_path(p, &path))
+    }
+}
+
+impl Repository {
+    /// List all submodules (recursively) with their recorded commit and status.
+    ///
+    /// This always runs the git CLI.
+    pub fn submodules(&self) -> Result<Vec<Submodule>> {
+        if !self.worktree.join(".gitmodules").exists() {
+            return Ok(Vec::new());
+        }
+
+        let status = cmd!(GIT, "submodule", "status", "--recursive"; current_dir=(&self.worktree), envs=(LC_ALL))
+            .stdout()?;
+        let recorded = cmd!(GIT, "submodule", "status", "--cached", "--recursive"; current_dir=(&self.worktree), envs=(LC_ALL))
+            .stdout()?;
+        let recorded: HashMap<_, _> = recorded
+            .lines()
+            .filter_map(parse_status_line)
+            .map(|(_, commit, path)| (path, commit))
+            .collect();
+
+        let mut gitmodules = HashMap::new();
+        let mut submodules: Vec<Submodule> = Vec::new();
+        for (flag, commit, path) in status.lines().filter_map(parse_status_line) {
+            let status = match flag {
+                '-' => SubmoduleStatus::Uninitialized,
+                '+' => SubmoduleStatus::Modified,
+                'U' => SubmoduleStatus::Conflict,
+                _ => SubmoduleStatus::UpToDate,
+            };
+
+            // Superprojects are listed before their submodules.
+            let parent = submodules
+                .iter()
+                .map(|s| s.path.as_str())
+                .filter(|p| path.starts_with(&format!("{p}/")))
+                .max_by_key(|p| p.len())
+                .unwrap_or("")
+                .to_owned();
+            if !gitmodules.contains_key(&parent) {
+                let modules = read_gitmodules(&self.worktree.join(&parent))?;
+                gitmodules.insert(parent.clone(), modules);
+            }
+            let module = gitmodules[&parent]
+                .iter()
+                .find(|m| join_path(&parent, &m.path) == path);
+
+            submodules.push(Submodule {
+                name: module
+                    .map(|m| m.name.clone())
+                    .unwrap_or_else(|| path.clone()),
+                url: module.and_then(|m| m.url.clone()),
+                commit: recorded
+                    .get(&path)
+                    .cloned()
+                    .unwrap_or_else(|| commit.clone()),
+                checked_out: if status == SubmoduleStatus::Uninitialized {
+                    None
+                } else {
+                    Some(commit)
+                },
+                path,
+                status,
+            });
+        }
+
+        Ok(submodules)
+    }
+
+    /// Initialize and check out all submodules selected by `filter` (recursively) and
+    /// return their paths.
+    ///
+    /// This can be used to check out more submodules of a repository that was cloned
+    /// with [`CloneOptions::submodules`](super::CloneOptions::submodules). Submodules are
+    /// fetched shallowly if the repository is shallow and from a mirror if one is
+    /// configured (see [`Mirrors::from_env`](crate::utils::Mirrors::from_env)). This
+    /// always runs the git CLI.
+    pub fn init_submodules(&self, filter: &SubmoduleFilter) -> Result<Vec<String>> {
+        let mirror_args = crate::utils::Mirrors::from_env()?.git_config_args();
+        self.update_submodules_filtered(filter, self.is_shallow(), &mirror_args, &super::jobs()?)
+    }
+
+    pub(super) fn update_submodules_filtered(
+        &self,
+        filter: &SubmoduleFilter,
+        shallow: bool,
+        mirror_args: &[String],
+        jobs: &str,
+    ) -> Result<Vec<String>> {
+        let mut updated = Vec::new();
+        update_filtered_in(
+            &self.worktree,
+            "",
+            filter,
+            shallow,
+            mirror_args,
+            jobs,
+            &mut updated,
+        )?;
+        Ok(updated)
+    }
+}
+
+fn update_filtered_in(
+    dir: &Path,
+    prefix: &str,
+    filter: &SubmoduleFilter,
+    shallow: bool,
+    mirror_args: &[String],
+    jobs: &str,
+    updated: &mut Vec<String>,
+) -> Result<()> {
+    let paths: Vec<_> = read_gitmodules(dir)?
+        .into_iter()
+        .filter(|m| m.update.as_deref() != Some("none"))
+        .map(|m| m.path)
+        .filter(|p| filter.matches(&join_path(prefix, p)))
+        .collect();
+    if paths.is_empty() {
+        return Ok(());
+    }
+
+    let fetched_shallow = shallow
+        && match cmd!(GIT, @mirror_args, "submodule", "update", "--init", "--depth", "1", jobs, "--"; args=(&paths), current_dir=(dir), envs=(LC_ALL), tee=(TeeSink::Parent)).run() {
+            Ok(()) => true,
+            Err(e) => {
+                log::warn!("Could not fetch submodules shallowly, fetching them fully instead: {e}");
+                false
+            }
+        };
+    if !fetched_shallow {
+        cmd!(GIT, @mirror_args, "submodule", "update", "--init", jobs, "--"; args=(&paths), current_dir=(dir), envs=(LC_ALL), tee=(TeeSink::Parent))
+            .run()
+            .with_context(|| format!("could not update the submodules of '{}'", dir.display()))?;
+    }
+
+    for path in paths {
+        let full_path = join_path(prefix, &path);
+        updated.push(full_path.clone());
+        update_filtered_in(
+            &dir.join(&path),
+            &full_path,
+            filter,
+            shallow,
+            mirror_args,
+            jobs,
+            updated,
+        )?;
+    }
+    Ok(())
+}
+
+/// A submodule entry of a `.gitmodules` file.
+#[derive(Debug, Default)]
+pub(super) struct GitModule {
+    pub name: String,
+    pub path: String,
+    pub url: Option<String>,
+    pub update: Option<String>,
+}
+
+/// Read the submodules in the `.gitmodules` file of the work-tree `dir`.
+pub(super) fn read_gitmodules(dir: &Path) -> Result<Vec<GitModule>> {
+    if !dir.join(".gitmodules").exists() {
+        return Ok(Vec::new());
+    }
+
+    let config = cmd!(GIT, "config", "--file", ".gitmodules", "--get-regexp", r"^submodule\..*\.(path|url|update)$"; current_dir=(dir), envs=(LC_ALL))
+        .stdout()
+        .unwrap_or_default();
+
+    let mut modules: Vec<GitModule> = Vec::new();
+    for line in config.lines() {
+        let (key, value) = match line.split_once(' ') {
+            Some(v) => v,
+            None => continue,
+        };
+        let (name, var) = match key
+            .strip_prefix("submodule.")
+            .and_then(|k| k.rsplit_once('.'))
+        {
+            Some(v) => v,
+            None => continue,
+        };
+
+        let index = match modules.iter().position(|m| m.name == name) {
+            Some(i) => i,
+            None => {
+                modules.push(GitModule {
+                    name: name.to_owned(),
+                    ..Default::default()
+                });
+                modules.len() - 1
+            }
+        };
+        let module = &mut modules[index];
+        match var {
+            "path" => module.path = value.to_owned(),
+            "url" => module.url = Some(value.to_owned()),
+            _ => module.update = Some(value.to_owned()),
+        }
+    }
+    modules.retain(|m| !m.path.is_empty());
+
+    Ok(modules)
+}
+
+/// Parse a line of `git submodule status` into its flag, commit and path.
+fn parse_status_line(line: &str) -> Option<(char, String, String)> {
+    let flag = line.chars().next()?;
+    let (commit, rest) = line[flag.len_utf8()..].split_once(' ')?;
+    // Strip the optional ` (<describe output>)`.
+    let path = match rest.rfind(" (") {
+        Some(i) if rest.ends_with(')') => &rest[..i],
+        _ => rest,
+    };
+    Some((flag, commit.to_owned(), path.to_owned()))
+}
+
+pub(super) fn join_path(prefix: &str, path: &str) -> String {
+    if prefix.is_empty() {
+        path.to_owned()
+    } else {
+        format!("{prefix}/{path}")
+    }
+}
+
+/// Whether `pattern` matches `path` or one of its parent directories.
+fn matches_path(pattern: &str, path: &[&str]) -> bool {
+    let pattern: Vec<_> = pattern.split('/').filter(|c| !c.is_empty()).collect();
+    (1..=path.len()).any(|len| glob(&pattern, &path[..len]))
+}
+
+/// Whether `pattern` may match a path below `path`.
+fn may_match_below(pattern: &str, path: &[&str]) -> bool {
+    let pattern: Vec<_> = pattern.split('/').filter(|c| !c.is_empty()).collect();
+    if pattern.len() <= path.len() {
+        return pattern.contains(&"**");
+    }
+    let pattern = &pattern[..path.len()];
+    pattern.contains(&"**") || glob(pattern, path)
+}
+
+fn glob(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|i| glob(rest, &path[i..])),
+        Some((component, rest)) => match path.split_first() {
+            Some((c, path_rest)) => wildcard(component, c) && glob(rest, path_rest),
+            None => false,
+        },
+    }
+}
+
+fn wildcard(pattern: &str, s: &str) -> bool {
+    let mut chars = pattern.chars();
+    match chars.next() {
+        None => s.is_empty(),
+        Some('*') => (0..=s.len())
+            .filter(|i| s.is_char_boundary(*i))
+            .any(|i| wildcard(chars.as_str(), &s[i..])),
+        Some('?') => match s.chars().next() {
+            Some(c) => wildcard(chars.as_str(), &s[c.len_utf8()..]),
+            None => false,
+        },
+        Some(c) => s.starts_with(c) && wildcard(chars.as_str(), &s[c.len_utf8()..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{allow_file_protocol, create_origin, git};
+    use super::super::{Backend, CloneOptions, Ref};
+    use super::*;
+
+    #[test]
+    fn filter() {
+        let filter = SubmoduleFilter::new()
+            .exclude("components/bt/controller/lib_*")
+            .exclude("**/test");
+        assert!(filter.matches("components/bt/controller/lib"));
+        assert!(!filter.matches("components/bt/controller/lib_esp32"));
+        assert!(!filter.matches("components/bt/controller/lib_esp32/nested"));
+        assert!(!filter.matches("components/test"));
+        assert!(filter.matches("components/testing"));
+
+        let filter = SubmoduleFilter::new()
+            .include("components/mbedtls")
+            .include("components/*/nested");
+        assert!(filter.matches("components/mbedtls"));
+        assert!(filter.matches("components/mbedtls/mbedtls"));
+        assert!(filter.matches("components"));
+        assert!(filter.matches("components/bt"));
+        assert!(filter.matches("components/bt/nested"));
+        assert!(!filter.matches("components/bt/other"));
+        assert!(!filter.matches("other"));
+    }
+
+    #[test]
+    fn selective_checkout() {
+        allow_file_protocol();
+        let dir = tempfile::tempdir().unwrap();
+        let (url, _) = create_origin(dir.path());
+        let lib_commit = git(&dir.path().join("lib"), &["rev-parse", "HEAD"]);
+
+        let mut repo = Repository::new(dir.path().join("repo")).with_backend(Backend::Cli);
+        let options = CloneOptions::new()
+            .force_ref(Ref::Tag("v2".into()))
+            .submodules(SubmoduleFilter::new().exclude("lib"));
+        repo.clone_ext(&url, options).unwrap();
+
+        let lib = Submodule {
+            name: "lib".into(),
+            path: "lib".into(),
+            url: Some("../lib".into()),
+            commit: lib_commit.clone(),
+            checked_out: None,
+            status: SubmoduleStatus::Uninitialized,
+        };
+        assert_eq!(repo.submodules().unwrap(), std::slice::from_ref(&lib));
+        assert!(!repo.worktree().join("lib/lib.txt").exists());
+
+        let updated = repo
+            .init_submodules(&SubmoduleFilter::new().include("lib"))
+            .unwrap();
+        assert_eq!(updated, ["lib"]);
+        assert_eq!(
+            repo.submodules().unwrap(),
+            [Submodule {
+                checked_out: Some(lib_commit),
+                status: SubmoduleStatus::UpToDate,
+                ..lib
+            }]
+        );
+        assert!(repo.worktree().join("lib/lib.txt").exists());
+    }
+}
@@ -0,0 +1,381 @@
+//! Verification and repair of (possibly half-initialized) repositories.
+
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+
+use super::{
+    jobs, CloneOptions, Repository, Submodule, SubmoduleFilter, SubmoduleStatus, GIT, LC_ALL,
+};
+use crate::cmd;
+
+/// A problem of a [`Repository`] found by [`Repository::verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntegrityIssue {
+    /// `HEAD` does not resolve to a commit, e.g. because the clone was interrupted.
+    InvalidHead(String),
+    /// The lock file of the index of the repository (or of a submodule) was left behind
+    /// by an interrupted git command.
+    IndexLock(PathBuf),
+    /// A submodule is not initialized or not checked out at its recorded commit; only
+    /// uninitialized submodules are repaired by [`Repository::repair`].
+    Submodule(Submodule),
+    /// Tracked files are missing from the work-tree or the index.
+    MissingFiles(Vec<String>),
+    /// Tracked files were modified; this is not repaired by [`Repository::repair`].
+    Modified(Vec<String>),
+}
+
+impl Display for IntegrityIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidHead(e) => write!(f, "HEAD does not resolve to a commit: {e}"),
+            Self::IndexLock(path) => write!(f, "stale index lock '{}'", path.display()),
+            Self::Submodule(s) => write!(
+                f,
+                "submodule '{}' is {:?} (recorded commit {})",
+                s.path, s.status, s.commit
+            ),
+            Self::MissingFiles(files) => write!(f, "{} tracked files are missing", files.len()),
+            Self::Modified(files) => write!(f, "{} tracked files are modified", files.len()),
+        }
+    }
+}
+
+impl IntegrityIssue {
+    /// Whether this issue may be a local change, which [`Repository::repair`] keeps.
+    fn is_local_change(&self) -> bool {
+        match self {
+            Self::Modified(_) => true,
+            Self::Submodule(s) => s.status != SubmoduleStatus::Uninitialized,
+            _ => false,
+        }
+    }
+}
+
+impl Repository {
+    /// Check that this repository is completely cloned and checked out.
+    ///
+    /// This checks that `HEAD` resolves to a commit, no `index.lock` remains, all
+    /// submodules are initialized at their recorded commits and no tracked files are
+    /// missing or modified (untracked files are ignored). Returns all issues found, which
+    /// is empty if the repository is intact.
+    ///
+    /// This always runs the git CLI.
+    pub fn verify(&self) -> Result<Vec<IntegrityIssue>> {
+        self.verify_with(None)
+    }
+
+    /// Like [`verify`](Self::verify), but only require the submodules selected by
+    /// `filter` to be initialized.
+    pub fn verify_with(&self, filter: Option<&SubmoduleFilter>) -> Result<Vec<IntegrityIssue>> {
+        let mut issues = self.verify_head()?;
+        if issues
+            .iter()
+            .any(|i| matches!(i, IntegrityIssue::InvalidHead(_)))
+        {
+            // The work-tree can still be compared with the index, if there is one (an
+            // interrupted clone doesn't write it), so that local changes are not lost.
+            if let Ok(status) = self.status() {
+                let modified: Vec<_> = status
+                    .lines()
+                    .filter(|l| l.len() > 3 && l.as_bytes()[1] == b'M')
+                    .map(|l| l[3..].to_owned())
+                    .collect();
+                if !modified.is_empty() {
+                    issues.push(IntegrityIssue::Modified(modified));
+                }
+            }
+            return Ok(issues);
+        }
+
+        issues.extend(
+            self.submodules()?
+                .into_iter()
+                .filter(|s| {
+                    s.status != SubmoduleStatus::UpToDate
+                        && filter.map_or(true, |f| f.matches(&s.path))
+                })
+                .map(IntegrityIssue::Submodule),
+        );
+
+        let status = self.status()?;
+        let (mut missing, mut modified) = (Vec::new(), Vec::new());
+        for line in status.lines().filter(|l| l.len() > 3) {
+            let path = line[3..].to_owned();
+            if line[..2].contains('D') {
+                missing.push(path);
+            } else {
+                modified.push(path);
+            }
+        }
+        if !missing.is_empty() {
+            issues.push(IntegrityIssue::MissingFiles(missing));
+        }
+        if !modified.is_empty() {
+            issues.push(IntegrityIssue::Modified(modified));
+        }
+
+        Ok(issues)
+    }
+
+    /// The porcelain status of the tracked files, ignoring submodules.
+    fn status(&self) -> Result<String> {
+        Ok(cmd!(GIT, @self.git_args(), "status", "--porcelain", "--untracked-files=no", "--ignore-submodules=all"; current_dir=(&self.worktree), envs=(LC_ALL))
+            .stdout()?)
+    }
+
+    /// Check that `HEAD` resolves to a commit and no `index.lock` remains.
+    ///
+    /// This is the cheap part of [`verify`](Self::verify), which neither looks at the
+    /// work-tree nor at the submodules; it finds the issues most interrupted git commands
+    /// leave behind.
+    pub fn verify_head(&self) -> Result<Vec<IntegrityIssue>> {
+        let mut issues = Vec::new();
+        find_index_locks(&self.git_dir, &mut issues)?;
+
+        if let Err(e) = cmd!(GIT, @self.git_args(), "rev-parse", "--verify", "--quiet", "HEAD^{commit}"; envs=(LC_ALL))
+            .stdout()
+        {
+            issues.push(IntegrityIssue::InvalidHead(e.to_string()));
+        }
+        Ok(issues)
+    }
+
+    /// Repair the issues found by [`verify_with`](Self::verify_with) (with the submodule
+    /// filter of `options`) and return them.
+    ///
+    /// Stale index locks are removed, missing files are checked out and uninitialized
+    /// submodules are initialized. If `HEAD` does not resolve or the repository is still
+    /// not intact after that, it is cloned again from `url` with `options` (see
+    /// [`clone_ext`](Self::clone_ext)).
+    ///
+    /// Modified files and submodules with another commit checked out may be local
+    /// changes, which are kept and not returned. If the repository would have to be
+    /// cloned again but has such changes, this fails instead.
+    ///
+    /// This must not be called while another git command is running in this repository,
+    /// as it would remove its lock.
+    pub fn repair(&mut self, url: &str, options: CloneOptions) -> Result<Vec<IntegrityIssue>> {
+        let filter = options.submodules.as_ref();
+        let (kept, issues): (Vec<_>, Vec<_>) = self
+            .verify_with(filter)?
+            .into_iter()
+            .partition(IntegrityIssue::is_local_change);
+        if issues.is_empty() {
+            return Ok(issues);
+        }
+
+        let repaired = if issues
+            .iter()
+            .any(|i| matches!(i, IntegrityIssue::InvalidHead(_)))
+        {
+            false
+        } else {
+            match self.repair_in_place(url, &issues, &kept, &options) {
+                Ok(()) => self
+                    .verify_with(filter)?
+                    .iter()
+                    .all(IntegrityIssue::is_local_change),
+                Err(e) => {
+                    log::warn!("Could not repair '{}': {e:#}", self.worktree.display());
+                    false
+                }
+            }
+        };
+
+        if !repaired {
+            if !kept.is_empty() {
+                bail!(
+                    "'{}' is broken ({}), but is not cloned again as it has local changes: {}",
+                    self.worktree.display(),
+                    join_issues(&issues),
+                    join_issues(&kept)
+                );
+            }
+            log::warn!(
+                "Cloning '{}' again as it is broken: {}",
+                self.worktree.display(),
+                join_issues(&issues)
+            );
+            if self.worktree.exists() {
+                remove_dir_all::remove_dir_all(&self.worktree)?;
+            }
+            self.clone_ext(url, options)?;
+        }
+
+        Ok(issues)
+    }
+
+    fn repair_in_place(
+        &self,
+        url: &str,
+        issues: &[IntegrityIssue],
+        kept: &[IntegrityIssue],
+        options: &CloneOptions,
+    ) -> Result<()> {
+        for issue in issues {
+            match issue {
+                IntegrityIssue::IndexLock(lock) => fs::remove_file(lock)?,
+                IntegrityIssue::MissingFiles(files) => {
+                    // Check out the files in chunks to stay below the command line limit.
+                    for files in files.chunks(256) {
+                        cmd!(GIT, @self.git_args(), "checkout", "--quiet", "HEAD", "--"; args=(files), current_dir=(&self.worktree), envs=(LC_ALL))
+                            .run()?;
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        if issues
+            .iter()
+            .any(|i| matches!(i, IntegrityIssue::Submodule(_)))
+        {
+            let mirror_args = crate::utils::Mirrors::from_env()?.git_config_args();
+            // Submodules at their recorded commit are not changed by an update, the others
+            // are excluded so that their checked out commits are kept.
+            let filter = kept
+                .iter()
+                .filter_map(|i| match i {
+                    IntegrityIssue::Submodule(s) => Some(s.path.as_str()),
+                    _ => None,
+                })
+                .fold(options.submodules.clone().unwrap_or_default(), |f, path| {
+                    f.exclude(path)
+                });
+            if let Some(store) = &options.object_store {
+                store.update_repo(self, url, &mirror_args, Some(&filter), || Ok(()))?;
+            } else {
+                self.update_submodules(Some(&filter), self.is_shallow(), &mirror_args, &jobs()?)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn join_issues(issues: &[IntegrityIssue]) -> String {
+    issues
+        .iter()
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Find the `index.lock` files in `git_dir` and the git dirs of its submodules.
+fn find_index_locks(git_dir: &Path, issues: &mut Vec<IntegrityIssue>) -> Result<()> {
+    let lock = git_dir.join("index.lock");
+    if lock.exists() {
+        issues.push(IntegrityIssue::IndexLock(lock));
+    }
+
+    let modules = git_dir.join("modules");
+    if modules.is_dir() {
+        for entry in fs::read_dir(modules)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                find_index_locks(&entry.path(), issues)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::super::tests::{allow_file_protocol, create_origin, git};
+    use super::*;
+    use crate::git::{Ref, SubmoduleStatus};
+
+    #[test]
+    fn verify_and_repair() {
+        allow_file_protocol();
+        let dir = tempfile::tempdir().unwrap();
+        let (url, _) = create_origin(dir.path());
+        let options = || CloneOptions::new().force_ref(Ref::Tag("v2".into()));
+
+        let mut repo = Repository::new(dir.path().join("clone"));
+        repo.clone_ext(&url, options()).unwrap();
+        assert_eq!(repo.verify().unwrap(), []);
+
+        git(repo.worktree(), &["submodule", "deinit", "--quiet", "lib"]);
+        fs::remove_file(repo.worktree().join("file.txt")).unwrap();
+        let lock = repo.git_dir.join("index.lock");
+        fs::write(&lock, "").unwrap();
+
+        let issues = repo.verify().unwrap();
+        assert_eq!(issues.len(), 3, "{issues:?}");
+        assert_eq!(issues[0], IntegrityIssue::IndexLock(lock.clone()));
+        assert!(
+            matches!(&issues[1], IntegrityIssue::Submodule(s) if s.status == SubmoduleStatus::Uninitialized)
+        );
+        assert_eq!(
+            issues[2],
+            IntegrityIssue::MissingFiles(vec!["file.txt".into()])
+        );
+        // A filter that excludes the submodule doesn't require it.
+        let filter = SubmoduleFilter::new().exclude("lib");
+        assert_eq!(repo.verify_with(Some(&filter)).unwrap().len(), 2);
+
+        // Untracked files are ignored.
+        fs::write(repo.worktree().join("untracked.txt"), "").unwrap();
+        assert_eq!(repo.repair(&url, options()).unwrap(), issues);
+        assert_eq!(repo.verify().unwrap(), []);
+        assert!(!lock.exists());
+        assert!(repo.worktree().join("lib/lib.txt").exists());
+
+        // Modified files are reported but kept.
+        fs::write(repo.worktree().join("file.txt"), "patched").unwrap();
+        assert_eq!(
+            repo.verify().unwrap(),
+            [IntegrityIssue::Modified(vec!["file.txt".into()])]
+        );
+        assert_eq!(repo.repair(&url, options()).unwrap(), []);
+        let content = fs::read_to_string(repo.worktree().join("file.txt")).unwrap();
+        assert_eq!(content, "patched");
+
+        // A submodule with another commit checked out is kept as well.
+        let lib = repo.worktree().join("lib");
+        git(&lib, &["checkout", "--quiet", "HEAD~1"]);
+        let lib_commit = git(&lib, &["rev-parse", "HEAD"]);
+        fs::remove_file(repo.worktree().join(".gitmodules")).unwrap();
+        assert_eq!(
+            repo.repair(&url, options()).unwrap(),
+            [IntegrityIssue::MissingFiles(vec![".gitmodules".into()])]
+        );
+        assert_eq!(git(&lib, &["rev-parse", "HEAD"]), lib_commit);
+
+        // A repository with local changes that can't be repaired in place is not cloned
+        // again.
+        fs::create_dir(&lock).unwrap();
+        let error = repo.repair(&url, options()).unwrap_err();
+        assert!(error.to_string().contains("local changes"), "{error}");
+        let content = fs::read_to_string(repo.worktree().join("file.txt")).unwrap();
+        assert_eq!(content, "patched");
+        fs::remove_dir(&lock).unwrap();
+        assert_eq!(repo.verify_head().unwrap(), []);
+
+        // A repository without a valid HEAD is cloned again, unless files differ from
+        // the index.
+        fs::write(repo.git_dir.join("HEAD"), "ref: refs/heads/missing\n").unwrap();
+        let issues = repo.verify().unwrap();
+        assert!(
+            matches!(&issues[..], [IntegrityIssue::InvalidHead(_), IntegrityIssue::Modified(files)] if files == &["file.txt"]),
+            "{issues:?}"
+        );
+        assert!(repo.repair(&url, options()).is_err());
+        git(repo.worktree(), &["checkout", "--", "file.txt"]);
+        let issues = repo.verify().unwrap();
+        assert!(
+            matches!(issues[..], [IntegrityIssue::InvalidHead(_)]),
+            "{issues:?}"
+        );
+        repo.repair(&url, options()).unwrap();
+        assert_eq!(repo.verify().unwrap(), []);
+        assert_eq!(git(repo.worktree(), &["describe", "--tags"]), "v2");
+    }
+}
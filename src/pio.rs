@@ -9,7 +9,7 @@ use std::ffi::OsStr;
 use std::fs::{self, File};
 use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Output, Stdio};
+use std::process::{Output, Stdio};
 
 use anyhow::{bail, Result};
 use log::*;
@@ -17,10 +17,14 @@ use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use tempfile::*;
 
+use crate::cmd::Cmd;
 use crate::python::{check_python_at_least, PYTHON};
 use crate::utils;
 
 const INSTALLER_URL: &str = "https://raw.githubusercontent.com/platformio/platformio-core-installer/master/get-platformio.py";
+/// The lock file that is held while installing or updating PlatformIO (see
+/// [`FileLock`](crate::fs::FileLock)).
+pub const INSTALL_LOCK_FILENAME: &str = ".embuild-install.lock";
 const INSTALLER_BLOB: &[u8] = include_bytes!("pio/resources/get-platformio.py.resource");
 
 /// The logging verbosity level when executing platformio.
@@ -292,11 +296,10 @@ impl Pio {
     }
 
     pub fn try_from_env() -> Option<Self> {
-        let mut cmd = Command::new("platformio");
+        let mut cmd = Cmd::new("platformio");
+        cmd.arg("system").arg("info");
 
-        Self::json::<PioInfo>(cmd.arg("system").arg("info"))
-            .map(Pio::from)
-            .ok()
+        Self::json::<PioInfo>(&mut cmd).map(Pio::from).ok()
     }
 
     #[must_use]
@@ -318,15 +321,15 @@ impl Pio {
         Ok(())
     }
 
-    pub fn cmd(&self) -> Command {
-        let mut command = Command::new(&self.platformio_exe);
+    pub fn cmd(&self) -> Cmd {
+        let mut command = Cmd::new(&self.platformio_exe);
 
         command.env("PLATFORMIO_CORE_DIR", &self.core_dir);
 
         command
     }
 
-    pub fn run_cmd(&self) -> Command {
+    pub fn run_cmd(&self) -> Cmd {
         let mut cmd = self.cmd();
 
         cmd.arg("run");
@@ -357,40 +360,40 @@ impl Pio {
 
     pub fn exec_with_args(&self, args: &[impl AsRef<OsStr>]) -> Result<()> {
         let mut cmd = self.cmd();
+        cmd.args(args);
 
-        self.exec(cmd.args(args))
+        self.exec(&mut cmd)
     }
 
     pub fn run_with_args(&self, args: &[impl AsRef<OsStr>]) -> Result<()> {
         let mut cmd = self.run_cmd();
+        cmd.args(args);
 
-        self.exec(cmd.args(args))
+        self.exec(&mut cmd)
     }
 
-    pub fn exec(&self, cmd: &mut Command) -> Result<()> {
-        debug!("Running PlatformIO command: {:?}", cmd);
+    pub fn exec(&self, cmd: &mut Cmd) -> Result<()> {
+        debug!("Running PlatformIO command: {:?}", cmd.cmd);
 
         if self.log_level == LogLevel::Quiet {
             // Suppress PlatformIO's "Warning! Ignore unknown configuration option `...` in section [...]"
             // ... and the Download Manager verbosity... it is not suppressed by passing "-s" to pio run, unfortunately
-            cmd.stderr(Stdio::null());
-            cmd.stdout(Stdio::null());
+            cmd.cmd.stderr(Stdio::null());
+            cmd.cmd.stdout(Stdio::null());
         }
 
-        cmd.status()?;
+        cmd.ignore_exitcode().run()?;
 
         Ok(())
     }
 
-    pub fn json<T: DeserializeOwned>(cmd: &mut Command) -> Result<T> {
+    pub fn json<T: DeserializeOwned>(cmd: &mut Cmd) -> Result<T> {
         cmd.arg("--json-output");
-        debug!("Running PlatformIO command {:?}", cmd);
+        debug!("Running PlatformIO command {:?}", cmd.cmd);
 
-        let output = cmd.output()?;
+        let stdout = cmd.output(|output| output.stdout)?;
 
-        Self::check(&output)?;
-
-        Ok(serde_json::from_slice::<T>(&output.stdout)?)
+        Ok(serde_json::from_slice::<T>(&stdout)?)
     }
 
     pub fn boards(&self, id: Option<impl AsRef<str>>) -> Result<Vec<Board>> {
@@ -599,6 +602,16 @@ impl PioInstaller {
     }
 
     pub fn update(&self) -> Result<Pio> {
+        // Other builds may install into the same directory concurrently.
+        let lock_dir = self
+            .pio_location
+            .clone()
+            .or_else(|| std::env::var_os("PLATFORMIO_CORE_DIR").map(PathBuf::from))
+            .unwrap_or_else(std::env::temp_dir);
+        let _lock = crate::fs::LockOptions::new()
+            .message("Waiting for another build to finish installing PlatformIO")
+            .lock(lock_dir.join(INSTALL_LOCK_FILENAME))?;
+
         if let Ok(pii) = self.check() {
             info!("PlatformIO is up-to-date");
 
@@ -614,15 +627,15 @@ impl PioInstaller {
     pub fn install(&self) -> Result<()> {
         let mut cmd = self.command();
 
-        debug!("Running command {:?}", cmd);
+        debug!("Running command {:?}", cmd.cmd);
 
         if self.silent {
             // Suppress PlatformIO's installer verbose output
-            cmd.stdout(Stdio::null());
-            cmd.stderr(Stdio::null());
+            cmd.cmd.stdout(Stdio::null());
+            cmd.cmd.stderr(Stdio::null());
         }
 
-        cmd.status()?;
+        cmd.ignore_exitcode().run()?;
 
         Ok(())
     }
@@ -634,21 +647,21 @@ impl PioInstaller {
 
         cmd.arg("check").arg("core").arg("--dump-state").arg(&path);
 
-        debug!("Running command {:?}", cmd);
+        debug!("Running command {:?}", cmd.cmd);
 
         if self.silent {
             // Suppress PlatformIO's installer verbose output
-            cmd.stdout(Stdio::null());
-            cmd.stderr(Stdio::null());
+            cmd.cmd.stdout(Stdio::null());
+            cmd.cmd.stderr(Stdio::null());
         }
 
-        cmd.status()?;
+        cmd.ignore_exitcode().run()?;
 
         Ok(serde_json::from_reader::<File, PioInstallerInfo>(file)?)
     }
 
-    fn command(&self) -> Command {
-        let mut command = Command::new(PYTHON);
+    fn command(&self) -> Cmd {
+        let mut command = Cmd::new(PYTHON);
         if let Some(pio_location) = self.pio_location.as_ref() {
             command.env("PLATFORMIO_CORE_DIR", pio_location);
         }
@@ -1334,3 +1347,119 @@ impl Resolver {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::cmd::{with_executor, FakeCmd, FakeExecutor};
+
+    const BOARDS: &str = r#"[
+        {"id": "esp32dev", "name": "Espressif ESP32 Dev Module", "platform": "espressif32",
+         "mcu": "ESP32", "fcpu": 240000000, "ram": 327680, "rom": 4194304,
+         "frameworks": ["arduino", "espidf"], "vendor": "Espressif", "url": ""},
+        {"id": "esp32-c3-devkitm-1", "name": "Espressif ESP32-C3-DevKitM-1",
+         "platform": "espressif32", "mcu": "ESP32C3", "fcpu": 160000000, "ram": 327680,
+         "rom": 4194304, "frameworks": ["espidf"], "vendor": "Espressif", "url": ""}
+    ]"#;
+
+    #[test]
+    fn install_and_query_boards() {
+        let dir = tempfile::tempdir().unwrap();
+        let installer = dir.path().join("get-platformio.py");
+        let installer_arg = installer.to_string_lossy().into_owned();
+        let core_dir = dir.path().join("core");
+        let platformio_exe = core_dir.join("penv/bin/platformio");
+
+        let state = serde_json::to_string(&PioInstallerInfo {
+            is_develop_core: false,
+            platformio_exe: platformio_exe.clone(),
+            penv_dir: core_dir.join("penv"),
+            installer_version: "1.2.2".into(),
+            python_version: "3.11.4".into(),
+            core_version: "6.1.16".into(),
+            system: "linux_x86_64".into(),
+            python_exe: core_dir.join("penv/bin/python"),
+            cache_dir: core_dir.join(".cache"),
+            penv_bin_dir: core_dir.join("penv/bin"),
+            core_dir: core_dir.clone(),
+        })
+        .unwrap();
+        let exe = platformio_exe.clone();
+        let fake = Arc::new(
+            FakeExecutor::new()
+                .with(
+                    FakeCmd::new(PYTHON)
+                        .args(["--version"])
+                        .stdout("Python 3.11.4"),
+                )
+                // The installer creates the platformio executable.
+                .with(
+                    FakeCmd::new(PYTHON)
+                        .args([installer_arg.clone()])
+                        .effect(move |_| {
+                            fs::create_dir_all(exe.parent().unwrap())?;
+                            fs::write(&exe, "")
+                        }),
+                )
+                // The state is only dumped once platformio is installed.
+                .with(
+                    FakeCmd::new(PYTHON)
+                        .args_prefix([installer_arg.as_str(), "check", "core"])
+                        .effect({
+                            let exe = platformio_exe.clone();
+                            move |cmd| {
+                                let path = cmd.get_args().last().unwrap();
+                                if exe.exists() {
+                                    fs::write(path, &state)?;
+                                }
+                                Ok(())
+                            }
+                        }),
+                )
+                .with(
+                    FakeCmd::new(platformio_exe.to_string_lossy())
+                        .args(["boards", "esp32dev", "--json-output"])
+                        .stdout(BOARDS),
+                ),
+        );
+
+        let boards = with_executor(fake.clone(), || {
+            let pio = PioInstaller::new_location(&installer)?
+                .pio(&core_dir)
+                .update()?;
+            assert_eq!(pio.platformio_exe, platformio_exe);
+            pio.boards(Some("esp32dev"))
+        })
+        .unwrap();
+
+        assert_eq!(
+            boards.iter().map(|b| b.id.as_str()).collect::<Vec<_>>(),
+            ["esp32dev"]
+        );
+        // The path of the dumped state is a temporary file.
+        let calls: Vec<_> = fake
+            .calls()
+            .iter()
+            .map(|call| {
+                call.iter()
+                    .skip(1)
+                    .take_while(|arg| *arg != "--dump-state")
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect();
+        assert_eq!(
+            calls,
+            [
+                "--version".to_owned(),
+                format!("{installer_arg} check core"),
+                installer_arg.clone(),
+                format!("{installer_arg} check core"),
+                "boards esp32dev --json-output".to_owned(),
+            ]
+        );
+    }
+}
@@ -115,6 +115,7 @@ pub struct Builder {
     git_repos_enabled: bool,
     git_repos: Vec<(String, PathBuf)>,
     files: Vec<(PathBuf, PathBuf)>,
+    dirs: Vec<(PathBuf, PathBuf)>,
     platform_packages: Vec<(String, PathBuf)>,
     platform_packages_patches_enabled: bool,
     platform_packages_patches: Vec<(PathBuf, PathBuf)>,
@@ -132,6 +133,7 @@ impl Builder {
             git_repos_enabled: false,
             git_repos: Vec::new(),
             files: Vec::new(),
+            dirs: Vec::new(),
             platform_packages: Vec::new(),
             platform_packages_patches_enabled: false,
             platform_packages_patches: Vec::new(),
@@ -208,6 +210,14 @@ impl Builder {
         self
     }
 
+    /// Sync the directory `source` into `dest` in the project, copying only files that
+    /// are different or don't exist in `dest` (see [`crate::fs::sync_dir`]).
+    pub fn dir(&mut self, source: impl AsRef<Path>, dest: impl AsRef<Path>) -> &mut Self {
+        self.dirs
+            .push((source.as_ref().to_owned(), dest.as_ref().to_owned()));
+        self
+    }
+
     pub fn platform_package(
         &mut self,
         package: impl AsRef<str>,
@@ -454,7 +464,7 @@ impl Builder {
 
         debug!("Creating file {}", platformio_ini_path.display());
 
-        fs::write(
+        crate::fs::write_atomic_if_changed(
             platformio_ini_path,
             format!(
                 r#"
@@ -506,7 +516,19 @@ build_type = release
             debug!("Creating/updating {}", dest_file.display());
 
             fs::create_dir_all(dest_file.parent().unwrap())?;
-            fs::copy(&file_pair.0, dest_file)?;
+            crate::fs::copy_file_if_different(&file_pair.0, dest_file)?;
+        }
+
+        for (source, dest) in &self.dirs {
+            let dest_dir = self.project_dir.join(dest);
+
+            let report = crate::fs::sync_dir(source, &dest_dir, &crate::fs::SyncOptions::new())?;
+            debug!(
+                "Synced {} ({} created, {} updated)",
+                dest_dir.display(),
+                report.created.len(),
+                report.updated.len()
+            );
         }
 
         Ok(())
@@ -4,6 +4,13 @@ use anyhow::{anyhow, Context, Result};
 
 use crate::cmd;
 
+mod interpreter;
+mod requirements;
+mod venv;
+pub use interpreter::*;
+pub use requirements::*;
+pub use venv::*;
+
 /// Python 3 executable name.
 ///
 /// `python` for Window, `python3` otherwise.
@@ -17,11 +24,18 @@ pub const PYTHON: &str = {
 };
 
 /// The Version of a Python Binary
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct PythonVersion {
     pub major: u32,
     pub minor: u32,
 }
 
+impl std::fmt::Display for PythonVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
 /// Check that python is at least `major.minor`.
 pub fn check_python_at_least(major: u32, minor: u32) -> Result<PythonVersion> {
     let version_str = cmd!(PYTHON, "--version")
@@ -46,10 +60,12 @@ pub fn check_python_at_least(major: u32, minor: u32) -> Result<PythonVersion> {
         );
     }
 
-    let python_major = version[0].unwrap();
-    let python_minor = version[1].unwrap();
+    let python_version = PythonVersion {
+        major: version[0].unwrap(),
+        minor: version[1].unwrap(),
+    };
 
-    if python_major < major || python_minor < minor {
+    if python_version < (PythonVersion { major, minor }) {
         Err(anyhow!(
             "Invalid python version '{}'; expected at least {}.{}",
             version_str,
@@ -58,9 +74,35 @@ pub fn check_python_at_least(major: u32, minor: u32) -> Result<PythonVersion> {
         )
         .context(format!("When running '{PYTHON} --version'")))
     } else {
-        Ok(PythonVersion {
-            major: python_major,
-            minor: python_minor,
-        })
+        Ok(python_version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::cmd::{with_executor, FakeCmd, FakeExecutor};
+
+    fn fake_python(version: &str) -> Arc<FakeExecutor> {
+        Arc::new(FakeExecutor::new().with(FakeCmd::new(PYTHON).args(["--version"]).stdout(version)))
+    }
+
+    #[test]
+    fn python_at_least() {
+        let version =
+            with_executor(fake_python("Python 3.11.4"), || check_python_at_least(3, 6)).unwrap();
+        assert_eq!((version.major, version.minor), (3, 11));
+
+        let result = with_executor(fake_python("Python 3.5.1"), || check_python_at_least(3, 6));
+        assert!(result.is_err());
+
+        let version =
+            with_executor(fake_python("Python 4.0.0"), || check_python_at_least(3, 6)).unwrap();
+        assert_eq!((version.major, version.minor), (4, 0));
+
+        let result = with_executor(fake_python("garbage"), || check_python_at_least(3, 6));
+        assert!(result.is_err());
     }
 }
@@ -0,0 +1,201 @@
+use std::env;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use super::{PythonVersion, PYTHON};
+use crate::cmd;
+use crate::cmd::Cmd;
+
+/// Environment variable with the python executable that should be used.
+pub const PYTHON_VAR: &str = "PYTHON";
+/// Environment variable with the python executable that should be used, as used by the
+/// esp-idf (takes precedence over [`PYTHON_VAR`]).
+pub const IDF_PYTHON_VAR: &str = "IDF_PYTHON";
+
+/// Prints the version, machine architecture, pointer width and executable of python.
+const PROBE_SCRIPT: &str = "import platform, struct, sys; \
+    print(sys.version_info[0], sys.version_info[1], sys.version_info[2], \
+    platform.machine() or 'unknown', struct.calcsize('P') * 8, sys.executable)";
+
+/// A python interpreter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interpreter {
+    /// The program used to run this interpreter (e.g. `python3` or `py`).
+    pub program: OsString,
+    /// The arguments passed to [`program`](Self::program) before any other argument
+    /// (e.g. `-3` for the windows `py` launcher).
+    pub args: Vec<String>,
+    /// The absolute path of the python executable (`sys.executable`).
+    pub executable: PathBuf,
+    /// The python version.
+    pub version: PythonVersion,
+    /// The patch version.
+    pub patch: u32,
+    /// The machine architecture, e.g. `x86_64` or `AMD64` (`platform.machine()`).
+    pub arch: String,
+    /// The pointer width of the interpreter in bits (i.e. whether it is a 32 or 64 bit
+    /// python).
+    pub pointer_width: u32,
+}
+
+impl Interpreter {
+    /// Find a python 3 interpreter (see [`find_at_least`](Self::find_at_least)).
+    pub fn find() -> Result<Self> {
+        Self::find_at_least(3, 0)
+    }
+
+    /// Find a python interpreter with at least version `major.minor`.
+    ///
+    /// If the [`IDF_PYTHON_VAR`] or [`PYTHON_VAR`] environment variable is set, only
+    /// that interpreter is used. Otherwise the first working interpreter out of
+    /// [`PYTHON`], `python3.<minor>` (newest first), `python3` and `python` is used, on
+    /// windows the `py -3` launcher is tried first.
+    pub fn find_at_least(major: u32, minor: u32) -> Result<Self> {
+        let required = PythonVersion { major, minor };
+
+        for var in [IDF_PYTHON_VAR, PYTHON_VAR] {
+            if let Some(program) = env::var_os(var).filter(|p| !p.is_empty()) {
+                let interpreter = Self::probe(&program, &[])
+                    .with_context(|| format!("Invalid python interpreter in ${var}"))?;
+                if interpreter.version < required {
+                    bail!(
+                        "Python {} in ${var} is too old; expected at least {required}",
+                        interpreter.version_string()
+                    );
+                }
+                return Ok(interpreter);
+            }
+        }
+
+        let mut found = Vec::new();
+        for (program, args) in Self::candidates() {
+            match Self::probe(&program, &args) {
+                Ok(interpreter) if interpreter.version >= required => return Ok(interpreter),
+                Ok(interpreter) => found.push(format!(
+                    "{} ({})",
+                    interpreter.executable.display(),
+                    interpreter.version_string()
+                )),
+                Err(e) => log::debug!("Python candidate {program:?} {args:?}: {e:#}"),
+            }
+        }
+
+        if found.is_empty() {
+            bail!("Failed to locate python. Is python installed and in your $PATH?")
+        } else {
+            bail!(
+                "No python with at least version {required} found; found {}",
+                found.join(", ")
+            )
+        }
+    }
+
+    fn candidates() -> Vec<(OsString, Vec<String>)> {
+        let mut candidates = Vec::new();
+        if cfg!(windows) {
+            candidates.push(("py".into(), vec!["-3".to_owned()]));
+        }
+        candidates.push((PYTHON.into(), Vec::new()));
+        if !cfg!(windows) {
+            candidates.extend(
+                (6..=14)
+                    .rev()
+                    .map(|minor| (format!("python3.{minor}").into(), Vec::new())),
+            );
+        }
+        for program in ["python3", "python"] {
+            if program != PYTHON {
+                candidates.push((program.into(), Vec::new()));
+            }
+        }
+        candidates
+    }
+
+    /// Get the interpreter that is run with `program` and `args`.
+    pub fn probe(program: impl Into<OsString>, args: &[String]) -> Result<Self> {
+        let program = program.into();
+        let output = cmd!(&program, @args, "-c", PROBE_SCRIPT).stdout()?;
+
+        let parse_err = || anyhow!("Unexpected output from {program:?}: '{output}'");
+        let mut fields = output.trim().splitn(6, ' ');
+        let mut number = || -> Result<u32> {
+            fields
+                .next()
+                .and_then(|f| f.parse().ok())
+                .ok_or_else(parse_err)
+        };
+        let (major, minor, patch) = (number()?, number()?, number()?);
+        let arch = fields.next().ok_or_else(parse_err)?.to_owned();
+        let pointer_width = fields
+            .next()
+            .and_then(|f| f.parse().ok())
+            .ok_or_else(parse_err)?;
+        let executable = fields.next().ok_or_else(parse_err)?;
+
+        Ok(Interpreter {
+            program: program.clone(),
+            args: args.to_vec(),
+            executable: executable.into(),
+            version: PythonVersion { major, minor },
+            patch,
+            arch,
+            pointer_width,
+        })
+    }
+
+    /// The full version string, e.g. `3.11.4`.
+    pub fn version_string(&self) -> String {
+        format!("{}.{}", self.version, self.patch)
+    }
+
+    /// Create a command that runs this interpreter.
+    pub fn cmd(&self) -> Cmd {
+        cmd!(&self.program; args=(&self.args))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::cmd::{with_executor, FakeCmd, FakeExecutor};
+
+    fn probe_output(version: &str) -> String {
+        format!("{} x86_64 64 /usr/bin/python3", version.replace('.', " "))
+    }
+
+    #[test]
+    fn find() {
+        // Make sure no override from the environment is used.
+        if env::var_os(PYTHON_VAR).is_some() || env::var_os(IDF_PYTHON_VAR).is_some() {
+            return;
+        }
+
+        let fake = Arc::new(
+            FakeExecutor::new()
+                .with(FakeCmd::new("python3.12").exit_code(1))
+                .with(FakeCmd::new("python3.11").stdout(probe_output("3.11.4")))
+                .with(FakeCmd::new(PYTHON).stdout(probe_output("3.5.1")))
+                .with(FakeCmd::new("py").stdout(probe_output("3.5.1"))),
+        );
+
+        let python = with_executor(fake.clone(), || Interpreter::find_at_least(3, 6)).unwrap();
+        assert_eq!(
+            python.version,
+            PythonVersion {
+                major: 3,
+                minor: 11
+            }
+        );
+        assert_eq!(python.version_string(), "3.11.4");
+        assert_eq!(python.arch, "x86_64");
+        assert_eq!(python.pointer_width, 64);
+        assert_eq!(python.executable, PathBuf::from("/usr/bin/python3"));
+
+        let err = with_executor(fake, || Interpreter::find_at_least(3, 12)).unwrap_err();
+        assert!(err.to_string().contains("3.12"));
+    }
+}
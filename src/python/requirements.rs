@@ -0,0 +1,1104 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+use super::Venv;
+
+/// A python package version, the release, pre-, post- and dev-release subset of
+/// [PEP 440](https://peps.python.org/pep-0440/).
+///
+/// Local version labels (`+local`) are parsed but ignored when comparing versions.
+#[derive(Debug, Clone)]
+pub struct Version {
+    epoch: u64,
+    release: Vec<u64>,
+    pre: Option<(PreRelease, u64)>,
+    post: Option<u64>,
+    dev: Option<u64>,
+    text: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum PreRelease {
+    Alpha,
+    Beta,
+    Rc,
+}
+
+impl Version {
+    /// The release segments, e.g. `[1, 2, 3]` for `1.2.3rc1`.
+    pub fn release(&self) -> &[u64] {
+        &self.release
+    }
+
+    /// Whether this is a pre- or dev-release.
+    pub fn is_prerelease(&self) -> bool {
+        self.pre.is_some() || self.dev.is_some()
+    }
+
+    fn key(&self) -> (u64, &[u64], (i8, u64), i64, u64) {
+        let release = match self.release.iter().rposition(|&s| s != 0) {
+            Some(last) => &self.release[..=last],
+            None => &[],
+        };
+        let pre = match (self.pre, self.post, self.dev) {
+            (Some((kind, n)), _, _) => (kind as i8, n),
+            // `1.0.dev1` sorts before `1.0a1`.
+            (None, None, Some(_)) => (-1, 0),
+            (None, _, _) => (i8::MAX, 0),
+        };
+        let post = self.post.map(|n| n as i64).unwrap_or(-1);
+        let dev = self.dev.unwrap_or(u64::MAX);
+
+        (self.epoch, release, pre, post, dev)
+    }
+}
+
+impl FromStr for Version {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let err = || anyhow!("Invalid python package version '{text}'");
+
+        let lower = text.trim().to_ascii_lowercase();
+        let version = lower.strip_prefix('v').unwrap_or(&lower);
+        // The local version label is ignored.
+        let version = version.split('+').next().unwrap_or_default();
+
+        let (epoch, mut rest) = match version.split_once('!') {
+            Some((epoch, rest)) => (epoch.parse().map_err(|_| err())?, rest),
+            None => (0, version),
+        };
+
+        let mut release = Vec::new();
+        loop {
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            if digits == 0 {
+                return Err(err());
+            }
+            release.push(rest[..digits].parse().map_err(|_| err())?);
+            rest = &rest[digits..];
+
+            match rest.strip_prefix('.') {
+                Some(r) if r.starts_with(|c: char| c.is_ascii_digit()) => rest = r,
+                _ => break,
+            }
+        }
+
+        let mut version = Version {
+            epoch,
+            release,
+            pre: None,
+            post: None,
+            dev: None,
+            text: text.trim().to_owned(),
+        };
+
+        while !rest.is_empty() {
+            // `1.0-1` is an implicit post-release.
+            if let Some(r) = rest.strip_prefix('-') {
+                if r.starts_with(|c: char| c.is_ascii_digit()) && version.post.is_none() {
+                    let (n, r) = number(r);
+                    version.post = Some(n);
+                    rest = r;
+                    continue;
+                }
+            }
+
+            let r = rest.trim_start_matches(['.', '-', '_']);
+            let (label, r) = [
+                "alpha", "beta", "preview", "pre", "rc", "c", "a", "b", "post", "rev", "r", "dev",
+            ]
+            .iter()
+            .find_map(|label| r.strip_prefix(label).map(|r| (*label, r)))
+            .ok_or_else(err)?;
+            let (n, r) = number(r.trim_start_matches(['.', '-', '_']));
+            rest = r;
+
+            let duplicate = match label {
+                "alpha" | "a" => version.pre.replace((PreRelease::Alpha, n)).is_some(),
+                "beta" | "b" => version.pre.replace((PreRelease::Beta, n)).is_some(),
+                "preview" | "pre" | "rc" | "c" => {
+                    version.pre.replace((PreRelease::Rc, n)).is_some()
+                }
+                "post" | "rev" | "r" => version.post.replace(n).is_some(),
+                _ => version.dev.replace(n).is_some(),
+            };
+            if duplicate {
+                return Err(err());
+            }
+        }
+
+        Ok(version)
+    }
+}
+
+/// Parse the leading number of `s` (`0` if there is none).
+fn number(s: &str) -> (u64, &str) {
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    (s[..digits].parse().unwrap_or(0), &s[digits..])
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// A version specifier operator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    /// `~=`
+    Compatible,
+    /// `==`
+    Equal,
+    /// `!=`
+    NotEqual,
+    /// `<=`
+    LessEqual,
+    /// `>=`
+    GreaterEqual,
+    /// `<`
+    Less,
+    /// `>`
+    Greater,
+    /// `===`
+    ArbitraryEqual,
+}
+
+impl Operator {
+    const ALL: [(&'static str, Operator); 8] = [
+        ("===", Operator::ArbitraryEqual),
+        ("~=", Operator::Compatible),
+        ("==", Operator::Equal),
+        ("!=", Operator::NotEqual),
+        ("<=", Operator::LessEqual),
+        (">=", Operator::GreaterEqual),
+        ("<", Operator::Less),
+        (">", Operator::Greater),
+    ];
+
+    fn as_str(self) -> &'static str {
+        Self::ALL.iter().find(|(_, op)| *op == self).unwrap().0
+    }
+}
+
+/// A version specifier, e.g. `>=1.2` or `==2.*`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Specifier {
+    /// The comparison operator.
+    pub op: Operator,
+    /// The version as written, which may end with `.*` for `==` and `!=`.
+    pub version: String,
+}
+
+impl Specifier {
+    /// Whether `version` satisfies this specifier.
+    pub fn contains(&self, version: &Version) -> Result<bool> {
+        if self.op == Operator::ArbitraryEqual {
+            return Ok(self.version.eq_ignore_ascii_case(&version.text));
+        }
+
+        if let Some(prefix) = self.version.strip_suffix(".*") {
+            let prefix: Version = prefix.parse()?;
+            let matches = version.epoch == prefix.epoch
+                && (0..prefix.release.len())
+                    .all(|i| version.release.get(i).copied().unwrap_or(0) == prefix.release[i]);
+            return match self.op {
+                Operator::Equal => Ok(matches),
+                Operator::NotEqual => Ok(!matches),
+                _ => bail!("Invalid version specifier '{self}'"),
+            };
+        }
+
+        let spec: Version = self.version.parse()?;
+        Ok(match self.op {
+            Operator::Equal => *version == spec,
+            Operator::NotEqual => *version != spec,
+            Operator::LessEqual => *version <= spec,
+            Operator::GreaterEqual => *version >= spec,
+            Operator::Less => *version < spec,
+            Operator::Greater => *version > spec,
+            Operator::Compatible => {
+                if spec.release.len() < 2 {
+                    bail!("Invalid version specifier '{self}'");
+                }
+                let prefix = &spec.release[..spec.release.len() - 1];
+                *version >= spec
+                    && version.epoch == spec.epoch
+                    && (0..prefix.len())
+                        .all(|i| version.release.get(i).copied().unwrap_or(0) == prefix[i])
+            }
+            Operator::ArbitraryEqual => unreachable!(),
+        })
+    }
+}
+
+impl fmt::Display for Specifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.op.as_str(), self.version)
+    }
+}
+
+/// A single requirement of the form `name[extras] specifiers ; marker`
+/// ([PEP 508](https://peps.python.org/pep-0508/), without URL requirements).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Requirement {
+    /// The package name as written.
+    pub name: String,
+    /// The requested extras, e.g. `["socks"]` for `requests[socks]`.
+    pub extras: Vec<String>,
+    /// The version specifiers, all of which must be satisfied.
+    pub specifiers: Vec<Specifier>,
+    /// The environment marker, if any (see [`MarkerEnv`]).
+    pub marker: Option<String>,
+}
+
+impl Requirement {
+    /// Whether `version` satisfies all specifiers of this requirement.
+    pub fn contains(&self, version: &Version) -> Result<bool> {
+        for specifier in &self.specifiers {
+            if !specifier.contains(version)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Whether this requirement applies to the environment `env` with the requested
+    /// `extras`.
+    pub fn applies_to(&self, env: &MarkerEnv, extras: &[String]) -> Result<bool> {
+        match &self.marker {
+            Some(marker) => env
+                .evaluate(marker, extras)
+                .with_context(|| format!("Invalid environment marker '{marker}'")),
+            None => Ok(true),
+        }
+    }
+}
+
+impl FromStr for Requirement {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let err = || anyhow!("Invalid python requirement '{text}'");
+
+        let (requirement, marker) = match text.split_once(';') {
+            Some((r, m)) => (r.trim(), Some(m.trim().to_owned())),
+            None => (text.trim(), None),
+        };
+
+        let name_len = requirement
+            .find(|c: char| !(c.is_ascii_alphanumeric() || "-_.".contains(c)))
+            .unwrap_or(requirement.len());
+        if name_len == 0 {
+            return Err(err());
+        }
+        let name = requirement[..name_len].to_owned();
+        let mut rest = requirement[name_len..].trim_start();
+
+        let mut extras = Vec::new();
+        if let Some(r) = rest.strip_prefix('[') {
+            let (list, r) = r.split_once(']').ok_or_else(err)?;
+            extras = list
+                .split(',')
+                .map(|e| e.trim().to_owned())
+                .filter(|e| !e.is_empty())
+                .collect();
+            rest = r.trim_start();
+        }
+
+        if rest.starts_with('@') {
+            bail!("URL requirements are not supported: '{text}'");
+        }
+        // `Requires-Dist` uses `name (>=1.0)`.
+        if let Some(r) = rest.strip_prefix('(') {
+            rest = r.strip_suffix(')').ok_or_else(err)?;
+        }
+
+        let specifiers = rest
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                let (op_str, op) = Operator::ALL
+                    .iter()
+                    .find(|(op_str, _)| s.starts_with(op_str))
+                    .ok_or_else(err)?;
+                let version = s[op_str.len()..].trim();
+                if version.is_empty() {
+                    return Err(err());
+                }
+                Ok(Specifier {
+                    op: *op,
+                    version: version.to_owned(),
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Requirement {
+            name,
+            extras,
+            specifiers,
+            marker,
+        })
+    }
+}
+
+impl fmt::Display for Requirement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)?;
+        if !self.extras.is_empty() {
+            write!(f, "[{}]", self.extras.join(","))?;
+        }
+        let specifiers = self
+            .specifiers
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        f.write_str(&specifiers.join(","))?;
+        if let Some(marker) = &self.marker {
+            write!(f, "; {marker}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Normalize a python package name ([PEP 503](https://peps.python.org/pep-0503/)).
+pub fn normalize_package_name(name: &str) -> String {
+    let mut normalized = String::with_capacity(name.len());
+    for c in name.trim().chars() {
+        if "-_.".contains(c) {
+            if !normalized.ends_with('-') {
+                normalized.push('-');
+            }
+        } else {
+            normalized.push(c.to_ascii_lowercase());
+        }
+    }
+    normalized
+}
+
+/// The requirements and constraints of pip requirements files.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Requirements {
+    /// The packages to install.
+    pub requirements: Vec<Requirement>,
+    /// Constraints which restrict the versions of packages, if they are installed.
+    pub constraints: Vec<Requirement>,
+}
+
+impl Requirements {
+    /// Parse the contents of a requirements file.
+    ///
+    /// Comments, line continuations and pip options are supported, though options
+    /// other than `-r`/`--requirement` and `-c`/`--constraint` are ignored. As there is
+    /// no file to resolve their paths against, `-r` and `-c` fail here; use
+    /// [`from_file`](Self::from_file) to follow them. Editable (`-e`) requirements are
+    /// not supported.
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut requirements = Self::default();
+        requirements.parse_in(contents, None, false)?;
+        Ok(requirements)
+    }
+
+    /// Read the requirements file `path`, including all files it references with
+    /// `-r` or `-c`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let mut requirements = Self::default();
+        requirements.read(path.as_ref(), false)?;
+        Ok(requirements)
+    }
+
+    /// Add all requirements of the constraints file `path` as constraints.
+    pub fn add_constraints_file(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        self.read(path.as_ref(), true)
+    }
+
+    fn read(&mut self, path: &Path, constraints: bool) -> Result<()> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Could not read requirements file '{}'", path.display()))?;
+        self.parse_in(&contents, path.parent(), constraints)
+            .with_context(|| format!("Invalid requirements file '{}'", path.display()))
+    }
+
+    fn parse_in(&mut self, contents: &str, dir: Option<&Path>, constraints: bool) -> Result<()> {
+        let contents = contents.replace("\\\r\n", "").replace("\\\n", "");
+
+        for line in contents.lines() {
+            // Comments start at a `#` at the beginning of the line or after whitespace.
+            let line = match line.find(" #").or_else(|| line.find("\t#")) {
+                Some(i) => &line[..i],
+                None if line.trim_start().starts_with('#') => "",
+                None => line,
+            }
+            .trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with('-') {
+                let (option, value) = match line.split_once(|c: char| c == '=' || c.is_whitespace())
+                {
+                    Some((option, value)) => (option, value.trim()),
+                    None => (line, ""),
+                };
+                let include_constraints = match option {
+                    "-r" | "--requirement" => constraints,
+                    "-c" | "--constraint" => true,
+                    "-e" | "--editable" => {
+                        bail!("Editable requirements are not supported: '{line}'")
+                    }
+                    _ => continue,
+                };
+
+                let dir = dir.ok_or_else(|| {
+                    anyhow!("Cannot include '{value}' without a requirements file")
+                })?;
+                self.read(&dir.join(value), include_constraints)?;
+                continue;
+            }
+
+            let requirement = line.parse()?;
+            if constraints {
+                self.constraints.push(requirement);
+            } else {
+                self.requirements.push(requirement);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The environment to evaluate requirement environment markers in (e.g.
+/// `sys_platform == 'win32'`).
+///
+/// The platform variables describe the host this build runs on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MarkerEnv {
+    vars: HashMap<&'static str, String>,
+}
+
+impl MarkerEnv {
+    /// Create the marker environment for a CPython interpreter with the full version
+    /// `python_version` (e.g. `3.11.4`), running on the host.
+    pub fn new(python_version: &Version) -> Self {
+        let release = python_version.release();
+        let python_short = format!(
+            "{}.{}",
+            release.first().copied().unwrap_or(0),
+            release.get(1).copied().unwrap_or(0)
+        );
+
+        let (sys_platform, platform_system, os_name) = if cfg!(windows) {
+            ("win32", "Windows", "nt")
+        } else if cfg!(target_os = "macos") {
+            ("darwin", "Darwin", "posix")
+        } else if cfg!(target_os = "linux") {
+            ("linux", "Linux", "posix")
+        } else {
+            (std::env::consts::OS, std::env::consts::OS, "posix")
+        };
+        let platform_machine = match std::env::consts::ARCH {
+            "x86_64" if cfg!(windows) => "AMD64",
+            "aarch64" if cfg!(windows) => "ARM64",
+            "aarch64" if cfg!(target_os = "macos") => "arm64",
+            arch => arch,
+        };
+
+        let vars = [
+            ("python_version", python_short),
+            ("python_full_version", python_version.to_string()),
+            ("sys_platform", sys_platform.to_owned()),
+            ("platform_system", platform_system.to_owned()),
+            ("os_name", os_name.to_owned()),
+            ("platform_machine", platform_machine.to_owned()),
+            ("implementation_name", "cpython".to_owned()),
+            ("platform_python_implementation", "CPython".to_owned()),
+        ];
+
+        Self {
+            vars: vars.into_iter().collect(),
+        }
+    }
+
+    /// Set the marker variable `name` to `value`.
+    #[must_use]
+    pub fn var(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.vars.insert(name, value.into());
+        self
+    }
+
+    /// Evaluate the environment marker `marker` with the requested `extras`.
+    pub fn evaluate(&self, marker: &str, extras: &[String]) -> Result<bool> {
+        let tokens = tokenize(marker)?;
+        let mut parser = MarkerParser {
+            env: self,
+            extras,
+            tokens: &tokens,
+            pos: 0,
+        };
+        let result = parser.or()?;
+        if parser.pos != tokens.len() {
+            bail!("Unexpected '{}'", tokens[parser.pos]);
+        }
+        Ok(result)
+    }
+}
+
+fn tokenize(marker: &str) -> Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut chars = marker.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '\'' || c == '"' {
+            chars.next();
+            let mut s = String::from('\'');
+            loop {
+                match chars.next() {
+                    Some(q) if q == c => break,
+                    Some(ch) => s.push(ch),
+                    None => bail!("Unterminated string"),
+                }
+            }
+            tokens.push(s);
+        } else if c == '(' || c == ')' {
+            chars.next();
+            tokens.push(c.to_string());
+        } else if "=!<>~".contains(c) {
+            let mut op = String::new();
+            while let Some(&ch) = chars.peek().filter(|ch| "=!<>~".contains(**ch)) {
+                op.push(ch);
+                chars.next();
+            }
+            tokens.push(op);
+        } else {
+            let mut word = String::new();
+            while let Some(&ch) = chars
+                .peek()
+                .filter(|ch| ch.is_ascii_alphanumeric() || **ch == '_' || **ch == '.')
+            {
+                word.push(ch);
+                chars.next();
+            }
+            if word.is_empty() {
+                bail!("Unexpected character '{c}'");
+            }
+            tokens.push(word);
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct MarkerParser<'a> {
+    env: &'a MarkerEnv,
+    extras: &'a [String],
+    tokens: &'a [String],
+    pos: usize,
+}
+
+impl MarkerParser<'_> {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Result<&str> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .ok_or_else(|| anyhow!("Unexpected end of marker"))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn or(&mut self) -> Result<bool> {
+        let mut result = self.and()?;
+        while self.peek() == Some("or") {
+            self.pos += 1;
+            result |= self.and()?;
+        }
+        Ok(result)
+    }
+
+    fn and(&mut self) -> Result<bool> {
+        let mut result = self.atom()?;
+        while self.peek() == Some("and") {
+            self.pos += 1;
+            result &= self.atom()?;
+        }
+        Ok(result)
+    }
+
+    fn atom(&mut self) -> Result<bool> {
+        if self.peek() == Some("(") {
+            self.pos += 1;
+            let result = self.or()?;
+            if self.next()? != ")" {
+                bail!("Expected ')'");
+            }
+            return Ok(result);
+        }
+
+        let lhs = self.next()?.to_owned();
+        let op = match self.next()? {
+            "not" => {
+                if self.next()? != "in" {
+                    bail!("Expected 'not in'");
+                }
+                "not in".to_owned()
+            }
+            op => op.to_owned(),
+        };
+        let rhs = self.next()?.to_owned();
+
+        if lhs == "extra" || rhs == "extra" {
+            let extra = if lhs == "extra" { &rhs } else { &lhs };
+            let extra = normalize_package_name(self.value(extra)?);
+            let contains = self
+                .extras
+                .iter()
+                .any(|e| normalize_package_name(e) == extra);
+            return match op.as_str() {
+                "==" => Ok(contains),
+                "!=" => Ok(!contains),
+                _ => bail!("Invalid operator '{op}' for 'extra'"),
+            };
+        }
+
+        let (lhs, rhs) = (self.value(&lhs)?, self.value(&rhs)?);
+        match op.as_str() {
+            "in" => Ok(rhs.contains(lhs)),
+            "not in" => Ok(!rhs.contains(lhs)),
+            _ => {
+                let (_, op) = Operator::ALL
+                    .iter()
+                    .find(|(op_str, _)| *op_str == op)
+                    .ok_or_else(|| anyhow!("Invalid operator '{op}'"))?;
+
+                match lhs.parse::<Version>() {
+                    Ok(version) if rhs.parse::<Version>().is_ok() || rhs.ends_with(".*") => {
+                        Specifier {
+                            op: *op,
+                            version: rhs.to_owned(),
+                        }
+                        .contains(&version)
+                    }
+                    _ => match op {
+                        Operator::Equal | Operator::ArbitraryEqual => Ok(lhs == rhs),
+                        Operator::NotEqual => Ok(lhs != rhs),
+                        _ => bail!("Cannot compare '{lhs}' {op:?} '{rhs}'"),
+                    },
+                }
+            }
+        }
+    }
+
+    fn value<'v>(&'v self, token: &'v str) -> Result<&'v str> {
+        if let Some(s) = token.strip_prefix('\'') {
+            Ok(s)
+        } else {
+            self.env
+                .vars
+                .get(token)
+                .map(String::as_str)
+                .ok_or_else(|| anyhow!("Unknown marker variable '{token}'"))
+        }
+    }
+}
+
+/// A package installed in a python environment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstalledPackage {
+    /// The package name as recorded in its metadata.
+    pub name: String,
+    /// The installed version.
+    pub version: Version,
+    /// The requirements of this package (`Requires-Dist`).
+    pub requires: Vec<Requirement>,
+}
+
+/// A requirement that is not satisfied by a python environment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unsatisfied {
+    /// The requirement that is not satisfied.
+    pub requirement: Requirement,
+    /// The installed version, or [`None`] if the package is not installed.
+    pub installed: Option<Version>,
+    /// The name of the installed package that requires this package, or [`None`] if it
+    /// is a requirement or constraint itself.
+    pub required_by: Option<String>,
+}
+
+impl fmt::Display for Unsatisfied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.installed {
+            Some(version) => write!(f, "'{}' (installed {version})", self.requirement)?,
+            None => write!(f, "'{}' (not installed)", self.requirement)?,
+        }
+        if let Some(required_by) = &self.required_by {
+            write!(f, " required by '{required_by}'")?;
+        }
+        Ok(())
+    }
+}
+
+impl Venv {
+    /// The `site-packages` directories of this virtual environment.
+    pub fn site_packages(&self) -> Result<Vec<PathBuf>> {
+        let dirs = if cfg!(windows) {
+            vec![self.dir().join("Lib").join("site-packages")]
+        } else {
+            let lib = self.dir().join("lib");
+            let mut dirs = fs::read_dir(&lib)
+                .with_context(|| format!("Could not read '{}'", lib.display()))?
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_name().to_string_lossy().starts_with("python"))
+                .map(|e| e.path().join("site-packages"))
+                .collect::<Vec<_>>();
+            dirs.sort();
+            dirs
+        };
+
+        Ok(dirs.into_iter().filter(|d| d.is_dir()).collect())
+    }
+
+    /// The full python version of this virtual environment, read from its
+    /// `pyvenv.cfg`.
+    pub fn python_version(&self) -> Result<Version> {
+        let cfg = self.dir().join("pyvenv.cfg");
+        let contents = fs::read_to_string(&cfg)
+            .with_context(|| format!("Could not read '{}'", cfg.display()))?;
+
+        contents
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .find(|(key, _)| matches!(key.trim(), "version" | "version_info"))
+            .and_then(|(_, value)| {
+                // `version_info` is e.g. `3.12.1.final.0`.
+                let release = value
+                    .trim()
+                    .split('.')
+                    .take(3)
+                    .collect::<Vec<_>>()
+                    .join(".");
+                release.parse().ok()
+            })
+            .ok_or_else(|| anyhow!("No python version in '{}'", cfg.display()))
+    }
+
+    /// Get all packages installed in this virtual environment by reading the metadata
+    /// of its `site-packages` (without running python), keyed by their
+    /// [normalized](normalize_package_name) name.
+    pub fn installed_distributions(&self) -> Result<HashMap<String, InstalledPackage>> {
+        let mut packages = HashMap::new();
+
+        for dir in self.site_packages()? {
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                let metadata = match path.extension().and_then(|e| e.to_str()) {
+                    Some("dist-info") => path.join("METADATA"),
+                    Some("egg-info") if path.is_dir() => path.join("PKG-INFO"),
+                    Some("egg-info") => path.clone(),
+                    _ => continue,
+                };
+
+                match read_metadata(&metadata) {
+                    Ok(package) => {
+                        packages.insert(normalize_package_name(&package.name), package);
+                    }
+                    Err(e) => log::debug!("Ignoring '{}': {e:#}", path.display()),
+                }
+            }
+        }
+
+        Ok(packages)
+    }
+
+    /// Check whether all `requirements` (including their dependencies) are installed in
+    /// this virtual environment and no installed package violates a constraint, without
+    /// running python or pip.
+    ///
+    /// Returns all unsatisfied requirements, which is empty if the requirements are
+    /// satisfied.
+    pub fn check_requirements(&self, requirements: &Requirements) -> Result<Vec<Unsatisfied>> {
+        let env = MarkerEnv::new(&self.python_version()?);
+        let installed = self.installed_distributions()?;
+
+        let mut unsatisfied = Vec::new();
+        let mut visited = HashSet::new();
+        let mut queue = Vec::new();
+        for requirement in &requirements.requirements {
+            if requirement.applies_to(&env, &[])? {
+                queue.push((requirement.clone(), None));
+            }
+        }
+
+        while let Some((requirement, required_by)) = queue.pop() {
+            let name = normalize_package_name(&requirement.name);
+            let package = installed.get(&name);
+
+            match package {
+                Some(package) if requirement.contains(&package.version)? => (),
+                _ => unsatisfied.push(Unsatisfied {
+                    requirement: requirement.clone(),
+                    installed: package.map(|p| p.version.clone()),
+                    required_by: required_by.clone(),
+                }),
+            }
+
+            let package = match package {
+                Some(package) => package,
+                None => continue,
+            };
+            let mut extras = requirement.extras.clone();
+            extras.sort();
+            if !visited.insert((name, extras)) {
+                continue;
+            }
+
+            for dependency in &package.requires {
+                if dependency.applies_to(&env, &requirement.extras)? {
+                    queue.push((dependency.clone(), Some(package.name.clone())));
+                }
+            }
+        }
+
+        for constraint in &requirements.constraints {
+            if !constraint.applies_to(&env, &[])? {
+                continue;
+            }
+            if let Some(package) = installed.get(&normalize_package_name(&constraint.name)) {
+                if !constraint.contains(&package.version)? {
+                    unsatisfied.push(Unsatisfied {
+                        requirement: constraint.clone(),
+                        installed: Some(package.version.clone()),
+                        required_by: None,
+                    });
+                }
+            }
+        }
+
+        Ok(unsatisfied)
+    }
+}
+
+/// Read the name, version and requirements from the core metadata file `path`
+/// (`METADATA` or `PKG-INFO`).
+fn read_metadata(path: &Path) -> Result<InstalledPackage> {
+    let contents = fs::read_to_string(path)?;
+
+    let (mut name, mut version, mut requires) = (None, None, Vec::new());
+    // The headers end at the first empty line.
+    for line in contents.lines().take_while(|l| !l.trim().is_empty()) {
+        if let Some((key, value)) = line.split_once(':') {
+            let value = value.trim();
+            match key {
+                "Name" => name = Some(value.to_owned()),
+                "Version" => version = Some(value.parse()?),
+                "Requires-Dist" => requires.push(value.parse()?),
+                _ => (),
+            }
+        }
+    }
+
+    Ok(InstalledPackage {
+        name: name.ok_or_else(|| anyhow!("No 'Name' in '{}'", path.display()))?,
+        version: version.ok_or_else(|| anyhow!("No 'Version' in '{}'", path.display()))?,
+        requires,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(version: &str) -> Version {
+        version.parse().unwrap()
+    }
+
+    #[test]
+    fn version_order() {
+        let ordered = [
+            "1.0.dev1",
+            "1.0a1.dev1",
+            "1.0a1",
+            "1.0b2",
+            "1.0rc1",
+            "1.0",
+            "1.0.post1.dev1",
+            "1.0.post1",
+            "1.0.1",
+            "1.1",
+            "1!0.1",
+        ];
+        for pair in ordered.windows(2) {
+            assert!(v(pair[0]) < v(pair[1]), "{} < {}", pair[0], pair[1]);
+        }
+
+        assert_eq!(v("1.0"), v("1.0.0"));
+        assert_eq!(v("1.0-1"), v("1.0.post1"));
+        assert_eq!(v("v1.0RC1"), v("1.0rc1"));
+        assert_eq!(v("1.0+local"), v("1.0"));
+        assert!("1.0x".parse::<Version>().is_err());
+    }
+
+    #[test]
+    fn requirement() {
+        let req: Requirement = "Py_Yaml[extra1, extra2] >=5.1,!=5.2.*,<7 ; python_version >= '3.7'"
+            .parse()
+            .unwrap();
+        assert_eq!(req.name, "Py_Yaml");
+        assert_eq!(req.extras, ["extra1", "extra2"]);
+        assert_eq!(req.marker.as_deref(), Some("python_version >= '3.7'"));
+        assert!(req.contains(&v("6.0")).unwrap());
+        assert!(!req.contains(&v("5.2.1")).unwrap());
+        assert!(!req.contains(&v("7.0")).unwrap());
+
+        let req: Requirement = "esptool~=4.6".parse().unwrap();
+        assert!(req.contains(&v("4.7.2")).unwrap());
+        assert!(!req.contains(&v("5.0")).unwrap());
+
+        let req: Requirement = "click (>=7.0)".parse().unwrap();
+        assert!(req.contains(&v("8.1.3")).unwrap());
+
+        assert!("pkg @ https://example.com/pkg.zip"
+            .parse::<Requirement>()
+            .is_err());
+        assert_eq!(normalize_package_name("Py_Yaml.__x"), "py-yaml-x");
+    }
+
+    #[test]
+    fn markers() {
+        let env = MarkerEnv::new(&v("3.8.10"))
+            .var("sys_platform", "linux")
+            .var("platform_machine", "x86_64");
+
+        let eval = |marker: &str| env.evaluate(marker, &["Socks".to_owned()]).unwrap();
+        assert!(eval("python_version < \"3.9\""));
+        assert!(!eval("python_version >= '3.10'"));
+        assert!(eval(
+            "sys_platform == 'linux' and (platform_machine == 'x86_64' or os_name == 'nt')"
+        ));
+        assert!(!eval(
+            "sys_platform == 'win32' or 'arm' in platform_machine"
+        ));
+        assert!(eval("platform_machine not in 'armv7l aarch64'"));
+        assert!(eval("extra == 'socks'"));
+        assert!(!eval("extra == 'test'"));
+        assert!(env.evaluate("foo == 'bar'", &[]).is_err());
+    }
+
+    #[test]
+    fn check_requirements() {
+        let dir = tempfile::tempdir().unwrap();
+        let venv = Venv::new(dir.path());
+        let site_packages = if cfg!(windows) {
+            dir.path().join("Lib").join("site-packages")
+        } else {
+            dir.path()
+                .join("lib")
+                .join("python3.8")
+                .join("site-packages")
+        };
+
+        fs::write(
+            dir.path().join("pyvenv.cfg"),
+            "home = /usr/bin\nversion = 3.8.10\n",
+        )
+        .unwrap();
+        let package = |name: &str, version: &str, requires: &[&str]| {
+            let dist_info = site_packages.join(format!("{name}-{version}.dist-info"));
+            fs::create_dir_all(&dist_info).unwrap();
+            let mut metadata = format!("Metadata-Version: 2.1\nName: {name}\nVersion: {version}\n");
+            for r in requires {
+                metadata += &format!("Requires-Dist: {r}\n");
+            }
+            metadata += "\nRequires-Dist: not-a-header\n";
+            fs::write(dist_info.join("METADATA"), metadata).unwrap();
+        };
+        package("click", "8.1.3", &[]);
+        package("PyYAML", "6.0", &[]);
+        package(
+            "requests",
+            "2.31.0",
+            &[
+                "urllib3 (<3,>=1.21.1)",
+                "PySocks (>=1.5.6) ; extra == 'socks'",
+            ],
+        );
+        package("urllib3", "2.0.4", &[]);
+
+        let requirements = dir.path().join("requirements.txt");
+        fs::write(
+            dir.path().join("constraints.txt"),
+            "urllib3<2.1\nclick==8.1.3 # pinned\n",
+        )
+        .unwrap();
+        fs::write(
+            &requirements,
+            "# core\n-c constraints.txt\n--extra-index-url https://example.com\n\
+             click>=7\npyyaml\\\n  >=5.1\nrequests\n\
+             jython-only; platform_python_implementation == 'Jython'\n\
+             importlib_metadata; python_version < '3.8'\n",
+        )
+        .unwrap();
+
+        let mut requirements = Requirements::from_file(&requirements).unwrap();
+        assert_eq!(requirements.requirements.len(), 5);
+        assert_eq!(requirements.constraints.len(), 2);
+        assert_eq!(venv.check_requirements(&requirements).unwrap(), []);
+
+        requirements
+            .requirements
+            .push("requests[socks]".parse().unwrap());
+        fs::write(dir.path().join("extra.txt"), "urllib3<2").unwrap();
+        requirements
+            .add_constraints_file(dir.path().join("extra.txt"))
+            .unwrap();
+
+        let unsatisfied = venv.check_requirements(&requirements).unwrap();
+        let unsatisfied = unsatisfied
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            unsatisfied,
+            [
+                "'PySocks>=1.5.6; extra == 'socks'' (not installed) required by 'requests'",
+                "'urllib3<2' (installed 2.0.4)"
+            ]
+        );
+    }
+}
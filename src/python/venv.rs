@@ -0,0 +1,222 @@
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use super::Interpreter;
+use crate::cmd;
+use crate::cmd::Cmd;
+
+/// A python virtual environment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Venv {
+    dir: PathBuf,
+}
+
+impl Venv {
+    /// Open the virtual environment in `dir`, which may not exist yet.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Create a virtual environment in `dir` with `interpreter` (using `python -m venv`)
+    /// if there is no [healthy](Self::is_healthy) one already.
+    pub fn create(interpreter: &Interpreter, dir: impl Into<PathBuf>) -> Result<Self> {
+        let venv = Self::new(dir);
+        if venv.is_healthy() {
+            return Ok(venv);
+        }
+
+        let mut cmd = interpreter.cmd();
+        cmd.args([OsStr::new("-m"), OsStr::new("venv"), OsStr::new("--clear")])
+            .arg(&venv.dir);
+        cmd.run().with_context(|| {
+            format!(
+                "Could not create python virtual env in '{}'",
+                venv.dir.display()
+            )
+        })?;
+
+        Ok(venv)
+    }
+
+    /// The directory of this virtual environment.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The directory containing the executables of this virtual environment.
+    pub fn bin_dir(&self) -> PathBuf {
+        if cfg!(windows) {
+            self.dir.join("Scripts")
+        } else {
+            self.dir.join("bin")
+        }
+    }
+
+    /// The python executable of this virtual environment.
+    pub fn python(&self) -> PathBuf {
+        if cfg!(windows) {
+            self.bin_dir().join("python.exe")
+        } else {
+            self.bin_dir().join("python")
+        }
+    }
+
+    /// Create a command that runs the python of this virtual environment.
+    pub fn cmd(&self) -> Cmd {
+        Cmd::new(self.python())
+    }
+
+    /// The interpreter of this virtual environment.
+    pub fn interpreter(&self) -> Result<Interpreter> {
+        Interpreter::probe(self.python(), &[])
+    }
+
+    /// Whether this virtual environment exists, its python runs and pip is available.
+    pub fn is_healthy(&self) -> bool {
+        self.dir.join("pyvenv.cfg").is_file()
+            && self.python().exists()
+            && cmd!(self.python(), "-m", "pip", "--version")
+                .stdout()
+                .is_ok()
+    }
+
+    /// Install `packages` (which are passed as is to `pip install`).
+    pub fn install(&self, packages: impl IntoIterator<Item = impl AsRef<OsStr>>) -> Result<()> {
+        cmd!(self.python(), "-m", "pip", "install", "--disable-pip-version-check"; args=(packages))
+            .run()?;
+        Ok(())
+    }
+
+    /// Install all packages in the requirements file `requirements`, optionally
+    /// constrained by `constraints` (see `pip install -r` and `-c`).
+    pub fn install_requirements(
+        &self,
+        requirements: impl AsRef<Path>,
+        constraints: Option<&Path>,
+    ) -> Result<()> {
+        let constraints = constraints
+            .map(|c| [OsStr::new("-c"), c.as_os_str()])
+            .into_iter()
+            .flatten();
+
+        self.install(
+            [OsStr::new("-r"), requirements.as_ref().as_os_str()]
+                .into_iter()
+                .chain(constraints),
+        )
+    }
+
+    /// Get the name and version of all packages installed in this virtual environment
+    /// (using `pip list`).
+    pub fn installed_packages(&self) -> Result<Vec<(String, String)>> {
+        let output = cmd!(
+            self.python(),
+            "-m",
+            "pip",
+            "list",
+            "--format=freeze",
+            "--disable-pip-version-check"
+        )
+        .stdout()?;
+
+        Ok(output
+            .lines()
+            .filter_map(|line| line.split_once("=="))
+            .map(|(name, version)| (name.trim().to_owned(), version.trim().to_owned()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::cmd::{with_executor, FakeCmd, FakeExecutor};
+    use crate::python::PythonVersion;
+
+    #[test]
+    fn create_and_install() {
+        let dir = tempfile::tempdir().unwrap();
+        let venv_dir = dir.path().join("venv");
+        let venv = Venv::new(&venv_dir);
+        let python = venv.python().to_string_lossy().into_owned();
+        let interpreter = Interpreter {
+            program: "python3".into(),
+            args: Vec::new(),
+            executable: "/usr/bin/python3".into(),
+            version: PythonVersion {
+                major: 3,
+                minor: 11,
+            },
+            patch: 4,
+            arch: "x86_64".into(),
+            pointer_width: 64,
+        };
+
+        let created = venv.clone();
+        let fake = Arc::new(
+            FakeExecutor::new()
+                .with(
+                    FakeCmd::new("python3")
+                        .args_prefix(["-m", "venv"])
+                        .effect(move |_| {
+                            fs::create_dir_all(created.bin_dir())?;
+                            fs::write(created.dir().join("pyvenv.cfg"), "")?;
+                            fs::write(created.python(), "")
+                        }),
+                )
+                .with(FakeCmd::new(python.clone()).args_prefix(["-m", "pip"])),
+        );
+
+        with_executor(fake.clone(), || {
+            let venv = Venv::create(&interpreter, &venv_dir)?;
+            venv.install_requirements("requirements.txt", None)?;
+            // The healthy virtual env is not created again.
+            Venv::create(&interpreter, &venv_dir)
+        })
+        .unwrap();
+
+        let venv_arg = venv_dir.to_string_lossy().into_owned();
+        assert_eq!(
+            fake.calls(),
+            [
+                vec!["python3", "-m", "venv", "--clear", &venv_arg],
+                vec![
+                    &python,
+                    "-m",
+                    "pip",
+                    "install",
+                    "--disable-pip-version-check",
+                    "-r",
+                    "requirements.txt"
+                ],
+                vec![&python, "-m", "pip", "--version"],
+            ]
+        );
+    }
+
+    #[test]
+    fn installed_packages() {
+        let venv = Venv::new("/venv");
+        let fake = Arc::new(
+            FakeExecutor::new().with(
+                FakeCmd::new(venv.python().to_string_lossy())
+                    .args_prefix(["-m", "pip", "list"])
+                    .stdout("idf-component-manager==1.2.2\n-e /src/pkg\nPyYAML==6.0\n"),
+            ),
+        );
+
+        let packages = with_executor(fake, || venv.installed_packages()).unwrap();
+        assert_eq!(
+            packages,
+            [
+                ("idf-component-manager".to_owned(), "1.2.2".to_owned()),
+                ("PyYAML".to_owned(), "6.0".to_owned())
+            ]
+        );
+    }
+}
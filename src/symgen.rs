@@ -1,4 +1,4 @@
-use std::fs::{self, File};
+use std::fs;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::{env, fmt};
@@ -155,7 +155,11 @@ impl Symgen {
 
         eprintln!("Output: {output_file:?}");
 
-        self.write(&mut File::create(output_file)?)
+        let mut output = Vec::new();
+        self.write(&mut output)?;
+        crate::fs::write_atomic_if_changed(output_file, output)?;
+
+        Ok(())
     }
 
     pub fn write(&self, output: &mut impl Write) -> Result<()> {
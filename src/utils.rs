@@ -6,6 +6,15 @@ use std::{env, io};
 
 use anyhow::Result;
 
+#[cfg(feature = "download")]
+mod download;
+mod mirror;
+#[cfg(feature = "ureq")]
+mod proxy;
+#[cfg(feature = "download")]
+pub use download::*;
+pub use mirror::*;
+
 /// Build a [`PathBuf`].
 ///
 /// # Examples
@@ -87,12 +96,20 @@ impl OsStrExt for std::ffi::OsString {}
 impl OsStrExt for Path {}
 impl OsStrExt for PathBuf {}
 
-/// Download the file at `url` to `writer`.
+/// Download the file at `url` to `writer`, using the mirrors (see [`Mirrors::from_env`])
+/// and proxy configured in the environment (see [`Downloader`]).
 ///
-/// Fails if the response status is not `200` (`OK`).
+/// Fails if the response status is not `200` (`OK`). Use [`Downloader`] for retries and
+/// checksum verification.
 #[cfg(feature = "ureq")]
 pub fn download_file_to(url: &str, writer: &mut impl std::io::Write) -> Result<()> {
-    let req = ureq::get(url).call()?;
+    let url = &Mirrors::from_env()?.rewrite(url);
+    if let Some(path) = file_url_to_path(url) {
+        std::io::copy(&mut std::fs::File::open(path)?, writer)?;
+        return Ok(());
+    }
+
+    let req = proxy::agent(url, None)?.get(url).call()?;
     if req.status() != 200 {
         anyhow::bail!(
             "Server at url '{}' returned unexpected status {}: {}",
@@ -0,0 +1,583 @@
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use sha2::{Digest, Sha256};
+
+use super::proxy::agent;
+use super::{file_url_to_path, Mirrors};
+
+/// The progress of a download, passed to the callback set with
+/// [`Downloader::progress`].
+#[derive(Debug, Clone, Copy)]
+pub struct DownloadProgress<'a> {
+    /// The url that is downloaded.
+    pub url: &'a str,
+    /// The number of bytes downloaded so far, including resumed bytes.
+    pub downloaded: u64,
+    /// The total size of the file if known.
+    pub total: Option<u64>,
+}
+
+type ProgressFn = Arc<dyn Fn(&DownloadProgress) + Send + Sync>;
+
+/// Downloads files over HTTP(S) with checksum verification, retries, resuming of
+/// interrupted downloads and an optional download cache.
+///
+/// Proxies are configured from the `HTTPS_PROXY`, `HTTP_PROXY`, `ALL_PROXY` and
+/// `NO_PROXY` environment variables (or their lowercase variants).
+#[derive(Clone)]
+#[must_use]
+pub struct Downloader {
+    retries: u32,
+    backoff: Duration,
+    timeout: Option<Duration>,
+    cache_dir: Option<PathBuf>,
+    progress: Option<ProgressFn>,
+    /// The mirrors to use, or [`None`] for [`Mirrors::from_env`].
+    mirrors: Option<Mirrors>,
+}
+
+impl std::fmt::Debug for Downloader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Downloader")
+            .field("retries", &self.retries)
+            .field("backoff", &self.backoff)
+            .field("timeout", &self.timeout)
+            .field("cache_dir", &self.cache_dir)
+            .field("mirrors", &self.mirrors)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Downloader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An error of a single download attempt.
+enum AttemptError {
+    /// The download may succeed if retried.
+    Retry(anyhow::Error),
+    Fatal(anyhow::Error),
+}
+
+impl Downloader {
+    /// Create a downloader that retries 3 times without a cache.
+    pub fn new() -> Self {
+        Self {
+            retries: 3,
+            backoff: Duration::from_secs(1),
+            timeout: None,
+            cache_dir: None,
+            progress: None,
+            mirrors: None,
+        }
+    }
+
+    /// Retry failed downloads `retries` times.
+    ///
+    /// Downloads are retried on connection errors, `429` and `5xx` responses and
+    /// checksum mismatches.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Wait `backoff` before the first retry, doubling the time for every further retry.
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Abort a download attempt if no data is received for `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Store all files downloaded with a sha256 checksum in `cache_dir`, and reuse them
+    /// for later downloads with the same checksum.
+    pub fn cache_dir(mut self, cache_dir: impl Into<PathBuf>) -> Self {
+        self.cache_dir = Some(cache_dir.into());
+        self
+    }
+
+    /// Call `progress` whenever data was received.
+    pub fn progress(
+        mut self,
+        progress: impl Fn(&DownloadProgress) + Send + Sync + 'static,
+    ) -> Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    /// Use `mirrors` instead of the mirrors configured in the environment (see
+    /// [`Mirrors::from_env`]).
+    pub fn mirrors(mut self, mirrors: Mirrors) -> Self {
+        self.mirrors = Some(mirrors);
+        self
+    }
+
+    /// Download `url` to the file `dest`.
+    ///
+    /// See [`download_checked`](Self::download_checked).
+    pub fn download(&self, url: &str, dest: impl AsRef<Path>) -> Result<()> {
+        self.download_inner(url, dest.as_ref(), None, None)
+    }
+
+    /// Download `url` to the file `dest` and verify its `sha256` checksum (as hex
+    /// string) and its `size` if given.
+    ///
+    /// The data is first downloaded to `<dest>.part`, which is renamed to `dest` once it
+    /// has been verified. If `<dest>.part` already exists (e.g. because a previous build
+    /// was interrupted), the download is resumed with a HTTP range request. The range
+    /// request is conditional on the `ETag` (or `Last-Modified` date) of the first
+    /// response (stored in `<dest>.part.if-range`), so that the download is restarted if
+    /// the remote file changed in the meantime or the server doesn't support ranges.
+    ///
+    /// If `url` is mirrored (see [`Mirrors`]) it is downloaded from the mirror instead,
+    /// which may also be a local directory.
+    pub fn download_checked(
+        &self,
+        url: &str,
+        dest: impl AsRef<Path>,
+        sha256: &str,
+        size: Option<u64>,
+    ) -> Result<()> {
+        self.download_inner(url, dest.as_ref(), Some(sha256), size)
+    }
+
+    fn download_inner(
+        &self,
+        url: &str,
+        dest: &Path,
+        sha256: Option<&str>,
+        size: Option<u64>,
+    ) -> Result<()> {
+        let url = &match &self.mirrors {
+            Some(mirrors) => mirrors.rewrite(url),
+            None => Mirrors::from_env()?.rewrite(url),
+        };
+
+        let sha256 = sha256.map(str::to_ascii_lowercase);
+        let cached = self
+            .cache_dir
+            .as_ref()
+            .zip(sha256.as_ref())
+            .map(|(dir, sha256)| dir.join("sha256").join(sha256));
+
+        if let Some(cached) = &cached {
+            if cached.is_file() {
+                log::debug!("Using cached download '{}' for {url}", cached.display());
+                copy_file(cached, dest)?;
+                return Ok(());
+            }
+        }
+
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let part = with_suffix(dest, ".part");
+
+        let mut attempt = 0;
+        loop {
+            let error = match self.attempt(url, &part, sha256.as_deref(), size) {
+                Ok(()) => break,
+                Err(AttemptError::Fatal(e)) => return Err(e),
+                Err(AttemptError::Retry(e)) => e,
+            };
+
+            if attempt >= self.retries {
+                return Err(error.context(format!(
+                    "Failed to download {url} after {} attempts",
+                    attempt + 1
+                )));
+            }
+
+            let wait = self.backoff * 2u32.saturating_pow(attempt);
+            log::warn!("Download of {url} failed, retrying in {wait:?}: {error:#}");
+            thread::sleep(wait);
+            attempt += 1;
+        }
+
+        fs::rename(&part, dest)
+            .with_context(|| format!("Could not move download to '{}'", dest.display()))?;
+        fs::remove_file(with_suffix(&part, IF_RANGE_SUFFIX)).ok();
+
+        if let Some(cached) = &cached {
+            // The cache is best-effort.
+            if let Err(e) = store_in_cache(dest, cached) {
+                log::warn!("Could not cache download in '{}': {e}", cached.display());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Download (or resume downloading) `url` to `part` and verify it.
+    fn attempt(
+        &self,
+        url: &str,
+        part: &Path,
+        sha256: Option<&str>,
+        size: Option<u64>,
+    ) -> Result<(), AttemptError> {
+        let retry = |e: anyhow::Error| AttemptError::Retry(e);
+        let fatal = |e: anyhow::Error| AttemptError::Fatal(e);
+
+        let if_range_file = with_suffix(part, IF_RANGE_SUFFIX);
+        let if_range = fs::read_to_string(&if_range_file)
+            .ok()
+            .filter(|v| !v.is_empty());
+        // A partial download can only be resumed if it is known to be of the same file.
+        let resume_from = match &if_range {
+            Some(_) => fs::metadata(part).map(|m| m.len()).unwrap_or(0),
+            None => 0,
+        };
+        let complete = matches!(size, Some(size) if resume_from == size);
+
+        if let Some(path) = file_url_to_path(url) {
+            fs::copy(&path, part).map_err(|e| {
+                fatal(anyhow::Error::new(e).context(format!("Could not copy '{}'", path.display())))
+            })?;
+        } else if !complete {
+            let mut request = agent(url, self.timeout).map_err(fatal)?.get(url);
+            if let (true, Some(if_range)) = (resume_from > 0, &if_range) {
+                request = request
+                    .set("Range", &format!("bytes={resume_from}-"))
+                    .set("If-Range", if_range);
+            }
+
+            let response = match request.call() {
+                Ok(response) => response,
+                // The partial file is probably larger than the actual file.
+                Err(ureq::Error::Status(416, _)) => {
+                    fs::remove_file(&if_range_file).ok();
+                    fs::remove_file(part).map_err(|e| fatal(e.into()))?;
+                    return Err(retry(anyhow!(
+                        "Invalid range requested, restarting download"
+                    )));
+                }
+                Err(ureq::Error::Status(status, response)) if status == 429 || status >= 500 => {
+                    return Err(retry(anyhow!(
+                        "Server returned status {status}: {}",
+                        response.status_text()
+                    )))
+                }
+                Err(ureq::Error::Status(status, response)) => {
+                    return Err(fatal(anyhow!(
+                        "Server at url '{url}' returned unexpected status {status}: {}",
+                        response.status_text()
+                    )))
+                }
+                Err(e @ ureq::Error::Transport(_)) => return Err(retry(e.into())),
+            };
+
+            // The server sends the whole file (`200`) if it doesn't support ranges or the
+            // file changed, in which case the download starts over.
+            let resumed = resume_from > 0
+                && response.status() == 206
+                && content_range_start(&response) == Some(resume_from);
+            let (start, file) = if resumed {
+                (resume_from, OpenOptions::new().append(true).open(part))
+            } else if response.status() == 206 {
+                fs::remove_file(&if_range_file).ok();
+                return Err(retry(anyhow!(
+                    "Server returned an unexpected range, restarting download"
+                )));
+            } else {
+                let validator = response
+                    .header("ETag")
+                    .filter(|etag| !etag.starts_with("W/"))
+                    .or_else(|| response.header("Last-Modified"))
+                    .unwrap_or_default();
+                fs::write(&if_range_file, validator).map_err(|e| fatal(e.into()))?;
+                (0, File::create(part))
+            };
+            let mut file = file.map_err(|e| fatal(e.into()))?;
+
+            let total = response
+                .header("Content-Length")
+                .and_then(|l| l.parse::<u64>().ok())
+                .map(|l| l + start)
+                .or(size);
+
+            self.copy(url, response.into_reader(), &mut file, start, total)
+                .map_err(|e| retry(e.into()))?;
+        }
+
+        let actual_size = fs::metadata(part).map_err(|e| fatal(e.into()))?.len();
+        if let Some(size) = size {
+            if actual_size != size {
+                fs::remove_file(part).ok();
+                fs::remove_file(&if_range_file).ok();
+                return Err(retry(anyhow!(
+                    "Size of the download is {actual_size} bytes, expected {size} bytes"
+                )));
+            }
+        }
+
+        if let Some(expected) = sha256 {
+            let actual = sha256_file(part).map_err(fatal)?;
+            if actual != expected {
+                fs::remove_file(part).ok();
+                fs::remove_file(&if_range_file).ok();
+                return Err(retry(anyhow!(
+                    "Checksum mismatch, expected sha256 {expected} but got {actual}"
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn copy(
+        &self,
+        url: &str,
+        mut reader: impl Read,
+        writer: &mut File,
+        start: u64,
+        total: Option<u64>,
+    ) -> io::Result<()> {
+        let mut buf = vec![0; 64 * 1024];
+        let mut downloaded = start;
+
+        loop {
+            let len = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            writer.write_all(&buf[..len])?;
+            downloaded += len as u64;
+
+            if let Some(progress) = &self.progress {
+                progress(&DownloadProgress {
+                    url,
+                    downloaded,
+                    total,
+                });
+            }
+        }
+
+        writer.flush()
+    }
+}
+
+/// The suffix of the file next to a partial download with the value of the `If-Range`
+/// header for resuming it.
+const IF_RANGE_SUFFIX: &str = ".if-range";
+
+/// Append `suffix` to the file name of `path`.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// The first byte of the `Content-Range` header of a `206` response.
+fn content_range_start(response: &ureq::Response) -> Option<u64> {
+    let range = response.header("Content-Range")?.strip_prefix("bytes ")?;
+    range.split('-').next()?.trim().parse().ok()
+}
+
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+
+    let mut hex = String::with_capacity(64);
+    for byte in hasher.finalize() {
+        write!(hex, "{byte:02x}").unwrap();
+    }
+    Ok(hex)
+}
+
+fn copy_file(src: &Path, dest: &Path) -> Result<()> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(src, dest)
+        .with_context(|| format!("Could not copy '{}' to '{}'", src.display(), dest.display()))?;
+    Ok(())
+}
+
+fn store_in_cache(file: &Path, cached: &Path) -> Result<()> {
+    let mut tmp = cached.as_os_str().to_owned();
+    tmp.push(format!(".{}.tmp", std::process::id()));
+    let tmp = PathBuf::from(tmp);
+
+    copy_file(file, &tmp)?;
+    fs::rename(&tmp, cached)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::utils::path_to_file_url;
+
+    /// Serve `body` at `http://127.0.0.1:<port>/` with the ETag `"v1"`, failing the first
+    /// `failures` requests with a `503` and supporting (conditional) range requests.
+    fn serve(body: &'static [u8], failures: usize) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/file.bin", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut range_start = 0;
+                let mut if_range_matches = true;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    let line = line.to_ascii_lowercase();
+                    if let Some(range) = line.strip_prefix("range: bytes=") {
+                        range_start = range.trim().trim_end_matches('-').parse().unwrap();
+                    }
+                    if let Some(if_range) = line.strip_prefix("if-range:") {
+                        if_range_matches = if_range.trim() == "\"v1\"";
+                    }
+                }
+                if !if_range_matches {
+                    range_start = 0;
+                }
+
+                let n = counter.fetch_add(1, Ordering::SeqCst);
+                let response = if n < failures {
+                    b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n".to_vec()
+                } else {
+                    let status = if range_start > 0 {
+                        format!(
+                            "206 Partial Content\r\nContent-Range: bytes {range_start}-{}/{}",
+                            body.len() - 1,
+                            body.len()
+                        )
+                    } else {
+                        "200 OK".to_owned()
+                    };
+                    let body = &body[range_start..];
+                    let mut response = format!(
+                        "HTTP/1.1 {status}\r\nETag: \"v1\"\r\nContent-Length: {}\r\n\r\n",
+                        body.len()
+                    )
+                    .into_bytes();
+                    response.extend_from_slice(body);
+                    response
+                };
+                stream.write_all(&response).unwrap();
+            }
+        });
+
+        (url, requests)
+    }
+
+    const BODY: &[u8] = b"hello world";
+    const BODY_SHA256: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    #[test]
+    fn download_retry_and_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, requests) = serve(BODY, 1);
+        let downloader = Downloader::new()
+            .backoff(Duration::from_millis(1))
+            .cache_dir(dir.path().join("cache"));
+
+        let dest = dir.path().join("a.bin");
+        downloader
+            .download_checked(&url, &dest, BODY_SHA256, Some(BODY.len() as u64))
+            .unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), BODY);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        let dest = dir.path().join("b.bin");
+        downloader
+            .download_checked(&url, &dest, BODY_SHA256, None)
+            .unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), BODY);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        let err = downloader
+            .retries(0)
+            .download_checked(&url, dir.path().join("c.bin"), &"0".repeat(64), None)
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("Checksum mismatch"));
+    }
+
+    #[test]
+    fn download_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, _) = serve(BODY, 0);
+        let dest = dir.path().join("file.bin");
+        let part = dir.path().join("file.bin.part");
+        let if_range = dir.path().join("file.bin.part.if-range");
+
+        // Only the rest of the file is downloaded if the remote file is unchanged.
+        fs::write(&part, "HELLO").unwrap();
+        fs::write(&if_range, "\"v1\"").unwrap();
+        Downloader::new().download(&url, &dest).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"HELLO world");
+        assert!(!if_range.exists());
+
+        // The download starts over if the remote file changed or its version is unknown.
+        for validator in [Some("\"v0\""), None] {
+            fs::write(&part, "HELLO").unwrap();
+            if let Some(validator) = validator {
+                fs::write(&if_range, validator).unwrap();
+            }
+            Downloader::new().download(&url, &dest).unwrap();
+            assert_eq!(fs::read(&dest).unwrap(), BODY);
+        }
+
+        fs::write(&part, &BODY[..5]).unwrap();
+        fs::write(&if_range, "\"v1\"").unwrap();
+
+        let downloaded = Arc::new(AtomicUsize::new(0));
+        let progress = downloaded.clone();
+        Downloader::new()
+            .progress(move |p| progress.store(p.downloaded as usize, Ordering::SeqCst))
+            .download_checked(&url, &dest, BODY_SHA256, None)
+            .unwrap();
+
+        assert_eq!(fs::read(&dest).unwrap(), BODY);
+        assert_eq!(downloaded.load(Ordering::SeqCst), BODY.len());
+    }
+
+    #[test]
+    fn download_from_mirror() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("mirror")).unwrap();
+        fs::write(dir.path().join("mirror").join("file.bin"), BODY).unwrap();
+
+        let mirrors = Mirrors::new()
+            .with(
+                "https://example.invalid/",
+                path_to_file_url(&dir.path().join("mirror/")),
+            )
+            .unwrap();
+        let dest = dir.path().join("file.bin");
+        Downloader::new()
+            .mirrors(mirrors)
+            .download_checked("https://example.invalid/file.bin", &dest, BODY_SHA256, None)
+            .unwrap();
+
+        assert_eq!(fs::read(&dest).unwrap(), BODY);
+    }
+}
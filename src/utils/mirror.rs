@@ -0,0 +1,401 @@
+#[cfg(feature = "git")]
+use std::collections::{BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use anyhow::{anyhow, Context, Result};
+
+/// Environment variable with mirror rules, separated by `;` or newlines (see
+/// [`Mirrors::parse`]).
+pub const MIRRORS_VAR: &str = "EMBUILD_MIRRORS";
+/// Environment variable with the path of a file containing mirror rules, one per line
+/// (see [`Mirrors::parse`]).
+pub const MIRRORS_FILE_VAR: &str = "EMBUILD_MIRRORS_FILE";
+
+/// A mapping of URL prefixes to mirrors, used for all network fetches (git clones,
+/// downloads and esp-idf tool installations).
+///
+/// A mirror is either an alternative URL prefix or a local directory (or `file://`
+/// URL), e.g. for air-gapped builds:
+/// ```text
+/// https://github.com/espressif/ = /srv/mirror/espressif/
+/// https://dl.espressif.com/ = https://dl.example.com/espressif/
+/// ```
+/// With these rules `https://github.com/espressif/esp-idf.git` is fetched from
+/// `file:///srv/mirror/espressif/esp-idf.git`.
+///
+/// Local mirrors are not populated automatically; fill them from a machine with network
+/// access with the `embuild-mirror` tool, or with [`populate_git`](Self::populate_git)
+/// and [`populate_file`](Self::populate_file).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Mirrors {
+    /// All `(prefix, mirror url prefix)` rules.
+    rules: Vec<(String, String)>,
+}
+
+impl Mirrors {
+    /// Create an empty mirror configuration which doesn't change any URL.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a rule which replaces the URL `prefix` with `mirror`.
+    ///
+    /// `mirror` is either a URL or a local directory, relative directories are resolved
+    /// relative to the current directory.
+    pub fn with(mut self, prefix: impl Into<String>, mirror: impl AsRef<str>) -> Result<Self> {
+        let mirror = mirror.as_ref();
+        let mirror = if mirror.contains("://") {
+            mirror.to_owned()
+        } else {
+            path_to_file_url(&env::current_dir()?.join(mirror))
+        };
+
+        self.rules.push((prefix.into(), mirror));
+        Ok(self)
+    }
+
+    /// Parse mirror rules of the form `<url prefix> = <mirror>`, separated by `;` or
+    /// newlines.
+    ///
+    /// Empty rules and lines starting with `#` are ignored.
+    pub fn parse(rules: &str) -> Result<Self> {
+        rules
+            .split([';', '\n'])
+            .map(str::trim)
+            .filter(|r| !r.is_empty() && !r.starts_with('#'))
+            .try_fold(Self::new(), |mirrors, rule| {
+                let (prefix, mirror) = rule.split_once('=').ok_or_else(|| {
+                    anyhow!("Invalid mirror rule '{rule}', expected '<prefix> = <mirror>'")
+                })?;
+                mirrors.with(prefix.trim(), mirror.trim())
+            })
+    }
+
+    /// Get the mirror configuration from the file in [`MIRRORS_FILE_VAR`] and the rules
+    /// in [`MIRRORS_VAR`].
+    ///
+    /// In a build script (if `OUT_DIR` is set), both variables and the file are tracked
+    /// (see [`track_env_var`](crate::cargo::track_env_var)), so that cargo runs the build
+    /// script again if the mirror configuration changes.
+    pub fn from_env() -> Result<Self> {
+        let track = env::var_os("OUT_DIR").is_some();
+        if track {
+            crate::cargo::track_env_var(MIRRORS_FILE_VAR);
+            crate::cargo::track_env_var(MIRRORS_VAR);
+        }
+
+        let mut mirrors = Self::new();
+
+        if let Some(file) = env::var_os(MIRRORS_FILE_VAR).filter(|f| !f.is_empty()) {
+            let file = Path::new(&file);
+            if track {
+                crate::cargo::track_file(file);
+            }
+            let rules = fs::read_to_string(file)
+                .with_context(|| format!("Could not read mirror file '{}'", file.display()))?;
+            mirrors.rules.extend(Self::parse(&rules)?.rules);
+        }
+        if let Ok(rules) = env::var(MIRRORS_VAR) {
+            mirrors.rules.extend(
+                Self::parse(&rules)
+                    .with_context(|| format!("Invalid ${MIRRORS_VAR}"))?
+                    .rules,
+            );
+        }
+
+        Ok(mirrors)
+    }
+
+    /// Whether there are no mirror rules.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Get all `(prefix, mirror)` rules, where `mirror` is always a URL.
+    pub fn rules(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.rules.iter().map(|(p, m)| (p.as_str(), m.as_str()))
+    }
+
+    /// Rewrite `url` using the rule with the longest matching prefix, or return it
+    /// unchanged if no rule matches.
+    pub fn rewrite(&self, url: &str) -> String {
+        self.rules
+            .iter()
+            .filter(|(prefix, _)| url.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(prefix, mirror)| format!("{mirror}{}", &url[prefix.len()..]))
+            .unwrap_or_else(|| url.to_owned())
+    }
+
+    /// Get the local path `url` is mirrored to, if it is mirrored to a local directory.
+    pub fn local_path(&self, url: &str) -> Option<PathBuf> {
+        file_url_to_path(&self.rewrite(url))
+    }
+
+    /// Get the mirror rules in the format of the `IDF_MIRROR_PREFIX_MAP` environment
+    /// variable of the esp-idf `idf_tools.py`, or [`None`] if there are no rules.
+    pub fn to_idf_mirror_prefix_map(&self) -> Option<String> {
+        if self.is_empty() {
+            return None;
+        }
+
+        Some(
+            self.rules
+                .iter()
+                .map(|(prefix, mirror)| format!("{prefix},{mirror}"))
+                .collect::<Vec<_>>()
+                .join(";"),
+        )
+    }
+
+    /// Get the git `url.<mirror>.insteadOf=<prefix>` config values that make git fetch
+    /// from the mirrors.
+    pub fn git_config(&self) -> Vec<String> {
+        self.rules
+            .iter()
+            .map(|(prefix, mirror)| format!("url.{mirror}.insteadOf={prefix}"))
+            .collect()
+    }
+
+    /// Get the git `-c url.<mirror>.insteadOf=<prefix>` arguments that make git fetch
+    /// from the mirrors, including for submodules.
+    pub fn git_config_args(&self) -> Vec<String> {
+        self.git_config()
+            .into_iter()
+            .flat_map(|config| ["-c".to_owned(), config])
+            .collect()
+    }
+
+    /// Mirror the git repository at `url` into its local mirror directory, or update it
+    /// if it already exists, and return the path of the mirror.
+    ///
+    /// The submodules of all branches and tags are mirrored the same way, recursively,
+    /// if they are mirrored to a local directory (others are skipped with a warning).
+    /// Relative submodule urls are resolved relative to the url of their superproject.
+    ///
+    /// Fails if `url` is not mirrored to a local directory.
+    #[cfg(feature = "git")]
+    pub fn populate_git(&self, url: &str) -> Result<PathBuf> {
+        let path = self
+            .local_path(url)
+            .ok_or_else(|| anyhow!("'{url}' is not mirrored to a local directory"))?;
+
+        let mut visited = HashSet::new();
+        let mut pending = vec![(url.to_owned(), path.clone())];
+        while let Some((url, path)) = pending.pop() {
+            if !visited.insert(url.clone()) {
+                continue;
+            }
+            populate_git_repo(&url, &path)?;
+
+            for submodule in submodule_urls(&path)? {
+                let submodule = resolve_submodule_url(&url, &submodule);
+                match self.local_path(&submodule) {
+                    Some(path) => pending.push((submodule, path)),
+                    None => log::warn!(
+                        "Submodule '{submodule}' of '{url}' is not mirrored to a local directory"
+                    ),
+                }
+            }
+        }
+
+        Ok(path)
+    }
+
+    /// Download the file at `url` into its local mirror directory, if it doesn't exist
+    /// already.
+    ///
+    /// Fails if `url` is not mirrored to a local directory.
+    #[cfg(feature = "download")]
+    pub fn populate_file(&self, url: &str) -> Result<PathBuf> {
+        let path = self
+            .local_path(url)
+            .ok_or_else(|| anyhow!("'{url}' is not mirrored to a local directory"))?;
+
+        if !path.exists() {
+            super::Downloader::new()
+                .mirrors(Self::new())
+                .download(url, &path)?;
+        }
+
+        Ok(path)
+    }
+}
+
+/// Clone the git repository at `url` as a mirror into `path`, or update the mirror.
+#[cfg(feature = "git")]
+fn populate_git_repo(url: &str, path: &Path) -> Result<()> {
+    use crate::cmd;
+    use crate::git::GIT;
+
+    log::info!("Mirroring '{url}' into '{}'", path.display());
+    if path.exists() {
+        cmd!(GIT, "--git-dir", path, "remote", "update", "--prune").run()?;
+    } else {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        cmd!(GIT, "clone", "--mirror", url, path).run()?;
+    }
+    Ok(())
+}
+
+/// Get the urls of the submodules in the `.gitmodules` files of all branches and tags
+/// of the bare repository at `git_dir`.
+#[cfg(feature = "git")]
+fn submodule_urls(git_dir: &Path) -> Result<BTreeSet<String>> {
+    use crate::cmd;
+    use crate::git::GIT;
+
+    let refs = cmd!(
+        GIT,
+        "--git-dir",
+        git_dir,
+        "for-each-ref",
+        "--format=%(objectname)",
+        "refs/heads",
+        "refs/tags"
+    )
+    .stdout()?;
+
+    // Most refs share the same `.gitmodules`, so only read each version once.
+    let mut blobs = BTreeSet::new();
+    for object in refs.lines().collect::<BTreeSet<_>>() {
+        let tree = cmd!(GIT, "--git-dir", git_dir, "ls-tree", object, ".gitmodules").stdout()?;
+        // `<mode> blob <object>\t.gitmodules`
+        if let Some(blob) = tree.split_whitespace().nth(2) {
+            blobs.insert(blob.to_owned());
+        }
+    }
+
+    let mut urls = BTreeSet::new();
+    for blob in blobs {
+        let config = cmd!(
+            GIT,
+            "--git-dir",
+            git_dir,
+            "config",
+            "--blob",
+            blob,
+            "--list"
+        )
+        .stdout()?;
+        urls.extend(config.lines().filter_map(|line| {
+            let (key, url) = line.split_once('=')?;
+            (key.starts_with("submodule.") && key.ends_with(".url")).then(|| url.to_owned())
+        }));
+    }
+    Ok(urls)
+}
+
+/// Resolve the submodule `url` relative to the url of its superproject `base`, like git
+/// does for urls starting with `./` or `../`.
+#[cfg(feature = "git")]
+fn resolve_submodule_url(base: &str, url: &str) -> String {
+    if !url.starts_with("./") && !url.starts_with("../") {
+        return url.to_owned();
+    }
+
+    let mut base = base.trim_end_matches('/');
+    let mut url = url;
+    loop {
+        if let Some(rest) = url.strip_prefix("./") {
+            url = rest;
+        } else if let Some(rest) = url.strip_prefix("../") {
+            url = rest;
+            base = base.rsplit_once('/').map_or("", |(parent, _)| parent);
+        } else {
+            break;
+        }
+    }
+    format!("{base}/{url}")
+}
+
+/// Convert the absolute `path` into a `file://` URL.
+pub fn path_to_file_url(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    if path.starts_with('/') {
+        format!("file://{path}")
+    } else {
+        format!("file:///{path}")
+    }
+}
+
+/// Convert the `file://` `url` into a path, or return [`None`] if it is not a `file://`
+/// URL.
+///
+/// Percent-encoded characters are not decoded.
+pub fn file_url_to_path(url: &str) -> Option<PathBuf> {
+    let path = url.strip_prefix("file://")?;
+
+    // `file:///C:/dir` on windows.
+    let bytes = path.as_bytes();
+    if cfg!(windows) && bytes.len() > 2 && bytes[0] == b'/' && bytes[2] == b':' {
+        return Some(PathBuf::from(&path[1..]));
+    }
+
+    Some(PathBuf::from(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrite() {
+        let mirrors = Mirrors::parse(
+            "# mirrors\n\
+             https://github.com/ = https://mirror.example.com/github/\n\
+             https://github.com/espressif/ = srv/mirror/espressif/;\
+             https://dl.espressif.com/=file:///srv/dl/",
+        )
+        .unwrap();
+
+        assert_eq!(
+            mirrors.rewrite("https://github.com/espressif/esp-idf.git"),
+            path_to_file_url(&env::current_dir().unwrap().join("srv/mirror/espressif/"))
+                + "esp-idf.git"
+        );
+        assert_eq!(
+            mirrors.rewrite("https://github.com/rust-lang/rust"),
+            "https://mirror.example.com/github/rust-lang/rust"
+        );
+        assert_eq!(mirrors.rewrite("https://crates.io/"), "https://crates.io/");
+        assert_eq!(
+            mirrors.local_path("https://dl.espressif.com/tool.zip"),
+            Some(PathBuf::from("/srv/dl/tool.zip"))
+        );
+        assert_eq!(
+            mirrors.git_config_args()[..2],
+            [
+                "-c",
+                "url.https://mirror.example.com/github/.insteadOf=https://github.com/"
+            ]
+        );
+
+        assert!(Mirrors::parse("https://github.com/").is_err());
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn submodule_url() {
+        let base = "https://github.com/espressif/esp-idf.git";
+        assert_eq!(
+            resolve_submodule_url(base, "../../espressif/esptool.git"),
+            "https://github.com/espressif/esptool.git"
+        );
+        assert_eq!(
+            resolve_submodule_url(&format!("{base}/"), "./../mbedtls"),
+            "https://github.com/espressif/mbedtls"
+        );
+        assert_eq!(
+            resolve_submodule_url(base, "./components/lib"),
+            "https://github.com/espressif/esp-idf.git/components/lib"
+        );
+        assert_eq!(
+            resolve_submodule_url(base, "https://gitlab.com/lib.git"),
+            "https://gitlab.com/lib.git"
+        );
+    }
+}
@@ -0,0 +1,87 @@
+//! Proxy configuration of http requests.
+
+use std::env;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+/// Create an agent for `url` that uses the proxy configured in the environment.
+pub(super) fn agent(url: &str, timeout: Option<Duration>) -> Result<ureq::Agent> {
+    let mut builder = ureq::AgentBuilder::new();
+    if let Some(timeout) = timeout {
+        builder = builder.timeout_read(timeout).timeout_connect(timeout);
+    }
+    if let Some(proxy) = proxy_for(url, env_var) {
+        builder = builder
+            .proxy(ureq::Proxy::new(&proxy).with_context(|| format!("Invalid proxy '{proxy}'"))?);
+    }
+
+    Ok(builder.build())
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(name)
+        .or_else(|_| env::var(name.to_ascii_lowercase()))
+        .ok()
+        .filter(|v| !v.is_empty())
+}
+
+/// Get the proxy that should be used for `url` according to the environment variables
+/// returned by `env_var`.
+fn proxy_for(url: &str, env_var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let (scheme, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let authority = authority.rsplit('@').next()?;
+    let host = match authority.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next()?,
+        None => authority.split(':').next()?,
+    }
+    .to_ascii_lowercase();
+
+    if let Some(no_proxy) = env_var("NO_PROXY") {
+        let excluded = no_proxy.split(',').map(str::trim).any(|pattern| {
+            let pattern = pattern.trim_start_matches('*').trim_start_matches('.');
+            pattern == "*"
+                || (!pattern.is_empty()
+                    && (host == pattern || host.ends_with(&format!(".{pattern}"))))
+        });
+        if excluded || no_proxy.trim() == "*" {
+            return None;
+        }
+    }
+
+    match scheme {
+        "https" => env_var("HTTPS_PROXY"),
+        "http" => env_var("HTTP_PROXY"),
+        _ => None,
+    }
+    .or_else(|| env_var("ALL_PROXY"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proxy() {
+        let env = |name: &str| match name {
+            "HTTPS_PROXY" => Some("http://proxy:8080".to_owned()),
+            "NO_PROXY" => Some("localhost,.internal".to_owned()),
+            _ => None,
+        };
+
+        assert_eq!(
+            proxy_for("https://github.com/espressif", env).as_deref(),
+            Some("http://proxy:8080")
+        );
+        assert_eq!(proxy_for("https://localhost:8080/x", env), None);
+        assert_eq!(proxy_for("https://mirror.internal/x", env), None);
+        assert_eq!(proxy_for("http://github.com/x", env), None);
+
+        let all = |name: &str| (name == "ALL_PROXY").then(|| "http://all:3128".to_owned());
+        assert_eq!(
+            proxy_for("http://github.com/x", all).as_deref(),
+            Some("http://all:3128")
+        );
+    }
+}